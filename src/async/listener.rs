@@ -1,38 +1,594 @@
-use std::{future::Future, io::Cursor, net::SocketAddr, pin::Pin};
+use std::{future::Future, io::Cursor, net::SocketAddr, pin::Pin, sync::Arc, time::Duration};
 
 use bytes::Bytes;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{mpsc, Semaphore},
+};
 
-use crate::{packet::DNSPacket, r#async::resolve::resolve_async, TYPE_A};
+use crate::{
+    builder::MessageBuilder,
+    error::ResolveError,
+    header::{FLAG_QR, FLAG_RA, FLAG_TC},
+    packet::DNSPacket,
+    question::DNSQuestion,
+    r#async::resolve::lookup_async,
+    simple::query::tcp_frame,
+    RCODE_FORMERR, RCODE_NOERROR, RCODE_NXDOMAIN, RCODE_SERVFAIL,
+};
 
-async fn listen_inner(udp: std::net::UdpSocket) -> eyre::Result<()> {
-    let mut buf = [0; 1024];
-    let socket = tokio::net::UdpSocket::try_from(udp)?;
-    // let socket = Arc::new(socket);
-    tracing::debug!("Listening on {}", socket.local_addr()?);
+pub type ListenerFuture = Pin<Box<dyn Future<Output = eyre::Result<()>> + Send>>;
+
+/// Largest UDP response we send to clients that don't advertise EDNS.
+const MAX_UDP_SIZE: usize = 512;
+
+/// Queries from one connection being resolved at once. Further queries aren't
+/// read until one of them is answered.
+const MAX_PIPELINED: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct ListenerConfig {
+    /// Address for both the UDP socket and the TCP listener. With port 0 both
+    /// share whichever port the UDP socket was given. `[::]` listens on IPv4
+    /// and IPv6 alike.
+    pub address: SocketAddr,
+    /// TCP connections without a query for this long are closed. Also applies
+    /// to TLS, HTTPS and QUIC connections.
+    pub tcp_idle_timeout: Duration,
+    /// Further TCP connections are closed straight away. Counted separately
+    /// for each listener.
+    pub max_tcp_connections: usize,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        ListenerConfig {
+            address: "127.0.0.1:0".parse().unwrap(),
+            tcp_idle_timeout: Duration::from_secs(10),
+            max_tcp_connections: 128,
+        }
+    }
+}
+
+/// Resolves the first question of `query` and builds the response to send
+/// back to the client.
+pub async fn handle_query(query: &DNSPacket) -> DNSPacket {
+    let response = MessageBuilder::response_to(query).flag(FLAG_RA, true);
+    let Some(question) = query.questions().first() else {
+        return response.rcode(RCODE_FORMERR).build();
+    };
+    match lookup_async(&question.name, question.qtype).await {
+        Ok(lookup) => lookup
+            .answers()
+            .fold(response, |response, record| {
+                // Echo the name as the client spelled it
+                if same_name(record.name(), question) {
+                    response.answer(record.with_name(&question.name))
+                } else {
+                    response.answer(record.clone())
+                }
+            })
+            .build(),
+        Err(e) => match e.downcast_ref::<ResolveError>() {
+            // Only a denial for the name asked about, not for one of its
+            // nameservers
+            Some(negative) if denies(negative, question) => {
+                let rcode = match negative {
+                    ResolveError::NxDomain { .. } => RCODE_NXDOMAIN,
+                    ResolveError::NoData { .. } => RCODE_NOERROR,
+                };
+                response
+                    .rcode(rcode)
+                    .authority(negative.soa().clone())
+                    .build()
+            }
+            _ => {
+                tracing::warn!("Failed to resolve {}: {}", question.name, e);
+                response.rcode(RCODE_SERVFAIL).build()
+            }
+        },
+    }
+}
+
+fn denies(negative: &ResolveError, question: &DNSQuestion) -> bool {
+    match negative {
+        ResolveError::NxDomain { name, .. } => same_name(name, question),
+        ResolveError::NoData { name, qtype, .. } => {
+            same_name(name, question) && *qtype == question.qtype
+        }
+    }
+}
+
+fn same_name(name: &str, question: &DNSQuestion) -> bool {
+    fn unrooted(name: &str) -> &str {
+        name.strip_suffix('.').unwrap_or(name)
+    }
+    unrooted(name).eq_ignore_ascii_case(unrooted(&question.name))
+}
+
+/// Parses a query off the wire and returns the encoded response. This is the
+/// request path shared by every transport.
+///
+/// `max_size` is the largest response the transport can carry, anything
+/// bigger is replaced by an empty response with TC set.
+pub async fn handle_request(data: &[u8], max_size: Option<usize>) -> Vec<u8> {
+    let query = match DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::copy_from_slice(data))) {
+        Ok(query) => query,
+        Err(e) => {
+            tracing::info!("Malformed query: {}", e);
+            return format_error(data);
+        }
+    };
+    tracing::info!("Parsed packet:\n{}", query);
+    let response = handle_query(&query).await;
+    tracing::info!("Response:\n{}", response);
+    let bytes = response.to_be_bytes();
+    let max_size = max_size.map(|size| {
+        query
+            .edns()
+            .map_or(MAX_UDP_SIZE, |edns| edns.udp_payload_size as usize)
+            .clamp(MAX_UDP_SIZE, size)
+    });
+    match max_size {
+        Some(max_size) if bytes.len() > max_size => MessageBuilder::response_to(&query)
+            .flag(FLAG_RA, true)
+            .flag(FLAG_TC, true)
+            .to_be_bytes(),
+        _ => bytes,
+    }
+}
+
+/// FORMERR for a query that couldn't be parsed, with its ID if it has one.
+fn format_error(data: &[u8]) -> Vec<u8> {
+    let id = match data {
+        [high, low, ..] => u16::from_be_bytes([*high, *low]),
+        _ => 0,
+    };
+    MessageBuilder::new()
+        .id(id)
+        .flag(FLAG_QR, true)
+        .rcode(RCODE_FORMERR)
+        .to_be_bytes()
+}
+
+async fn listen_udp(socket: UdpSocket) -> eyre::Result<()> {
+    let mut buf = [0; 4096];
+    let socket = Arc::new(socket);
+    tracing::debug!("Listening on udp://{}", socket.local_addr()?);
     loop {
-        let client = socket.recv_from(&mut buf).await?;
-        tracing::info!("Received {} bytes from {}", client.0, client.1);
-        let packet =
-            DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::copy_from_slice(&buf[..])));
-        tracing::info!("Parsed packet: {:?}", packet);
-        // let s = socket.clone();
+        let (len, client) = socket.recv_from(&mut buf).await?;
+        tracing::info!("Received {} bytes from {}", len, client);
+        let query = buf[..len].to_vec();
+        let socket = socket.clone();
         tokio::spawn(async move {
-            let response = resolve_async("example.com", TYPE_A).await?;
-            tracing::info!("Response: {:?}", response);
+            let response = handle_request(&query, Some(buf.len())).await;
+            socket.send_to(&response, client).await?;
             eyre::Result::<()>::Ok(())
         });
-        // let response = resolve_async(&socket, "example.com", TYPE_A).await.unwrap();
     }
 }
 
-pub fn listener() -> Result<
-    (SocketAddr, Pin<Box<impl Future<Output = eyre::Result<()>>>>),
-    Box<dyn std::error::Error>,
-> {
-    let socket = std::net::UdpSocket::bind("127.0.0.1:0")?;
-    socket.set_nonblocking(true)?;
-    let address = socket.local_addr()?;
-    // let mut buf = [0; 1024];
-    // let _ = tokio::spawn(listen_inner(socket));
-    Ok((address, Box::pin(listen_inner(socket))))
+/// Serves length-prefixed queries from one connection until the client goes
+/// away or stays idle, also in the middle of a query. Queries are handled
+/// concurrently, up to [`MAX_PIPELINED`], and answered in whatever order they
+/// complete.
+async fn serve_stream<S>(stream: S, client: SocketAddr, idle_timeout: Duration) -> eyre::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (responses, mut outgoing) = mpsc::channel::<Vec<u8>>(MAX_PIPELINED);
+    let pending = Arc::new(Semaphore::new(MAX_PIPELINED));
+    let write = tokio::spawn(async move {
+        while let Some(response) = outgoing.recv().await {
+            writer.write_all(&tcp_frame(&response)).await?;
+        }
+        eyre::Result::<()>::Ok(())
+    });
+    loop {
+        let len = match tokio::time::timeout(idle_timeout, reader.read_u16()).await {
+            Err(_) => {
+                tracing::debug!("Closing idle connection from {}", client);
+                break;
+            }
+            // The client closed the connection
+            Ok(Err(_)) => break,
+            Ok(Ok(len)) => len,
+        };
+        let mut query = vec![0; len as usize];
+        match tokio::time::timeout(idle_timeout, reader.read_exact(&mut query)).await {
+            Err(_) => {
+                tracing::debug!("Closing connection from {} stalled mid-query", client);
+                break;
+            }
+            Ok(read) => read?,
+        };
+        tracing::info!("Received {} bytes from {} over a stream", len, client);
+        let permit = pending.clone().acquire_owned().await?;
+        let responses = responses.clone();
+        tokio::spawn(async move {
+            let response = handle_request(&query, None).await;
+            let _ = responses.send(response).await;
+            drop(permit);
+        });
+    }
+    // Let in-flight queries finish before the writer shuts down
+    drop(responses);
+    write.await?
+}
+
+/// Accepts connections on `listener` and hands each to `serve`, which gets
+/// the stream and the client address.
+async fn listen_tcp<A, F>(
+    listener: TcpListener,
+    config: ListenerConfig,
+    serve: A,
+) -> eyre::Result<()>
+where
+    A: Fn(TcpStream, SocketAddr) -> F,
+    F: Future<Output = eyre::Result<()>> + Send + 'static,
+{
+    tracing::debug!("Listening on tcp://{}", listener.local_addr()?);
+    let connections = Arc::new(Semaphore::new(config.max_tcp_connections));
+    loop {
+        let (stream, client) = listener.accept().await?;
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+            tracing::warn!("Too many TCP connections, closing {}", client);
+            continue;
+        };
+        let connection = serve(stream, client);
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                tracing::debug!("TCP connection from {} failed: {}", client, e);
+            }
+            drop(permit);
+        });
+    }
+}
+
+/// Wraps `serve` so that it gets the stream once the TLS handshake is done.
+#[cfg(feature = "tls")]
+fn accept_tls<S, F>(
+    tls: Arc<rustls::ServerConfig>,
+    idle_timeout: Duration,
+    serve: S,
+) -> impl Fn(TcpStream, SocketAddr) -> Pin<Box<dyn Future<Output = eyre::Result<()>> + Send>>
+where
+    S: Fn(tokio_rustls::server::TlsStream<TcpStream>, SocketAddr) -> F + Clone + Send + 'static,
+    F: Future<Output = eyre::Result<()>> + Send + 'static,
+{
+    let acceptor = tokio_rustls::TlsAcceptor::from(tls);
+    move |stream, client| {
+        let accepting = acceptor.accept(stream);
+        let serve = serve.clone();
+        Box::pin(async move {
+            let stream = tokio::time::timeout(idle_timeout, accepting)
+                .await
+                .map_err(|_| eyre::eyre!("handshake timed out"))??;
+            serve(stream, client).await
+        })
+    }
+}
+
+async fn listen_inner(
+    udp: std::net::UdpSocket,
+    tcp: std::net::TcpListener,
+    config: ListenerConfig,
+) -> eyre::Result<()> {
+    let udp = UdpSocket::from_std(udp)?;
+    let tcp = TcpListener::from_std(tcp)?;
+    let idle_timeout = config.tcp_idle_timeout;
+    let serve = move |stream, client| serve_stream(stream, client, idle_timeout);
+    tokio::try_join!(listen_udp(udp), listen_tcp(tcp, config, serve))?;
+    Ok(())
+}
+
+/// Creates a socket of the right family for `address`. The unspecified IPv6
+/// address `[::]` accepts IPv4 clients as well.
+fn socket_for(address: SocketAddr, ty: socket2::Type) -> std::io::Result<socket2::Socket> {
+    let socket = socket2::Socket::new(socket2::Domain::for_address(address), ty, None)?;
+    if address.is_ipv6() && address.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    Ok(socket)
+}
+
+fn udp_socket(address: SocketAddr) -> std::io::Result<std::net::UdpSocket> {
+    let socket = socket_for(address, socket2::Type::DGRAM)?;
+    socket.bind(&address.into())?;
+    Ok(socket.into())
+}
+
+fn tcp_listener(address: SocketAddr) -> std::io::Result<std::net::TcpListener> {
+    let socket = socket_for(address, socket2::Type::STREAM)?;
+    // Like std, so restarting doesn't wait for old connections to time out
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+/// Binds UDP and TCP on the same address, see [`ListenerConfig::address`].
+fn bind(address: SocketAddr) -> std::io::Result<(std::net::UdpSocket, std::net::TcpListener)> {
+    let mut attempts = 0;
+    loop {
+        let udp = udp_socket(address)?;
+        match tcp_listener(udp.local_addr()?) {
+            Ok(tcp) => return Ok((udp, tcp)),
+            // The port picked for UDP may already be taken for TCP
+            Err(e) if address.port() == 0 && attempts < 10 => {
+                tracing::debug!("Could not bind TCP on {}: {}", udp.local_addr()?, e);
+                attempts += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+pub fn listener_with(
+    config: ListenerConfig,
+) -> Result<(SocketAddr, ListenerFuture), Box<dyn std::error::Error>> {
+    let (udp, tcp) = bind(config.address)?;
+    udp.set_nonblocking(true)?;
+    tcp.set_nonblocking(true)?;
+    let address = udp.local_addr()?;
+    Ok((address, Box::pin(listen_inner(udp, tcp, config))))
+}
+
+pub fn listener() -> Result<(SocketAddr, ListenerFuture), Box<dyn std::error::Error>> {
+    listener_with(ListenerConfig::default())
+}
+
+/// Binds a TCP listener for the TLS based transports.
+#[cfg(feature = "tls")]
+fn bind_tcp(address: SocketAddr) -> std::io::Result<(SocketAddr, std::net::TcpListener)> {
+    let tcp = tcp_listener(address)?;
+    tcp.set_nonblocking(true)?;
+    Ok((tcp.local_addr()?, tcp))
+}
+
+/// Serves DNS over TLS on TCP only, see [`crate::tls::server_config`].
+#[cfg(feature = "tls")]
+pub fn tls_listener(
+    config: ListenerConfig,
+    tls: Arc<rustls::ServerConfig>,
+) -> Result<(SocketAddr, ListenerFuture), Box<dyn std::error::Error>> {
+    let (address, tcp) = bind_tcp(config.address)?;
+    let idle_timeout = config.tcp_idle_timeout;
+    let serve = move |stream, client| serve_stream(stream, client, idle_timeout);
+    let serve = accept_tls(tls, idle_timeout, serve);
+    Ok((
+        address,
+        Box::pin(async move { listen_tcp(TcpListener::from_std(tcp)?, config, serve).await }),
+    ))
+}
+
+/// Serves DNS over HTTPS at [`crate::https::DOH_PATH`], see
+/// [`crate::https::server_config`].
+#[cfg(feature = "https")]
+pub fn https_listener(
+    config: ListenerConfig,
+    tls: Arc<rustls::ServerConfig>,
+) -> Result<(SocketAddr, ListenerFuture), Box<dyn std::error::Error>> {
+    let (address, tcp) = bind_tcp(config.address)?;
+    let idle_timeout = config.tcp_idle_timeout;
+    let serve = move |stream, client| crate::https::serve_https(stream, client, idle_timeout);
+    let serve = accept_tls(tls, idle_timeout, serve);
+    Ok((
+        address,
+        Box::pin(async move { listen_tcp(TcpListener::from_std(tcp)?, config, serve).await }),
+    ))
+}
+
+/// Serves DNS over QUIC on UDP, see [`crate::quic::server_config`].
+#[cfg(feature = "quic")]
+pub fn quic_listener(
+    config: ListenerConfig,
+    quic: quinn::ServerConfig,
+) -> Result<(SocketAddr, ListenerFuture), Box<dyn std::error::Error>> {
+    let udp = udp_socket(config.address)?;
+    let address = udp.local_addr()?;
+    Ok((
+        address,
+        Box::pin(async move {
+            // Creating the endpoint needs a runtime, so wait until we're polled
+            let endpoint = quinn::Endpoint::new(
+                quinn::EndpointConfig::default(),
+                Some(quic),
+                udp,
+                Arc::new(quinn::TokioRuntime),
+            )?;
+            crate::quic::serve_quic(
+                endpoint,
+                config.max_tcp_connections,
+                config.tcp_idle_timeout,
+            )
+            .await
+        }),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn read_message(stream: &mut TcpStream) -> DNSPacket {
+        let len = stream.read_u16().await.unwrap();
+        let mut message = vec![0; len as usize];
+        stream.read_exact(&mut message).await.unwrap();
+        DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::from(message))).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_answers_from_cache() {
+        use crate::{
+            cache::record_cache,
+            header::FLAG_AA,
+            record::{DNSRecord, DNSRecordResult},
+            TYPE_A, TYPE_AAAA, TYPE_CNAME,
+        };
+
+        // Filled in up front so nothing needs resolving
+        let soa: DNSRecord = "cache.test. 3600 IN SOA ns.cache.test. admin.cache.test. 1 2 3 4 300"
+            .parse()
+            .unwrap();
+        let denial = |rcode| {
+            MessageBuilder::new()
+                .flag(FLAG_AA, true)
+                .rcode(rcode)
+                .authority(soa.clone())
+                .build()
+        };
+        let answer = MessageBuilder::new()
+            .flag(FLAG_AA, true)
+            .answer("www.cache.test. 600 IN A 192.0.2.80".parse().unwrap())
+            .build();
+        let cache = record_cache();
+        cache.cache_response(
+            "cache.test",
+            "gone.cache.test",
+            TYPE_A,
+            &denial(RCODE_NXDOMAIN),
+        );
+        cache.cache_response(
+            "cache.test",
+            "www.cache.test",
+            TYPE_AAAA,
+            &denial(RCODE_NOERROR),
+        );
+        cache.cache_response("cache.test", "www.cache.test", TYPE_A, &answer);
+        let alias = MessageBuilder::new()
+            .flag(FLAG_AA, true)
+            .answer(
+                "alias.cache.test. 600 IN CNAME www.cache.test."
+                    .parse()
+                    .unwrap(),
+            )
+            .build();
+        cache.cache_response("cache.test", "alias.cache.test", TYPE_A, &alias);
+
+        let query = |name, qtype| MessageBuilder::new().question(name, qtype, 1).build();
+        let response = handle_query(&query("gone.cache.test", TYPE_A)).await;
+        assert_eq!(response.header().rcode(), RCODE_NXDOMAIN);
+        assert!(response.answers().is_empty());
+        assert!(response.authorities()[0].ttl() <= 300);
+
+        let response = handle_query(&query("www.cache.test", TYPE_AAAA)).await;
+        assert_eq!(response.header().rcode(), RCODE_NOERROR);
+        assert!(response.answers().is_empty());
+        assert_eq!(response.authorities().len(), 1);
+
+        let response = handle_query(&query("www.cache.test", TYPE_A)).await;
+        assert_eq!(response.header().rcode(), RCODE_NOERROR);
+        assert!((599..=600).contains(&response.answers()[0].ttl()));
+
+        // The CNAME comes first, under the name as asked
+        let response = handle_query(&query("Alias.cache.test.", TYPE_A)).await;
+        let answers = response.answers();
+        assert_eq!(answers.len(), 2);
+        assert_eq!(answers[0].name(), "Alias.cache.test.");
+        assert_eq!(answers[0].qtype(), TYPE_CNAME);
+        assert!(matches!(
+            answers[1].res(),
+            DNSRecordResult::Address(ip) if ip.to_string() == "192.0.2.80"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_query() {
+        // A question whose name is a compression pointer to itself
+        let query = b"\x12\x34\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\xc0\x0c";
+        let response = handle_request(query, Some(512)).await;
+        let response =
+            DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::from(response))).unwrap();
+        assert_eq!(response.header().id, 0x1234);
+        assert_eq!(response.header().rcode(), RCODE_FORMERR);
+
+        let response = handle_request(b"\x12", None).await;
+        assert_eq!(&response[2..4], &[0x80, RCODE_FORMERR]);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_pipelining() {
+        let (address, server) = listener().unwrap();
+        tokio::spawn(server);
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        // Queries without a question are answered with FORMERR, no resolving needed
+        for id in [1, 2, 3] {
+            let query = MessageBuilder::new().id(id).to_be_bytes();
+            stream.write_all(&tcp_frame(&query)).await.unwrap();
+        }
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            let response = read_message(&mut stream).await;
+            assert_eq!(response.header().rcode(), RCODE_FORMERR);
+            ids.push(response.header().id);
+        }
+        ids.sort();
+        assert_eq!(ids, [1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_idle_timeout_and_limit() {
+        let (address, server) = listener_with(ListenerConfig {
+            tcp_idle_timeout: Duration::from_millis(50),
+            max_tcp_connections: 1,
+            ..ListenerConfig::default()
+        })
+        .unwrap();
+        tokio::spawn(server);
+
+        let mut first = TcpStream::connect(address).await.unwrap();
+        // Give the listener a moment to hand out the only permit
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let mut second = TcpStream::connect(address).await.unwrap();
+        assert_eq!(second.read(&mut [0; 1]).await.unwrap(), 0);
+
+        // The idle connection is closed by the server
+        assert_eq!(first.read(&mut [0; 1]).await.unwrap(), 0);
+        let mut third = TcpStream::connect(address).await.unwrap();
+        let query = MessageBuilder::new().id(7).to_be_bytes();
+        third.write_all(&tcp_frame(&query)).await.unwrap();
+        assert_eq!(read_message(&mut third).await.header().id, 7);
+
+        // Stalling after the length prefix doesn't keep the connection open
+        third.write_all(&[0, 12, 0]).await.unwrap();
+        assert_eq!(third.read(&mut [0; 1]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack() {
+        let (address, server) = listener_with(ListenerConfig {
+            address: "[::]:0".parse().unwrap(),
+            ..ListenerConfig::default()
+        })
+        .unwrap();
+        tokio::spawn(server);
+
+        for ip in ["127.0.0.1", "::1"] {
+            let client = SocketAddr::new(ip.parse().unwrap(), address.port());
+            let query = MessageBuilder::new().id(3).to_be_bytes();
+
+            let mut stream = TcpStream::connect(client).await.unwrap();
+            stream.write_all(&tcp_frame(&query)).await.unwrap();
+            assert_eq!(read_message(&mut stream).await.header().id, 3);
+
+            let local = if ip == "::1" {
+                "[::1]:0"
+            } else {
+                "127.0.0.1:0"
+            };
+            let socket = UdpSocket::bind(local).await.unwrap();
+            socket.send_to(&query, client).await.unwrap();
+            let mut buf = [0; 512];
+            let len = socket.recv(&mut buf).await.unwrap();
+            let response =
+                DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::copy_from_slice(&buf[..len])))
+                    .unwrap();
+            assert_eq!(response.header().rcode(), RCODE_FORMERR);
+        }
+    }
 }
@@ -1,3 +1,3 @@
+pub mod listener;
 pub mod query;
 pub mod resolve;
-pub mod listener;
@@ -1,48 +1,597 @@
-use std::{
-    io::Cursor,
-    net::{IpAddr, SocketAddr},
+use std::net::{IpAddr, SocketAddr};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time::Instant,
 };
 
-use bytes::Bytes;
+use crate::simple::query::{
+    build_query_packet, case_fallback, tcp_frame, Attempts, Matching, Protocol, QueryOptions,
+};
 
-use crate::simple::query::build_query;
+#[cfg(feature = "https")]
+use crate::https::HttpsConfig;
+#[cfg(feature = "quic")]
+use crate::quic::QuicConfig;
+#[cfg(feature = "tls")]
+use crate::tls::TlsConfig;
+use crate::{header::FLAG_TC, packet::DNSPacket, selection::ServerSelection};
 
-use crate::packet::DNSPacket;
+async fn exchange_udp_async(
+    query: &DNSPacket,
+    addr: SocketAddr,
+    options: &QueryOptions,
+    deadline: Instant,
+    matching: &mut Matching,
+) -> eyre::Result<Option<DNSPacket>> {
+    let local = if addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = tokio::net::UdpSocket::bind(local).await?;
+    socket.send_to(&query.to_be_bytes(), addr).await?;
+    tracing::debug!("Sent query to {}", addr);
+    let mut buf = vec![0; options.recv_buffer_size()];
+    loop {
+        let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await
+        else {
+            return Ok(None);
+        };
+        let (len, from) = received?;
+        tracing::debug!("Received {} bytes from {}", len, from);
+        if let Some(response) = matching.check(query, addr, from, &buf[..len]) {
+            return Ok(Some(response));
+        }
+    }
+}
+
+/// Sends `query` over a connected `stream` and waits for a matching response.
+async fn exchange_stream_async<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    query: &DNSPacket,
+    addr: SocketAddr,
+    matching: &mut Matching,
+) -> eyre::Result<DNSPacket> {
+    stream.write_all(&tcp_frame(&query.to_be_bytes())).await?;
+    loop {
+        let len = stream.read_u16().await?;
+        let mut message = vec![0; len as usize];
+        stream.read_exact(&mut message).await?;
+        if let Some(response) = matching.check(query, addr, addr, &message) {
+            return Ok(response);
+        }
+    }
+}
+
+/// Sends `query` over a new TCP connection and waits until `deadline` for a
+/// matching response. Returns `None` if none arrived in time.
+async fn exchange_tcp_async(
+    query: &DNSPacket,
+    addr: SocketAddr,
+    deadline: Instant,
+    matching: &mut Matching,
+) -> eyre::Result<Option<DNSPacket>> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        exchange_stream_async(&mut stream, query, addr, matching).await
+    };
+    match tokio::time::timeout_at(deadline, exchange).await {
+        Ok(response) => response.map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Like [`exchange_tcp_async`], with the connection wrapped in TLS.
+#[cfg(feature = "tls")]
+async fn exchange_tls_async(
+    query: &DNSPacket,
+    addr: SocketAddr,
+    tls: &TlsConfig,
+    deadline: Instant,
+    matching: &mut Matching,
+) -> eyre::Result<Option<DNSPacket>> {
+    let exchange = async {
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = tls.connect_async(stream).await?;
+        exchange_stream_async(&mut stream, query, addr, matching).await
+    };
+    match tokio::time::timeout_at(deadline, exchange).await {
+        Ok(response) => response.map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Sends `query` as a DoH request and waits until `deadline` for the
+/// response. Returns `None` if none arrived in time or it didn't match.
+#[cfg(feature = "https")]
+pub(crate) async fn exchange_https_async(
+    query: &DNSPacket,
+    addr: SocketAddr,
+    https: &HttpsConfig,
+    deadline: Instant,
+    matching: &mut Matching,
+) -> eyre::Result<Option<DNSPacket>> {
+    let message = query.to_be_bytes();
+    let exchange = https.exchange(addr, &message);
+    let Ok(message) = tokio::time::timeout_at(deadline, exchange).await else {
+        return Ok(None);
+    };
+    Ok(matching.check(query, addr, addr, &message?))
+}
+
+/// Sends `query` on a new DoQ connection and waits until `deadline` for the
+/// response. Returns `None` if none arrived in time or it didn't match.
+#[cfg(feature = "quic")]
+pub(crate) async fn exchange_quic_async(
+    query: &DNSPacket,
+    addr: SocketAddr,
+    quic: &QuicConfig,
+    deadline: Instant,
+    matching: &mut Matching,
+) -> eyre::Result<Option<DNSPacket>> {
+    let message = query.to_be_bytes();
+    let exchange = quic.exchange(addr, &message);
+    let Ok(message) = tokio::time::timeout_at(deadline, exchange).await else {
+        return Ok(None);
+    };
+    Ok(matching.check(query, addr, addr, &message?))
+}
 
 pub async fn send_query_async<R: rand::Rng>(
     rng: &mut R,
     ip_address: IpAddr,
     domain_name: &str,
     record_type: u16,
+    options: &QueryOptions,
 ) -> eyre::Result<DNSPacket> {
     tracing::debug!("Sending query to {}", ip_address);
-    let query = build_query(rng, domain_name, record_type);
-    let socket = tokio::net::UdpSocket::bind("0.0.0.0:0").await?;
-    let addr: SocketAddr = SocketAddr::new(ip_address, 53);
-    socket.send_to(&query, addr).await?;
-    tracing::debug!("Sent query to {}", addr);
-    let mut buf = [0; 1024];
-    let n = socket.recv_from(&mut buf).await?;
-    tracing::debug!("Received response from {} of {} bytes", addr, n.1);
-    let mut buf = Cursor::new(Bytes::copy_from_slice(&buf[..]));
-    Ok(DNSPacket::parse_dns_packet(&mut buf))
+    let addr: SocketAddr = SocketAddr::new(ip_address, options.port);
+    let mut options = options.clone();
+    let mut attempts = Attempts::new(addr, &options);
+    while let Some(deadline) = attempts.next_deadline() {
+        let query = build_query_packet(rng, domain_name, record_type, &options);
+        let deadline = Instant::from_std(deadline);
+        let matching = &mut attempts.matching;
+        let wrong_case = matching.wrong_case;
+        let response = match &options.protocol {
+            Protocol::Udp => exchange_udp_async(&query, addr, &options, deadline, matching).await?,
+            Protocol::Tcp => exchange_tcp_async(&query, addr, deadline, matching).await?,
+            #[cfg(feature = "tls")]
+            Protocol::Tls(tls) => exchange_tls_async(&query, addr, tls, deadline, matching).await?,
+            #[cfg(feature = "https")]
+            Protocol::Https(https) => {
+                exchange_https_async(&query, addr, https, deadline, matching).await?
+            }
+            #[cfg(feature = "quic")]
+            Protocol::Quic(quic) => {
+                exchange_quic_async(&query, addr, quic, deadline, matching).await?
+            }
+        };
+        let Some(response) = response else {
+            // Only give up on 0x20 once an attempt got nothing but responses
+            // in the wrong case, a spoofed one mustn't turn it off
+            if attempts.matching.wrong_case > wrong_case {
+                tracing::warn!(
+                    "{addr} did not preserve the case of {domain_name}, retrying without 0x20"
+                );
+                options = case_fallback(&options);
+                attempts.matching.exact_case = false;
+                attempts.repeat();
+            }
+            continue;
+        };
+        if response.header().has_flag(FLAG_TC) && matches!(options.protocol, Protocol::Udp) {
+            tracing::debug!("Response from {addr} was truncated, retrying over TCP");
+            options.protocol = Protocol::Tcp;
+            attempts.repeat();
+            continue;
+        }
+        return Ok(response);
+    }
+    Err(attempts.error().into())
+}
+
+/// Tries `servers` in order until one of them answers, for failing over
+/// between servers of the same zone. Returns the last error if none did.
+pub async fn send_query_any_async<R: rand::Rng>(
+    rng: &mut R,
+    servers: &[IpAddr],
+    domain_name: &str,
+    record_type: u16,
+    options: &QueryOptions,
+) -> eyre::Result<DNSPacket> {
+    let mut error = eyre::eyre!("no servers to query for {domain_name}");
+    for &server in servers {
+        match send_query_async(rng, server, domain_name, record_type, options).await {
+            Ok(response) => return Ok(response),
+            Err(e) => {
+                tracing::warn!("Query to {server} failed, trying the next server: {e}");
+                error = e;
+            }
+        }
+    }
+    Err(error)
+}
+
+/// See [`crate::simple::query::send_query_best`].
+pub async fn send_query_best_async<R: rand::Rng>(
+    rng: &mut R,
+    selection: &ServerSelection,
+    servers: &[IpAddr],
+    domain_name: &str,
+    record_type: u16,
+    options: &QueryOptions,
+) -> eyre::Result<(IpAddr, DNSPacket)> {
+    let mut error = eyre::eyre!("no servers to query for {domain_name}");
+    for server in selection.order(rng, servers) {
+        let start = Instant::now();
+        match send_query_async(rng, server, domain_name, record_type, options).await {
+            Ok(response) => {
+                selection.record_rtt(server, start.elapsed());
+                return Ok((server, response));
+            }
+            Err(e) => {
+                tracing::warn!("Query to {server} failed, trying the next server: {e}");
+                selection.record_failure(server);
+                error = e;
+            }
+        }
+    }
+    Err(error)
 }
 
 #[cfg(test)]
 mod test {
+    use crate::builder::MessageBuilder;
+    use crate::error::QueryError;
+    use crate::header::{FLAG_QR, FLAG_TC};
+    use crate::packet::DNSPacket;
     use crate::r#async::query::send_query_async;
-    use crate::TYPE_A;
+    use crate::record::{DNSRecord, DNSRecordResult};
+    use crate::simple::query::QueryOptions;
+    use crate::transport::Fixtures;
+    use crate::{CLASS_IN, TYPE_A};
+    use std::io::Cursor;
     use std::net::IpAddr;
+    use std::time::Duration;
 
+    use bytes::Bytes;
     use rand::rngs::mock::StepRng;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, UdpSocket};
+
+    async fn loopback_server() -> (UdpSocket, QueryOptions) {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let options = QueryOptions {
+            port: server.local_addr().unwrap().port(),
+            timeout: Duration::from_millis(50),
+            retries: 1,
+            ..QueryOptions::default()
+        };
+        (server, options)
+    }
 
     #[tokio::test]
-    async fn test_send_query_async2() {
-        let mut mock_rng = StepRng::new(0x8298, 0);
-        let nameserver: IpAddr = "198.41.0.4".parse().unwrap();
-        let res = send_query_async(&mut mock_rng, nameserver, "www.example.com", TYPE_A)
+    async fn test_discard_mismatched_responses() {
+        let (server, options) = loopback_server().await;
+        let client = tokio::spawn(async move {
+            let mut mock_rng = StepRng::new(0x8298, 0);
+            let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+            send_query_async(
+                &mut mock_rng,
+                localhost,
+                "www.example.com",
+                TYPE_A,
+                &options,
+            )
+            .await
+        });
+
+        let mut buf = [0; 512];
+        let (len, from) = server.recv_from(&mut buf).await.unwrap();
+        let query =
+            DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::copy_from_slice(&buf[..len])))
+                .unwrap();
+        let wrong_id = MessageBuilder::response_to(&query)
+            .id(query.header().id.wrapping_add(1))
+            .to_be_bytes();
+        let wrong_question = MessageBuilder::new()
+            .id(query.header().id)
+            .flag(FLAG_QR, true)
+            .question("evil.example.com", TYPE_A, CLASS_IN)
+            .to_be_bytes();
+        let matching = MessageBuilder::response_to(&query)
+            .answer(DNSRecord::new(
+                "www.example.com".to_string(),
+                TYPE_A,
+                CLASS_IN,
+                60,
+                DNSRecordResult::Address("10.0.0.1".parse().unwrap()),
+            ))
+            .to_be_bytes();
+        // Right ID and QR bit, but the answer it claims isn't there
+        let mut malformed = MessageBuilder::response_to(&query).to_be_bytes();
+        malformed[7] = 1;
+        // Not a response at all, just our query reflected back
+        server.send_to(&buf[..len], from).await.unwrap();
+        server.send_to(&wrong_id, from).await.unwrap();
+        server.send_to(&wrong_question, from).await.unwrap();
+        server.send_to(&malformed, from).await.unwrap();
+        server.send_to(&malformed[..14], from).await.unwrap();
+        server.send_to(&matching, from).await.unwrap();
+
+        let response = client.await.unwrap().unwrap();
+        assert_eq!(response.answers().len(), 1);
+    }
+
+    /// A response to `query` with its question name in lower case.
+    fn lowercased(query: &DNSPacket) -> Vec<u8> {
+        let question = &query.questions()[0];
+        MessageBuilder::new()
+            .id(query.header().id)
+            .flag(FLAG_QR, true)
+            .question(&question.name.to_ascii_lowercase(), TYPE_A, CLASS_IN)
+            .to_be_bytes()
+    }
+
+    async fn send_randomized(options: QueryOptions) -> eyre::Result<DNSPacket> {
+        let mut mock_rng = StepRng::new(0, 1 << 31);
+        let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+        let options = QueryOptions {
+            randomize_case: true,
+            ..options
+        };
+        send_query_async(
+            &mut mock_rng,
+            localhost,
+            "www.example.com",
+            TYPE_A,
+            &options,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_discard_wrong_case() {
+        let (server, options) = loopback_server().await;
+        let client = tokio::spawn(send_randomized(options));
+        let mut buf = [0; 512];
+        let (len, from) = server.recv_from(&mut buf).await.unwrap();
+        let query =
+            DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::copy_from_slice(&buf[..len])))
+                .unwrap();
+        assert_ne!(query.questions()[0].name, "www.example.com");
+        // A spoofed response in the wrong case mustn't win, nor turn off 0x20
+        server.send_to(&lowercased(&query), from).await.unwrap();
+        let echoed = MessageBuilder::response_to(&query).to_be_bytes();
+        server.send_to(&echoed, from).await.unwrap();
+
+        let response = client.await.unwrap().unwrap();
+        assert_eq!(response.questions()[0].name, query.questions()[0].name);
+    }
+
+    #[tokio::test]
+    async fn test_case_fallback_after_timeout() {
+        let (server, options) = loopback_server().await;
+        let client = tokio::spawn(send_randomized(options));
+        let mut buf = [0; 512];
+        let (len, from) = server.recv_from(&mut buf).await.unwrap();
+        let query =
+            DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::copy_from_slice(&buf[..len])))
+                .unwrap();
+        server.send_to(&lowercased(&query), from).await.unwrap();
+        // Once the attempt timed out the query is repeated in plain case
+        let (len, from) = server.recv_from(&mut buf).await.unwrap();
+        let retry =
+            DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::copy_from_slice(&buf[..len])))
+                .unwrap();
+        assert_eq!(retry.questions()[0].name, "www.example.com");
+        server.send_to(&lowercased(&retry), from).await.unwrap();
+
+        let response = client.await.unwrap().unwrap();
+        assert_eq!(response.header().id, retry.header().id);
+    }
+
+    #[tokio::test]
+    async fn test_no_matching_response() {
+        let (server, options) = loopback_server().await;
+        let client = tokio::spawn(async move {
+            let mut mock_rng = StepRng::new(0x8298, 0);
+            let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+            send_query_async(
+                &mut mock_rng,
+                localhost,
+                "www.example.com",
+                TYPE_A,
+                &options,
+            )
+            .await
+        });
+        let mut buf = [0; 512];
+        let (len, from) = server.recv_from(&mut buf).await.unwrap();
+        server.send_to(&buf[..len], from).await.unwrap();
+
+        let err = client.await.unwrap().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<QueryError>(),
+            Some(QueryError::NoMatchingResponse { discarded: 1, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_timeout() {
+        let (server, options) = loopback_server().await;
+        let client = tokio::spawn(async move {
+            let mut mock_rng = StepRng::new(0x8298, 1);
+            let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+            send_query_async(
+                &mut mock_rng,
+                localhost,
+                "www.example.com",
+                TYPE_A,
+                &options,
+            )
+            .await
+        });
+        let mut buf = [0; 512];
+        // Drop the first attempt on the floor and answer the retry
+        let (len, _) = server.recv_from(&mut buf).await.unwrap();
+        let first =
+            DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::copy_from_slice(&buf[..len])))
+                .unwrap();
+        let (len, from) = server.recv_from(&mut buf).await.unwrap();
+        let retry =
+            DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::copy_from_slice(&buf[..len])))
+                .unwrap();
+        assert_ne!(first.header().id, retry.header().id);
+        let response = MessageBuilder::response_to(&retry).to_be_bytes();
+        server.send_to(&response, from).await.unwrap();
+
+        let response = client.await.unwrap().unwrap();
+        assert_eq!(response.header().id, retry.header().id);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_fallback_on_truncation() {
+        let (server, options) = loopback_server().await;
+        let tcp = TcpListener::bind(server.local_addr().unwrap())
             .await
             .unwrap();
-        println!("Response: {:?}", res);
+        let client = tokio::spawn(async move {
+            let mut mock_rng = StepRng::new(0x8298, 1);
+            let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+            send_query_async(
+                &mut mock_rng,
+                localhost,
+                "www.example.com",
+                TYPE_A,
+                &options,
+            )
+            .await
+        });
+
+        let mut buf = [0; 512];
+        let (len, from) = server.recv_from(&mut buf).await.unwrap();
+        let query =
+            DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::copy_from_slice(&buf[..len])))
+                .unwrap();
+        let truncated = MessageBuilder::response_to(&query)
+            .flag(FLAG_TC, true)
+            .to_be_bytes();
+        server.send_to(&truncated, from).await.unwrap();
+
+        let (mut stream, _) = tcp.accept().await.unwrap();
+        let len = stream.read_u16().await.unwrap();
+        let mut message = vec![0; len as usize];
+        stream.read_exact(&mut message).await.unwrap();
+        let query = DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::from(message))).unwrap();
+        let mut answer = MessageBuilder::response_to(&query);
+        for i in 0..100 {
+            answer = answer.answer(DNSRecord::new(
+                "www.example.com".to_string(),
+                TYPE_A,
+                CLASS_IN,
+                60,
+                DNSRecordResult::Address([10, 0, 0, i].into()),
+            ));
+        }
+        let answer = answer.to_be_bytes();
+        stream.write_u16(answer.len() as u16).await.unwrap();
+        stream.write_all(&answer).await.unwrap();
+
+        let response = client.await.unwrap().unwrap();
+        assert!(!response.header().has_flag(FLAG_TC));
+        assert_eq!(response.answers().len(), 100);
+    }
+
+    #[tokio::test]
+    async fn test_ipv6() {
+        let server = UdpSocket::bind("[::1]:0").await.unwrap();
+        let options = QueryOptions {
+            port: server.local_addr().unwrap().port(),
+            ..QueryOptions::default()
+        };
+        let client = tokio::spawn(async move {
+            let mut mock_rng = StepRng::new(0x8298, 0);
+            let localhost: IpAddr = "::1".parse().unwrap();
+            send_query_async(
+                &mut mock_rng,
+                localhost,
+                "www.example.com",
+                TYPE_A,
+                &options,
+            )
+            .await
+        });
+        let mut buf = [0; 512];
+        let (len, from) = server.recv_from(&mut buf).await.unwrap();
+        let query =
+            DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::copy_from_slice(&buf[..len])))
+                .unwrap();
+        let response = MessageBuilder::response_to(&query).to_be_bytes();
+        server.send_to(&response, from).await.unwrap();
+
+        let response = client.await.unwrap().unwrap();
+        assert_eq!(response.header().id, 0x8298);
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let (_server, options) = loopback_server().await;
+        let mut mock_rng = StepRng::new(0x8298, 0);
+        let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+        let err = send_query_async(
+            &mut mock_rng,
+            localhost,
+            "www.example.com",
+            TYPE_A,
+            &options,
+        )
+        .await
+        .unwrap_err();
+        let Some(QueryError::Timeout {
+            attempts, elapsed, ..
+        }) = err.downcast_ref::<QueryError>()
+        else {
+            panic!("expected a timeout, got {err}");
+        };
+        assert_eq!(*attempts, 2);
+        // 50ms for the first attempt and 100ms for the retry
+        assert!(*elapsed >= Duration::from_millis(150));
+    }
+
+    #[tokio::test]
+    async fn test_send_query_async2() {
+        let fixtures = Fixtures::parse(include_str!("../../tests/fixtures/resolve.txt")).unwrap();
+        let root: IpAddr = "198.41.0.4".parse().unwrap();
+        let (server, options) = loopback_server().await;
+        let options = QueryOptions {
+            recursion_desired: false,
+            ..options
+        };
+        // Stands in for a.root-servers.net
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (len, from) = server.recv_from(&mut buf).await.unwrap();
+            let query =
+                DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::copy_from_slice(&buf[..len])))
+                    .unwrap();
+            let response = fixtures.reply(root, &query).unwrap();
+            server.send_to(&response.to_be_bytes(), from).await.unwrap();
+        });
+        let mut mock_rng = StepRng::new(0x8298, 0);
+        let res = send_query_async(
+            &mut mock_rng,
+            "127.0.0.1".parse().unwrap(),
+            "www.example.com",
+            TYPE_A,
+            &options,
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.header().id, 0x8298);
+        assert_eq!(res.get_nameserver_ips().len(), 2);
     }
 }
@@ -1,27 +1,161 @@
-use std::{io::Cursor, sync::Arc};
+use std::net::SocketAddr;
 
-use bytes::Bytes;
-use diaw::packet::DNSPacket;
+use diaw::{
+    hints::{root_hints, set_root_hints, RootHints},
+    r#async::listener::{listener_with, ListenerConfig},
+    simple::query::QueryOptions,
+};
+use rand::SeedableRng;
 use tracing::Level;
 
+/// Command line flags, all of them optional.
+#[derive(Default)]
+struct Args {
+    /// A `named.root` file to use instead of the built-in root hints.
+    root_hints: Option<String>,
+    /// Ask the roots for the current root servers before serving.
+    prime: bool,
+    #[cfg(feature = "tls")]
+    tls_cert: Option<String>,
+    #[cfg(feature = "tls")]
+    tls_key: Option<String>,
+    #[cfg(feature = "tls")]
+    tls_address: Option<String>,
+    #[cfg(feature = "https")]
+    https_address: Option<String>,
+}
+
+impl Args {
+    fn parse() -> eyre::Result<Self> {
+        let mut parsed = Args::default();
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            if arg == "--prime" {
+                parsed.prime = true;
+                continue;
+            }
+            let value = args
+                .next()
+                .ok_or_else(|| eyre::eyre!("missing value for {arg}"))?;
+            match arg.as_str() {
+                "--root-hints" => parsed.root_hints = Some(value),
+                #[cfg(feature = "tls")]
+                "--tls-cert" => parsed.tls_cert = Some(value),
+                #[cfg(feature = "tls")]
+                "--tls-key" => parsed.tls_key = Some(value),
+                #[cfg(feature = "tls")]
+                "--tls-address" => parsed.tls_address = Some(value),
+                #[cfg(feature = "https")]
+                "--https-address" => parsed.https_address = Some(value),
+                _ => eyre::bail!("unknown argument {arg}"),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+/// Starts the encrypted listeners when `--tls-cert` and `--tls-key` are
+/// given: DNS over TLS on `--tls-address` (127.0.0.1:853 by default), with
+/// the `quic` feature DNS over QUIC on the same address over UDP, and with
+/// the `https` feature DNS over HTTPS on `--https-address` (127.0.0.1:443 by
+/// default).
+#[cfg(feature = "tls")]
+fn tls_servers(args: &Args) -> eyre::Result<Vec<diaw::r#async::listener::ListenerFuture>> {
+    use diaw::{r#async::listener::tls_listener, tls::server_config};
+
+    let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) else {
+        return Ok(Vec::new());
+    };
+    let cert = std::fs::read(cert)?;
+    let key = std::fs::read(key)?;
+    let config = ListenerConfig {
+        address: args
+            .tls_address
+            .as_deref()
+            .unwrap_or("127.0.0.1:853")
+            .parse()?,
+        ..ListenerConfig::default()
+    };
+    let (address, server) = tls_listener(config.clone(), server_config(&cert, &key)?)
+        .map_err(|e| eyre::eyre!("{e}"))?;
+    tracing::info!("Serving DNS over TLS on {}", address);
+    #[cfg_attr(not(any(feature = "https", feature = "quic")), allow(unused_mut))]
+    let mut servers = vec![server];
+    #[cfg(feature = "quic")]
+    {
+        use diaw::r#async::listener::quic_listener;
+
+        let quic = diaw::quic::server_config(&cert, &key)?;
+        let (address, server) = quic_listener(config, quic).map_err(|e| eyre::eyre!("{e}"))?;
+        tracing::info!("Serving DNS over QUIC on {}", address);
+        servers.push(server);
+    }
+    #[cfg(feature = "https")]
+    {
+        use diaw::{https::DOH_PATH, r#async::listener::https_listener};
+
+        let config = ListenerConfig {
+            address: args
+                .https_address
+                .as_deref()
+                .unwrap_or("127.0.0.1:443")
+                .parse()?,
+            ..ListenerConfig::default()
+        };
+        let tls = diaw::https::server_config(&cert, &key)?;
+        let (address, server) = https_listener(config, tls).map_err(|e| eyre::eyre!("{e}"))?;
+        tracing::info!("Serving DNS over HTTPS on https://{}{}", address, DOH_PATH);
+        servers.push(server);
+    }
+    Ok(servers)
+}
+
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
     tracing_subscriber::fmt()
         .with_max_level(Level::TRACE)
         .init();
-    let socket = tokio::net::UdpSocket::bind("127.0.0.1:7777").await?;
-    let socket = Arc::new(socket);
-    let mut buf = [0; 1024];
-    loop {
-        let client = socket.recv_from(&mut buf).await?;
-        tracing::info!("Received {} bytes from {}", client.0, client.1);
-        let packet =
-            DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::copy_from_slice(&buf[..])));
-        tracing::info!("Parsed packet: {:?}", packet);
-        // let s = socket.clone();
-        // tokio::spawn(async {
-        //     let response = resolve_async(s, "example.com", TYPE_A).await?;
-        //     eyre::Result::<()>::Ok(())
-        // });
+    let args = Args::parse()?;
+    if let Some(path) = &args.root_hints {
+        set_root_hints(RootHints::load(path)?);
+    }
+    if args.prime {
+        let options = QueryOptions {
+            edns_udp_size: Some(1232),
+            ..QueryOptions::iterative()
+        };
+        let rng = &mut rand::rngs::SmallRng::from_entropy();
+        let primed = root_hints().prime_async(rng, &options).await?;
+        tracing::info!("Primed {} root servers", primed.servers().len());
+        set_root_hints(primed);
+    }
+
+    let mut servers = tokio::task::JoinSet::<eyre::Result<()>>::new();
+    for address in ["127.0.0.1:7777", "[::1]:7777"] {
+        let address: SocketAddr = address.parse()?;
+        let config = ListenerConfig {
+            address,
+            ..ListenerConfig::default()
+        };
+        match listener_with(config) {
+            Ok((address, server)) => {
+                tracing::info!("Serving UDP and TCP on {}", address);
+                servers.spawn(server);
+            }
+            // Not every host has an IPv6 loopback
+            Err(e) if address.is_ipv6() => {
+                tracing::warn!("Not serving on {}: {}", address, e);
+            }
+            Err(e) => return Err(eyre::eyre!("{e}")),
+        }
+    }
+    #[cfg(feature = "tls")]
+    for server in tls_servers(&args)? {
+        servers.spawn(server);
+    }
+    // Listeners only stop on error
+    match servers.join_next().await {
+        Some(result) => result?,
+        None => Ok(()),
     }
 }
@@ -0,0 +1,209 @@
+use crate::{
+    edns::Edns,
+    header::{DNSHeader, FLAG_QR, FLAG_RD},
+    packet::DNSPacket,
+    question::DNSQuestion,
+    record::DNSRecord,
+};
+
+/// Assembles a [`DNSPacket`] without having to keep the header counts in sync
+/// by hand.
+///
+/// ```
+/// use diaw::{builder::MessageBuilder, header::FLAG_RD, CLASS_IN, TYPE_A};
+///
+/// let query = MessageBuilder::new()
+///     .id(0x8298)
+///     .flag(FLAG_RD, true)
+///     .question("www.example.com", TYPE_A, CLASS_IN)
+///     .to_be_bytes();
+/// assert_eq!(&query[..4], &[0x82, 0x98, 0x01, 0x00]);
+/// ```
+#[derive(Debug, Default)]
+pub struct MessageBuilder {
+    id: u16,
+    flags: u16,
+    questions: Vec<DNSQuestion>,
+    answers: Vec<DNSRecord>,
+    authorities: Vec<DNSRecord>,
+    additionals: Vec<DNSRecord>,
+    edns: Option<Edns>,
+}
+
+impl MessageBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a response to `query`, copying its ID, opcode, RD bit and
+    /// questions and setting QR.
+    pub fn response_to(query: &DNSPacket) -> Self {
+        let header = query.header();
+        let flags = FLAG_QR | (header.flags & 0x7800) | (header.flags & FLAG_RD);
+        let questions = query
+            .questions()
+            .iter()
+            .map(|q| DNSQuestion {
+                name: q.name.clone(),
+                qtype: q.qtype,
+                class: q.class,
+            })
+            .collect();
+        MessageBuilder {
+            id: header.id,
+            flags,
+            questions,
+            ..Self::default()
+        }
+    }
+
+    pub fn id(mut self, id: u16) -> Self {
+        self.id = id;
+        self
+    }
+
+    pub fn opcode(mut self, opcode: u8) -> Self {
+        self.flags = (self.flags & !0x7800) | ((opcode as u16 & 0xf) << 11);
+        self
+    }
+
+    pub fn rcode(mut self, rcode: u8) -> Self {
+        self.flags = (self.flags & !0x000f) | (rcode as u16 & 0xf);
+        self
+    }
+
+    /// Sets or clears one of the `FLAG_*` bits from [`crate::header`].
+    pub fn flag(mut self, flag: u16, value: bool) -> Self {
+        if value {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+        self
+    }
+
+    pub fn question(mut self, name: &str, qtype: u16, class: u16) -> Self {
+        self.questions.push(DNSQuestion {
+            name: name.to_string(),
+            qtype,
+            class,
+        });
+        self
+    }
+
+    pub fn answer(mut self, record: DNSRecord) -> Self {
+        self.answers.push(record);
+        self
+    }
+
+    pub fn authority(mut self, record: DNSRecord) -> Self {
+        self.authorities.push(record);
+        self
+    }
+
+    pub fn additional(mut self, record: DNSRecord) -> Self {
+        self.additionals.push(record);
+        self
+    }
+
+    /// Attaches an OPT record, replacing any previously set EDNS options.
+    pub fn edns(mut self, edns: Edns) -> Self {
+        self.edns = Some(edns);
+        self
+    }
+
+    pub fn build(self) -> DNSPacket {
+        let mut additionals = self.additionals;
+        if let Some(edns) = self.edns {
+            additionals.push(edns.to_record());
+        }
+        let mut header = DNSHeader::new(self.id, self.flags);
+        header.num_questions = self.questions.len() as u16;
+        header.num_answers = self.answers.len() as u16;
+        header.num_authorities = self.authorities.len() as u16;
+        header.num_additionals = additionals.len() as u16;
+        DNSPacket::from_parts(
+            header,
+            self.questions,
+            self.answers,
+            self.authorities,
+            additionals,
+        )
+    }
+
+    pub fn to_be_bytes(self) -> Vec<u8> {
+        self.build().to_be_bytes()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use bytes::Bytes;
+
+    use crate::{edns::EdnsOption, header::FLAG_AA, record::DNSRecordResult, CLASS_IN, TYPE_A};
+
+    use super::*;
+
+    #[test]
+    fn test_build_response() {
+        let query = MessageBuilder::new()
+            .id(42)
+            .flag(FLAG_RD, true)
+            .question("example.com", TYPE_A, CLASS_IN)
+            .edns(Edns {
+                dnssec_ok: true,
+                options: vec![EdnsOption {
+                    code: 10,
+                    data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+                }],
+                ..Edns::default()
+            })
+            .build();
+        assert_eq!(query.header().num_additionals, 1);
+
+        let wire = MessageBuilder::response_to(&query)
+            .flag(FLAG_AA, true)
+            .answer(DNSRecord::new(
+                "example.com".to_string(),
+                TYPE_A,
+                CLASS_IN,
+                300,
+                DNSRecordResult::Address("93.184.216.34".parse().unwrap()),
+            ))
+            .to_be_bytes();
+        let response = DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::from(wire))).unwrap();
+        assert_eq!(
+            response.header().to_string(),
+            ";; ->>HEADER<<- opcode: QUERY, status: NOERROR, id: 42\n\
+             ;; flags: qr aa rd; QUERY: 1, ANSWER: 1, AUTHORITY: 0, ADDITIONAL: 0"
+        );
+        assert_eq!(response.questions()[0].name, "example.com");
+        assert_eq!(
+            response.answers()[0].to_string(),
+            "example.com.\t300\tIN\tA\t93.184.216.34"
+        );
+    }
+
+    #[test]
+    fn test_edns_round_trip() {
+        let wire = MessageBuilder::new()
+            .question("example.com", TYPE_A, CLASS_IN)
+            .edns(Edns {
+                udp_payload_size: 4096,
+                dnssec_ok: true,
+                options: vec![EdnsOption {
+                    code: 10,
+                    data: vec![0xaa; 8],
+                }],
+                ..Edns::default()
+            })
+            .to_be_bytes();
+        let packet = DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::from(wire))).unwrap();
+        let edns = packet.edns().unwrap();
+        assert_eq!(edns.udp_payload_size, 4096);
+        assert!(edns.dnssec_ok);
+        assert_eq!(edns.options[0].data, vec![0xaa; 8]);
+    }
+}
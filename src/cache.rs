@@ -0,0 +1,924 @@
+//! RRsets learned while resolving, kept until their TTL runs out and shared by
+//! both resolvers.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::ResolveError,
+    header::FLAG_AA,
+    lookup::{cname_target, follow_chain},
+    packet::DNSPacket,
+    record::{DNSRecord, DNSRecordResult},
+    CLASS_IN, RCODE_NOERROR, RCODE_NXDOMAIN, TYPE_A, TYPE_AAAA, TYPE_NS, TYPE_SOA,
+};
+
+/// Longer TTLs are cut down to this, like Unbound's `cache-max-ttl`.
+const MAX_TTL: u32 = 86400;
+
+/// Cap for negative answers, RFC 2308 section 5 suggests one to three hours.
+const MAX_NEGATIVE_TTL: u32 = 10800;
+
+static RECORD_CACHE: RwLock<Option<Arc<RecordCache>>> = RwLock::new(None);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    name: String,
+    /// `None` for NXDOMAIN, which covers every type.
+    qtype: Option<u16>,
+    class: u16,
+}
+
+impl CacheKey {
+    fn new(name: &str, qtype: Option<u16>, class: u16) -> Self {
+        CacheKey {
+            name: normalize(name),
+            qtype,
+            class,
+        }
+    }
+}
+
+/// How much an RRset can be trusted, see RFC 2181 section 5.4.1. Referrals
+/// and glue never replace what an authoritative server answered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Rank {
+    Glue,
+    Referral,
+    Answer,
+    Authoritative,
+}
+
+#[derive(Debug, Clone)]
+enum Data {
+    Records(Vec<DNSRecord>),
+    Negative(ResolveError),
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    data: Data,
+    /// The TTL it was cached with.
+    ttl: u32,
+    expires: Instant,
+    rank: Rank,
+    hits: u32,
+    prefetching: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheConfig {
+    pub max_entries: usize,
+    /// How long past expiry answers are kept to serve when the authorities
+    /// can't be reached, see RFC 8767. Zero turns serve-stale off.
+    pub stale_window: Duration,
+    /// The TTL stale answers are served with, RFC 8767 recommends 30 seconds.
+    pub stale_ttl: u32,
+    /// Answers asked for at least this many times are refreshed in the
+    /// background during the last tenth of their TTL, like Unbound's
+    /// `prefetch`. Zero turns prefetching off.
+    pub prefetch_hits: u32,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            max_entries: 10_000,
+            stale_window: Duration::from_secs(86400),
+            stale_ttl: 30,
+            prefetch_hits: 2,
+        }
+    }
+}
+
+/// A thread-safe cache of RRsets keyed by name, type and class, along with
+/// negative answers.
+#[derive(Debug, Default)]
+pub struct RecordCache {
+    config: CacheConfig,
+    entries: Mutex<HashMap<CacheKey, Entry>>,
+}
+
+impl RecordCache {
+    pub fn new(config: CacheConfig) -> Self {
+        RecordCache {
+            config,
+            entries: Mutex::default(),
+        }
+    }
+
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// The cached data with the time it has left. Expired entries are kept
+    /// for the stale window, and dropped after.
+    fn lookup(&self, key: &CacheKey) -> Option<(Data, u32)> {
+        self.lookup_stale(key)
+            .and_then(|(data, remaining)| Some((data, remaining?)))
+    }
+
+    /// Like [`RecordCache::lookup`], but also returns stale data, without the
+    /// time left.
+    fn lookup_stale(&self, key: &CacheKey) -> Option<(Data, Option<u32>)> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(key)?;
+        if entry.expires + self.config.stale_window <= now {
+            entries.remove(key);
+            return None;
+        }
+        entry.hits = entry.hits.saturating_add(1);
+        let remaining = entry
+            .expires
+            .checked_duration_since(now)
+            .filter(|remaining| !remaining.is_zero())
+            .map(|remaining| remaining.as_secs() as u32);
+        Some((entry.data.clone(), remaining))
+    }
+
+    /// The cached RRset with each TTL lowered to the time it has left, or
+    /// `None` if it isn't cached or has expired.
+    pub fn get(&self, name: &str, qtype: u16, class: u16) -> Option<Vec<DNSRecord>> {
+        match self.lookup(&CacheKey::new(name, Some(qtype), class))? {
+            (Data::Records(records), remaining) => Some(
+                records
+                    .iter()
+                    .map(|record| record.with_ttl(remaining))
+                    .collect(),
+            ),
+            (Data::Negative(_), _) => None,
+        }
+    }
+
+    /// A cached denial that `name` has records of type `qtype`, with the SOA
+    /// TTL lowered to the time it has left. An NXDOMAIN for any ancestor of
+    /// `name` counts too, since nothing exists below a name that doesn't
+    /// exist, see RFC 8020.
+    pub fn negative(&self, name: &str, qtype: u16, class: u16) -> Option<ResolveError> {
+        let name = normalize(name);
+        let nxdomain = ancestors(&name).find_map(|ancestor| {
+            match self.lookup(&CacheKey::new(ancestor, None, class))? {
+                (Data::Negative(ResolveError::NxDomain { soa, .. }), remaining) => {
+                    Some(ResolveError::NxDomain {
+                        name: name.clone(),
+                        soa: soa.with_ttl(remaining),
+                    })
+                }
+                _ => None,
+            }
+        });
+        nxdomain.or_else(
+            || match self.lookup(&CacheKey::new(&name, Some(qtype), class))? {
+                (Data::Negative(ResolveError::NoData { name, qtype, soa }), remaining) => {
+                    Some(ResolveError::NoData {
+                        name,
+                        qtype,
+                        soa: soa.with_ttl(remaining),
+                    })
+                }
+                _ => None,
+            },
+        )
+    }
+
+    /// Caches `records` as one RRset, for as long as the smallest TTL among
+    /// them. RRsets with a TTL of 0 are only good for the current query and
+    /// aren't cached.
+    pub fn insert(&self, records: Vec<DNSRecord>) {
+        self.insert_ranked(records, Rank::Answer);
+    }
+
+    fn insert_ranked(&self, records: Vec<DNSRecord>, rank: Rank) {
+        let Some(first) = records.first() else {
+            return;
+        };
+        let key = CacheKey::new(first.name(), Some(first.qtype()), first.class());
+        let ttl = records.iter().map(DNSRecord::ttl).min().unwrap_or(0);
+        self.store(key, Data::Records(records), ttl.min(MAX_TTL), rank);
+    }
+
+    /// Caches a denial for as long as the SOA record's TTL or its minimum
+    /// field, whichever is lower, see RFC 2308 section 5.
+    fn insert_negative(&self, negative: ResolveError, class: u16, rank: Rank) {
+        let key = match &negative {
+            ResolveError::NxDomain { name, .. } => CacheKey::new(name, None, class),
+            ResolveError::NoData { name, qtype, .. } => CacheKey::new(name, Some(*qtype), class),
+        };
+        let ttl = negative_ttl(negative.soa());
+        self.store(key, Data::Negative(negative), ttl, rank);
+    }
+
+    fn store(&self, key: CacheKey, data: Data, ttl: u32, rank: Rank) {
+        if ttl == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(existing) = entries.get(&key) {
+            if existing.expires > now && existing.rank > rank {
+                return;
+            }
+        }
+        let max_entries = self.config.max_entries;
+        if entries.len() >= max_entries && !entries.contains_key(&key) {
+            let stale_window = self.config.stale_window;
+            entries.retain(|_, entry| entry.expires + stale_window > now);
+            if entries.len() >= max_entries {
+                // Make room by dropping whatever would expire first
+                if let Some(key) = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.expires)
+                    .map(|(key, _)| key.clone())
+                {
+                    entries.remove(&key);
+                }
+            }
+        }
+        let expires = now + Duration::from_secs(ttl.into());
+        entries.insert(
+            key,
+            Entry {
+                data,
+                ttl,
+                expires,
+                rank,
+                hits: 0,
+                prefetching: false,
+            },
+        );
+    }
+
+    /// Caches what a server for `zone` told us about `qname`: the answer
+    /// RRsets for the name itself and the CNAME chain from it as far as it
+    /// stays within `zone`, NS records for the name or one of its
+    /// ancestors within `zone`, glue within `zone` for those nameservers, and
+    /// NXDOMAIN or NODATA answers. Anything else is outside the server's
+    /// bailiwick, could be a spoofing attempt and is ignored.
+    pub fn cache_response(&self, zone: &str, qname: &str, qtype: u16, response: &DNSPacket) {
+        let answer_rank = if response.header().has_flag(FLAG_AA) {
+            Rank::Authoritative
+        } else {
+            Rank::Answer
+        };
+        // Names further down a CNAME chain may be outside the zone of the
+        // server that answered, so they're trusted less
+        let rank = |record: &DNSRecord| {
+            if normalize(record.name()) == normalize(qname) {
+                answer_rank
+            } else {
+                Rank::Answer
+            }
+        };
+        let (cnames, records) = follow_chain_in(zone, qname, qtype, response.answers());
+        for cname in &cnames {
+            self.insert_ranked(vec![cname.clone()], rank(cname));
+        }
+        if let Some(records) = records {
+            let rank = rank(&records[0]);
+            self.insert_ranked(records, rank);
+        }
+        if let Some(negative) = negative_response(zone, qname, qtype, response) {
+            self.insert_negative(negative, CLASS_IN, answer_rank);
+            return;
+        }
+
+        let target = cnames.last().and_then(cname_target).unwrap_or(qname);
+        let delegations: Vec<_> = response
+            .authorities()
+            .iter()
+            .filter(|record| {
+                record.qtype() == TYPE_NS
+                    && is_ancestor(zone, record.name())
+                    && is_ancestor(record.name(), target)
+            })
+            .collect();
+        let nameservers: Vec<_> = delegations
+            .iter()
+            .filter_map(|record| match record.res() {
+                DNSRecordResult::NameServer(ns) => Some(normalize(ns)),
+                _ => None,
+            })
+            .collect();
+        for rrset in group(delegations.into_iter()) {
+            self.insert_ranked(rrset, Rank::Referral);
+        }
+        let glue = response.additionals().iter().filter(|record| {
+            matches!(record.qtype(), TYPE_A | TYPE_AAAA)
+                && is_ancestor(zone, record.name())
+                && nameservers.contains(&normalize(record.name()))
+        });
+        for rrset in group(glue) {
+            self.insert_ranked(rrset, Rank::Glue);
+        }
+    }
+
+    /// The first address record cached for `name`, for tests to check what
+    /// got cached.
+    #[cfg(test)]
+    pub(crate) fn answer(&self, name: &str, qtype: u16) -> Option<DNSRecord> {
+        self.get(name, qtype, CLASS_IN)?
+            .into_iter()
+            .find(|record| matches!(record.res(), DNSRecordResult::Address(_)))
+    }
+
+    /// An expired RRset still within the stale window, with the stale TTL, for
+    /// when it can't be resolved afresh.
+    pub fn get_stale(&self, name: &str, qtype: u16, class: u16) -> Option<Vec<DNSRecord>> {
+        match self.lookup_stale(&CacheKey::new(name, Some(qtype), class))? {
+            (Data::Records(records), None) => Some(
+                records
+                    .iter()
+                    .map(|record| record.with_ttl(self.config.stale_ttl))
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Whether the cached answer for `name` should be refreshed now: it's
+    /// popular, in the last tenth of its TTL, and no one else is refreshing
+    /// it yet. Returns `true` only once per cached answer.
+    pub fn should_prefetch(&self, name: &str, qtype: u16) -> bool {
+        if self.config.prefetch_hits == 0 {
+            return false;
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let Some(entry) = entries.get_mut(&CacheKey::new(name, Some(qtype), CLASS_IN)) else {
+            return false;
+        };
+        let Some(remaining) = entry.expires.checked_duration_since(now) else {
+            return false;
+        };
+        let due = remaining.as_secs() * 10 <= entry.ttl.into();
+        if entry.prefetching || !due || entry.hits < self.config.prefetch_hits {
+            return false;
+        }
+        entry.prefetching = true;
+        true
+    }
+
+    /// Finds the deepest zone cut above or at `name` whose nameservers we
+    /// have addresses for, so resolving can start there rather than at the
+    /// root. Returns the zone and the addresses, IPv4 first.
+    pub fn closest_nameservers(&self, name: &str) -> Option<(String, Vec<IpAddr>)> {
+        ancestors(&normalize(name)).find_map(|zone| {
+            let nameservers = self.get(zone, TYPE_NS, CLASS_IN)?;
+            let addresses = |qtype| {
+                nameservers
+                    .iter()
+                    .filter_map(move |record| match record.res() {
+                        DNSRecordResult::NameServer(ns) => self.get(ns, qtype, CLASS_IN),
+                        _ => None,
+                    })
+                    .flatten()
+                    .filter_map(|record| match record.res() {
+                        DNSRecordResult::Address(ip) => Some(*ip),
+                        _ => None,
+                    })
+            };
+            let addresses: Vec<_> = addresses(TYPE_A).chain(addresses(TYPE_AAAA)).collect();
+            (!addresses.is_empty()).then(|| (zone.to_string(), addresses))
+        })
+    }
+}
+
+/// Like [`follow_chain`] for a response from a server for `zone`, but stops
+/// where the chain leaves the zone. What follows is none of the server's
+/// business, and the target of the last CNAME has to be resolved afresh.
+pub(crate) fn follow_chain_in(
+    zone: &str,
+    qname: &str,
+    qtype: u16,
+    answers: &[DNSRecord],
+) -> (Vec<DNSRecord>, Option<Vec<DNSRecord>>) {
+    let (mut cnames, records) = follow_chain(qname, qtype, answers);
+    if let Some(outside) = cnames
+        .iter()
+        .position(|cname| !is_ancestor(zone, cname.name()))
+    {
+        cnames.truncate(outside);
+        return (cnames, None);
+    }
+    let records = records.filter(|records| is_ancestor(zone, records[0].name()));
+    (cnames, records)
+}
+
+/// Tells NXDOMAIN and NODATA responses from a server for `zone` about
+/// `qname` apart from referrals and answers, going by the SOA record of the
+/// zone in the authority section. Denials without one within `zone` can't
+/// be cached and aren't reported. After a CNAME chain, the denial is about
+/// the name at its end.
+pub(crate) fn negative_response(
+    zone: &str,
+    qname: &str,
+    qtype: u16,
+    response: &DNSPacket,
+) -> Option<ResolveError> {
+    let (cnames, records) = follow_chain_in(zone, qname, qtype, response.answers());
+    if records.is_some() || (cnames.is_empty() && !response.answers().is_empty()) {
+        return None;
+    }
+    let target = cnames.last().and_then(cname_target).unwrap_or(qname);
+    let soa = response
+        .authorities()
+        .iter()
+        .find(|record| {
+            record.qtype() == TYPE_SOA
+                && is_ancestor(zone, record.name())
+                && is_ancestor(record.name(), target)
+        })?
+        .clone();
+    let name = normalize(target);
+    match response.header().rcode() {
+        RCODE_NXDOMAIN => Some(ResolveError::NxDomain { name, soa }),
+        RCODE_NOERROR => Some(ResolveError::NoData { name, qtype, soa }),
+        _ => None,
+    }
+}
+
+/// How long a denial with `soa` may be cached, see RFC 2308 section 5.
+pub(crate) fn negative_ttl(soa: &DNSRecord) -> u32 {
+    let minimum = match soa.res() {
+        DNSRecordResult::Soa(soa) => soa.minimum,
+        _ => 0,
+    };
+    soa.ttl().min(minimum).min(MAX_NEGATIVE_TTL)
+}
+
+/// Names compare case-insensitively, and the trailing dot is optional.
+pub(crate) fn normalize(name: &str) -> String {
+    name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase()
+}
+
+/// `name` itself, then each parent up to and including the root, "".
+fn ancestors(name: &str) -> impl Iterator<Item = &str> {
+    std::iter::successors(Some(name), |name| match *name {
+        "" => None,
+        _ => Some(name.split_once('.').map_or("", |(_, parent)| parent)),
+    })
+}
+
+pub(crate) fn is_ancestor(zone: &str, name: &str) -> bool {
+    let zone = normalize(zone);
+    ancestors(&normalize(name)).any(|ancestor| ancestor == zone)
+}
+
+/// Splits records into RRsets, keeping the order they came in.
+fn group<'a>(records: impl Iterator<Item = &'a DNSRecord>) -> Vec<Vec<DNSRecord>> {
+    let mut rrsets: Vec<Vec<DNSRecord>> = Vec::new();
+    for record in records {
+        let key = CacheKey::new(record.name(), Some(record.qtype()), record.class());
+        match rrsets.iter_mut().find(|rrset| {
+            CacheKey::new(rrset[0].name(), Some(rrset[0].qtype()), rrset[0].class()) == key
+        }) {
+            Some(rrset) => rrset.push(record.clone()),
+            None => rrsets.push(vec![record.clone()]),
+        }
+    }
+    rrsets
+}
+
+/// The cache both resolvers share, created on first use unless set with
+/// [`set_record_cache`].
+pub fn record_cache() -> Arc<RecordCache> {
+    if let Some(cache) = RECORD_CACHE.read().unwrap().as_ref() {
+        return cache.clone();
+    }
+    RECORD_CACHE
+        .write()
+        .unwrap()
+        .get_or_insert_with(Arc::default)
+        .clone()
+}
+
+pub fn set_record_cache(cache: RecordCache) {
+    *RECORD_CACHE.write().unwrap() = Some(Arc::new(cache));
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{builder::MessageBuilder, TYPE_CNAME};
+
+    use super::*;
+
+    fn address(name: &str, ttl: u32, ip: &str) -> DNSRecord {
+        let qtype = if ip.contains(':') { TYPE_AAAA } else { TYPE_A };
+        DNSRecord::new(
+            name.to_string(),
+            qtype,
+            CLASS_IN,
+            ttl,
+            DNSRecordResult::Address(ip.parse().unwrap()),
+        )
+    }
+
+    fn ns(zone: &str, ttl: u32, ns: &str) -> DNSRecord {
+        DNSRecord::new(
+            zone.to_string(),
+            TYPE_NS,
+            CLASS_IN,
+            ttl,
+            DNSRecordResult::NameServer(ns.to_string()),
+        )
+    }
+
+    #[test]
+    fn test_ancestors() {
+        assert_eq!(
+            ancestors("www.example.com").collect::<Vec<_>>(),
+            ["www.example.com", "example.com", "com", ""]
+        );
+        assert_eq!(ancestors("").collect::<Vec<_>>(), [""]);
+        assert!(is_ancestor("Example.COM.", "www.example.com"));
+        assert!(!is_ancestor("ample.com", "www.example.com"));
+    }
+
+    #[test]
+    fn test_insert_and_get() {
+        let cache = RecordCache::default();
+        cache.insert(vec![
+            address("www.example.com", 300, "192.0.2.1"),
+            address("www.example.com", 60, "192.0.2.2"),
+        ]);
+        let rrset = cache.get("WWW.example.com.", TYPE_A, CLASS_IN).unwrap();
+        assert_eq!(rrset.len(), 2);
+        // The whole RRset lives as long as its shortest TTL
+        assert!(rrset.iter().all(|record| (59..=60).contains(&record.ttl())));
+        assert!(cache.get("www.example.com", TYPE_AAAA, CLASS_IN).is_none());
+
+        cache.insert(vec![address("now.example.com", 0, "192.0.2.3")]);
+        assert!(cache.get("now.example.com", TYPE_A, CLASS_IN).is_none());
+
+        cache.insert(vec![address("long.example.com", u32::MAX, "192.0.2.4")]);
+        let record = cache.answer("long.example.com", TYPE_A).unwrap();
+        assert!(record.ttl() <= MAX_TTL);
+    }
+
+    #[test]
+    fn test_max_entries() {
+        let cache = RecordCache::new(CacheConfig {
+            max_entries: 2,
+            ..CacheConfig::default()
+        });
+        cache.insert(vec![address("a.example", 10, "192.0.2.1")]);
+        cache.insert(vec![address("b.example", 20, "192.0.2.2")]);
+        cache.insert(vec![address("c.example", 30, "192.0.2.3")]);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("a.example", TYPE_A, CLASS_IN).is_none());
+        assert!(cache.get("c.example", TYPE_A, CLASS_IN).is_some());
+    }
+
+    #[test]
+    fn test_cache_referral() {
+        let cache = RecordCache::default();
+        let referral = MessageBuilder::new()
+            .authority(ns("example.com", 3600, "a.iana-servers.net"))
+            .authority(ns("example.com", 3600, "ns.example.com"))
+            // Not a parent of the name asked for
+            .authority(ns("example.org", 3600, "ns.evil.test"))
+            .additional(address("ns.example.com", 3600, "192.0.2.53"))
+            .additional(address("ns.example.com", 3600, "2001:db8::53"))
+            .additional(address("ns.evil.test", 3600, "192.0.2.66"))
+            .additional(address("www.example.com", 3600, "192.0.2.66"))
+            .build();
+        cache.cache_response("com", "www.example.com", TYPE_A, &referral);
+        assert!(cache.get("example.org", TYPE_NS, CLASS_IN).is_none());
+        assert!(cache.get("ns.evil.test", TYPE_A, CLASS_IN).is_none());
+        assert!(cache.answer("www.example.com", TYPE_A).is_none());
+
+        let (zone, addresses) = cache.closest_nameservers("mail.example.com").unwrap();
+        assert_eq!(zone, "example.com");
+        assert_eq!(
+            addresses,
+            [
+                "192.0.2.53".parse::<IpAddr>().unwrap(),
+                "2001:db8::53".parse().unwrap()
+            ]
+        );
+        assert!(cache.closest_nameservers("example.net").is_none());
+
+        // A deeper cut wins once it's known
+        let referral = MessageBuilder::new()
+            .authority(ns("sub.example.com", 3600, "ns.sub.example.com"))
+            .additional(address("ns.sub.example.com", 3600, "192.0.2.54"))
+            .build();
+        cache.cache_response("example.com", "www.sub.example.com", TYPE_A, &referral);
+        let (zone, _) = cache.closest_nameservers("www.sub.example.com").unwrap();
+        assert_eq!(zone, "sub.example.com");
+        let (zone, _) = cache.closest_nameservers("www.example.com").unwrap();
+        assert_eq!(zone, "example.com");
+    }
+
+    #[test]
+    fn test_child_cannot_override_parent_delegation() {
+        let cache = RecordCache::default();
+        let referral = MessageBuilder::new()
+            .authority(ns("example.com", 3600, "ns.example.com"))
+            .additional(address("ns.example.com", 3600, "192.0.2.53"))
+            .build();
+        cache.cache_response("com", "www.sub.example.com", TYPE_A, &referral);
+        // The server for sub.example.com has no say over its parents
+        let hijack = MessageBuilder::new()
+            .authority(ns("example.com", 3600, "ns.evil.test"))
+            .authority(ns("com", 3600, "ns.evil.test"))
+            .authority(ns("", 3600, "ns.evil.test"))
+            .additional(address("ns.evil.test", 3600, "192.0.2.66"))
+            .additional(address("ns.example.com", 3600, "192.0.2.66"))
+            .build();
+        cache.cache_response("sub.example.com", "www.sub.example.com", TYPE_A, &hijack);
+        let nameservers = cache.get("example.com", TYPE_NS, CLASS_IN).unwrap();
+        assert_eq!(nameservers.len(), 1);
+        assert!(matches!(
+            nameservers[0].res(),
+            DNSRecordResult::NameServer(ns) if ns == "ns.example.com"
+        ));
+        assert!(cache.get("com", TYPE_NS, CLASS_IN).is_none());
+        assert!(cache.get("", TYPE_NS, CLASS_IN).is_none());
+        assert!(cache.get("ns.evil.test", TYPE_A, CLASS_IN).is_none());
+        let (zone, addresses) = cache.closest_nameservers("www.sub.example.com").unwrap();
+        assert_eq!(zone, "example.com");
+        assert_eq!(addresses, ["192.0.2.53".parse::<IpAddr>().unwrap()]);
+    }
+
+    #[test]
+    fn test_glue_does_not_replace_answers() {
+        let cache = RecordCache::default();
+        let answer = MessageBuilder::new()
+            .flag(FLAG_AA, true)
+            .answer(address("ns.example.com", 3600, "192.0.2.53"))
+            .build();
+        cache.cache_response("example.com", "ns.example.com", TYPE_A, &answer);
+        let referral = MessageBuilder::new()
+            .authority(ns("example.com", 3600, "ns.example.com"))
+            .additional(address("ns.example.com", 3600, "192.0.2.99"))
+            .build();
+        cache.cache_response("com", "www.example.com", TYPE_A, &referral);
+        assert_eq!(
+            cache
+                .answer("ns.example.com", TYPE_A)
+                .unwrap()
+                .res()
+                .to_rdata(),
+            [192, 0, 2, 53]
+        );
+    }
+
+    fn soa(zone: &str, ttl: u32, minimum: u32) -> DNSRecord {
+        format!("{zone}. {ttl} IN SOA ns.{zone}. hostmaster.{zone}. 1 7200 3600 1209600 {minimum}")
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_nxdomain() {
+        let cache = RecordCache::default();
+        let nxdomain = MessageBuilder::new()
+            .rcode(RCODE_NXDOMAIN)
+            .flag(FLAG_AA, true)
+            .authority(soa("example.com", 3600, 300))
+            .build();
+        cache.cache_response("example.com", "gone.example.com", TYPE_A, &nxdomain);
+
+        let Some(ResolveError::NxDomain { name, soa }) =
+            cache.negative("Gone.example.com.", TYPE_AAAA, CLASS_IN)
+        else {
+            panic!("NXDOMAIN covers every type");
+        };
+        assert_eq!(name, "gone.example.com");
+        // The SOA minimum is lower than its TTL
+        assert!((299..=300).contains(&soa.ttl()));
+
+        // Nothing exists below a name that doesn't exist
+        assert!(matches!(
+            cache.negative("www.gone.example.com", TYPE_A, CLASS_IN),
+            Some(ResolveError::NxDomain { name, .. }) if name == "www.gone.example.com"
+        ));
+        assert!(cache.negative("example.com", TYPE_A, CLASS_IN).is_none());
+        assert!(cache
+            .negative("other.example.com", TYPE_A, CLASS_IN)
+            .is_none());
+    }
+
+    #[test]
+    fn test_cname_chain() {
+        let cache = RecordCache::default();
+        let cname = |line: &str| line.parse::<DNSRecord>().unwrap();
+        let answer = MessageBuilder::new()
+            .flag(FLAG_AA, true)
+            .answer(cname("www.example.com. 300 IN CNAME cdn.example.com."))
+            .answer(address("cdn.example.com", 60, "192.0.2.1"))
+            .build();
+        cache.cache_response("example.com", "www.example.com", TYPE_A, &answer);
+        assert!(cache.get("www.example.com", TYPE_CNAME, CLASS_IN).is_some());
+        assert!(cache.get("www.example.com", TYPE_A, CLASS_IN).is_none());
+        assert!(cache.answer("cdn.example.com", TYPE_A).is_some());
+
+        // The server for example.com has no say over example.net
+        let answer = MessageBuilder::new()
+            .flag(FLAG_AA, true)
+            .answer(cname("mail.example.com. 300 IN CNAME mail.example.net."))
+            .answer(address("mail.example.net", 60, "192.0.2.66"))
+            .build();
+        cache.cache_response("example.com", "mail.example.com", TYPE_A, &answer);
+        assert!(cache
+            .get("mail.example.com", TYPE_CNAME, CLASS_IN)
+            .is_some());
+        assert!(cache.answer("mail.example.net", TYPE_A).is_none());
+
+        // The denial is for the end of the chain
+        let nxdomain = MessageBuilder::new()
+            .rcode(RCODE_NXDOMAIN)
+            .flag(FLAG_AA, true)
+            .answer(cname("old.example.com. 300 IN CNAME gone.example.com."))
+            .authority(soa("example.com", 3600, 300))
+            .build();
+        let negative =
+            negative_response("example.com", "old.example.com", TYPE_A, &nxdomain).unwrap();
+        assert!(matches!(
+            &negative,
+            ResolveError::NxDomain { name, .. } if name == "gone.example.com"
+        ));
+        assert!(matches!(
+            negative.for_alias("old.example.com"),
+            ResolveError::NxDomain { name, .. } if name == "old.example.com"
+        ));
+        cache.cache_response("example.com", "old.example.com", TYPE_A, &nxdomain);
+        assert!(cache
+            .negative("gone.example.com", TYPE_A, CLASS_IN)
+            .is_some());
+        assert!(cache
+            .negative("old.example.com", TYPE_A, CLASS_IN)
+            .is_none());
+
+        // Nor can it deny names outside its zone, which would take all of
+        // com with it, see RFC 8020
+        let nxdomain = MessageBuilder::new()
+            .rcode(RCODE_NXDOMAIN)
+            .flag(FLAG_AA, true)
+            .answer(cname("evil.example.com. 300 IN CNAME com."))
+            .authority(soa("com", 3600, 300))
+            .build();
+        assert!(negative_response("example.com", "evil.example.com", TYPE_A, &nxdomain).is_none());
+        cache.cache_response("example.com", "evil.example.com", TYPE_A, &nxdomain);
+        assert!(cache.negative("com", TYPE_A, CLASS_IN).is_none());
+        assert!(cache.negative("other.com", TYPE_A, CLASS_IN).is_none());
+    }
+
+    #[test]
+    fn test_nodata() {
+        let cache = RecordCache::default();
+        let nodata = MessageBuilder::new()
+            .flag(FLAG_AA, true)
+            .authority(soa("example.com", 60, 3600))
+            .build();
+        cache.cache_response("example.com", "www.example.com", TYPE_AAAA, &nodata);
+        let negative = cache
+            .negative("www.example.com", TYPE_AAAA, CLASS_IN)
+            .unwrap();
+        assert!(matches!(
+            negative,
+            ResolveError::NoData {
+                qtype: TYPE_AAAA,
+                ..
+            }
+        ));
+        assert!((59..=60).contains(&negative.soa().ttl()));
+        assert!(cache
+            .negative("www.example.com", TYPE_A, CLASS_IN)
+            .is_none());
+        assert!(cache.get("www.example.com", TYPE_AAAA, CLASS_IN).is_none());
+
+        // An answer replaces the denial
+        let answer = MessageBuilder::new()
+            .flag(FLAG_AA, true)
+            .answer(address("www.example.com", 60, "2001:db8::80"))
+            .build();
+        cache.cache_response("example.com", "www.example.com", TYPE_AAAA, &answer);
+        assert!(cache
+            .negative("www.example.com", TYPE_AAAA, CLASS_IN)
+            .is_none());
+        assert!(cache.answer("www.example.com", TYPE_AAAA).is_some());
+    }
+
+    #[test]
+    fn test_uncacheable_denials() {
+        let cache = RecordCache::default();
+        // Without an SOA there's no telling how long the denial holds
+        let nxdomain = MessageBuilder::new().rcode(RCODE_NXDOMAIN).build();
+        cache.cache_response("example.com", "gone.example.com", TYPE_A, &nxdomain);
+        // An SOA for some unrelated zone doesn't count
+        let nxdomain = MessageBuilder::new()
+            .rcode(RCODE_NXDOMAIN)
+            .authority(soa("example.org", 3600, 3600))
+            .build();
+        cache.cache_response("example.com", "gone.example.com", TYPE_A, &nxdomain);
+        // Capped like RFC 2308 suggests
+        let nxdomain = MessageBuilder::new()
+            .rcode(RCODE_NXDOMAIN)
+            .authority(soa("example.net", u32::MAX, u32::MAX))
+            .build();
+        cache.cache_response("example.net", "gone.example.net", TYPE_A, &nxdomain);
+
+        assert!(cache
+            .negative("gone.example.com", TYPE_A, CLASS_IN)
+            .is_none());
+        let negative = cache
+            .negative("gone.example.net", TYPE_A, CLASS_IN)
+            .unwrap();
+        assert!(negative.soa().ttl() <= MAX_NEGATIVE_TTL);
+    }
+
+    /// Moves the expiry of a cached RRset `by` into the past.
+    fn age(cache: &RecordCache, name: &str, qtype: u16, by: Duration) {
+        let mut entries = cache.entries.lock().unwrap();
+        let key = CacheKey::new(name, Some(qtype), CLASS_IN);
+        entries.get_mut(&key).unwrap().expires -= by;
+    }
+
+    #[test]
+    fn test_serve_stale() {
+        // Short enough for the test's Instants to stay after boot
+        let cache = RecordCache::new(CacheConfig {
+            stale_window: Duration::from_secs(600),
+            ..CacheConfig::default()
+        });
+        cache.insert(vec![address("www.example.com", 60, "192.0.2.1")]);
+        assert!(cache
+            .get_stale("www.example.com", TYPE_A, CLASS_IN)
+            .is_none());
+
+        age(&cache, "www.example.com", TYPE_A, Duration::from_secs(61));
+        assert!(cache.answer("www.example.com", TYPE_A).is_none());
+        let stale = cache
+            .get_stale("www.example.com", TYPE_A, CLASS_IN)
+            .unwrap();
+        assert_eq!(stale[0].ttl(), cache.config().stale_ttl);
+
+        age(
+            &cache,
+            "www.example.com",
+            TYPE_A,
+            cache.config().stale_window,
+        );
+        assert!(cache
+            .get_stale("www.example.com", TYPE_A, CLASS_IN)
+            .is_none());
+        assert!(cache.is_empty());
+
+        let cache = RecordCache::new(CacheConfig {
+            stale_window: Duration::ZERO,
+            ..CacheConfig::default()
+        });
+        cache.insert(vec![address("www.example.com", 60, "192.0.2.1")]);
+        age(&cache, "www.example.com", TYPE_A, Duration::from_secs(61));
+        assert!(cache
+            .get_stale("www.example.com", TYPE_A, CLASS_IN)
+            .is_none());
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let cache = RecordCache::default();
+        cache.insert(vec![address("popular.example", 100, "192.0.2.1")]);
+        cache.insert(vec![address("rare.example", 100, "192.0.2.2")]);
+        for _ in 0..2 {
+            cache.answer("popular.example", TYPE_A).unwrap();
+        }
+        cache.answer("rare.example", TYPE_A).unwrap();
+        assert!(!cache.should_prefetch("popular.example", TYPE_A));
+
+        // In the last tenth of the TTL
+        age(&cache, "popular.example", TYPE_A, Duration::from_secs(91));
+        age(&cache, "rare.example", TYPE_A, Duration::from_secs(91));
+        assert!(cache.should_prefetch("popular.example", TYPE_A));
+        assert!(!cache.should_prefetch("popular.example", TYPE_A));
+        assert!(!cache.should_prefetch("rare.example", TYPE_A));
+        assert!(!cache.should_prefetch("unknown.example", TYPE_A));
+
+        // The refreshed answer can be prefetched again
+        cache.insert(vec![address("popular.example", 100, "192.0.2.3")]);
+        for _ in 0..2 {
+            cache.answer("popular.example", TYPE_A).unwrap();
+        }
+        age(&cache, "popular.example", TYPE_A, Duration::from_secs(95));
+        assert!(cache.should_prefetch("popular.example", TYPE_A));
+    }
+}
@@ -0,0 +1,95 @@
+use bytes::{Buf, BufMut};
+
+use crate::{
+    record::{DNSRecord, DNSRecordResult},
+    TYPE_OPT,
+};
+
+const FLAG_DO: u32 = 0x8000;
+
+/// A single EDNS(0) option, see RFC 6891 section 6.1.2.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+/// The contents of an OPT pseudo-record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edns {
+    pub udp_payload_size: u16,
+    pub extended_rcode: u8,
+    pub version: u8,
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+impl Default for Edns {
+    fn default() -> Self {
+        Edns {
+            udp_payload_size: 1232,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+}
+
+impl Edns {
+    /// Encodes as an OPT record for the additional section. The class holds
+    /// the payload size and the TTL holds the extended flags.
+    pub fn to_record(&self) -> DNSRecord {
+        let mut ttl = ((self.extended_rcode as u32) << 24) | ((self.version as u32) << 16);
+        if self.dnssec_ok {
+            ttl |= FLAG_DO;
+        }
+        let mut data = Vec::new();
+        for option in &self.options {
+            data.put_u16(option.code);
+            data.put_u16(option.data.len() as u16);
+            data.put_slice(&option.data);
+        }
+        DNSRecord::new(
+            String::new(),
+            TYPE_OPT,
+            self.udp_payload_size,
+            ttl,
+            DNSRecordResult::Unknown(data),
+        )
+    }
+
+    /// Returns `None` if the record isn't a well-formed OPT record.
+    pub fn from_record(record: &DNSRecord) -> Option<Self> {
+        let DNSRecordResult::Unknown(data) = record.res() else {
+            return None;
+        };
+        if record.qtype() != TYPE_OPT {
+            return None;
+        }
+        let mut options = Vec::new();
+        let mut data = &data[..];
+        while data.remaining() >= 4 {
+            let code = data.get_u16();
+            let len = data.get_u16() as usize;
+            if data.remaining() < len {
+                return None;
+            }
+            options.push(EdnsOption {
+                code,
+                data: data.copy_to_bytes(len).to_vec(),
+            });
+        }
+        if data.has_remaining() {
+            return None;
+        }
+        let ttl = record.ttl();
+        Some(Edns {
+            udp_payload_size: record.class(),
+            extended_rcode: (ttl >> 24) as u8,
+            version: (ttl >> 16) as u8,
+            dnssec_ok: ttl & FLAG_DO != 0,
+            options,
+        })
+    }
+}
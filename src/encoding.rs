@@ -2,61 +2,117 @@ use std::io::{Seek, SeekFrom};
 
 use bytes::Buf;
 
-pub fn decode_compressed_name<B>(length: u8, reader: &mut B) -> String
-where
-    B: Buf + Seek,
-{
-    let mut pointer_bytes = vec![length & 0b0011_1111];
-    pointer_bytes.push(reader.get_u8());
-    let pointer = u16::from_be_bytes(pointer_bytes.try_into().unwrap()) as u64;
-
-    let current_pos = reader.stream_position().unwrap();
-    reader.seek(SeekFrom::Start(pointer)).unwrap();
-    let name = decode_name(reader);
-    reader.seek(SeekFrom::Start(current_pos)).unwrap();
-    name
+/// Names are at most 255 bytes on the wire, see RFC 1035 section 3.1.
+const MAX_NAME_LEN: usize = 255;
+
+/// Fails unless `bytes` has at least `len` more bytes to read.
+pub(crate) fn ensure_remaining<B: Buf>(bytes: &B, len: usize) -> eyre::Result<()> {
+    if bytes.remaining() < len {
+        eyre::bail!(
+            "message truncated: needed {len} more bytes, {} left",
+            bytes.remaining()
+        );
+    }
+    Ok(())
 }
 
-pub fn decode_name<B>(bytes: &mut B) -> String
+/// Formats `bytes` as hex digits, as used for raw messages and RDATA in
+/// JSON and fixture files.
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02X}")).collect()
+}
+
+/// Parses the hex digits written by [`to_hex`], in either case.
+pub(crate) fn from_hex(hex: &str) -> eyre::Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        eyre::bail!("hex string has an odd number of digits");
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| eyre::eyre!("invalid hex digits at offset {i}"))
+        })
+        .collect()
+}
+
+/// Decodes a possibly compressed name, leaving `bytes` after it.
+///
+/// Every compression pointer has to point before the labels read so far, so
+/// pointers can't loop, and the name can't grow past 255 bytes.
+pub fn decode_name<B>(bytes: &mut B) -> eyre::Result<String>
 where
     B: Buf + Seek,
 {
     let mut acc: Vec<String> = Vec::new();
-    let mut len = bytes.get_u8();
-    while len != 0 {
-        // First two bits are 0x11, so name is compressed
-        if (len & 0b1100_0000) == 0b1100_0000 {
-            let s = decode_compressed_name(len, bytes);
-            acc.push(s);
-            break;
-        } else {
-            let s = bytes.copy_to_bytes(len as usize);
-            let s = String::from_utf8(s.to_vec()).unwrap();
-            acc.push(s);
+    let mut name_len = 1;
+    // Where the labels being read started, pointers must go before it
+    let mut start = bytes.stream_position()?;
+    // Where the name ends in the message, once a pointer was followed
+    let mut end = None;
+    loop {
+        ensure_remaining(bytes, 1)?;
+        let len = bytes.get_u8();
+        match len & 0b1100_0000 {
+            0 if len == 0 => break,
+            0 => {
+                let len = len as usize;
+                ensure_remaining(bytes, len)?;
+                name_len += len + 1;
+                if name_len > MAX_NAME_LEN {
+                    eyre::bail!("name longer than {MAX_NAME_LEN} bytes");
+                }
+                let label = bytes.copy_to_bytes(len);
+                acc.push(String::from_utf8(label.to_vec())?);
+            }
+            // First two bits are 0b11, so the rest of the name is elsewhere
+            0b1100_0000 => {
+                ensure_remaining(bytes, 1)?;
+                let pointer = u16::from_be_bytes([len & 0b0011_1111, bytes.get_u8()]) as u64;
+                if pointer >= start {
+                    eyre::bail!("compression pointer to {pointer} doesn't point backwards");
+                }
+                end.get_or_insert(bytes.stream_position()?);
+                bytes.seek(SeekFrom::Start(pointer))?;
+                start = pointer;
+            }
+            _ => eyre::bail!("unsupported label type {len:#04x}"),
         }
-        len = bytes.get_u8();
     }
-    acc.join(".")
+    if let Some(end) = end {
+        bytes.seek(SeekFrom::Start(end))?;
+    }
+    Ok(acc.join("."))
 }
 
-pub fn decode_name_simple<B>(bytes: &mut B) -> String
+/// Decodes an uncompressed name, or `None` if it runs past the end of
+/// `bytes`, uses compression or isn't UTF-8.
+pub fn decode_name_simple<B>(bytes: &mut B) -> Option<String>
 where
     B: Buf,
 {
     let mut acc: Vec<String> = Vec::new();
-    let mut len = bytes.get_u8() as usize;
-    while len != 0 {
+    loop {
+        if !bytes.has_remaining() {
+            return None;
+        }
+        let len = bytes.get_u8() as usize;
+        if len == 0 {
+            return Some(acc.join("."));
+        }
+        if len > 63 || bytes.remaining() < len {
+            return None;
+        }
         let s = bytes.copy_to_bytes(len);
-        let s = String::from_utf8(s.to_vec()).unwrap();
-        acc.push(s);
-        len = bytes.get_u8() as usize;
+        acc.push(String::from_utf8(s.to_vec()).ok()?);
     }
-    acc.join(".")
 }
 
 pub fn encode_domain_name(domain_name: &str) -> Vec<u8> {
     let mut bytes = Vec::new();
-    for label in domain_name.split('.') {
+    // Skipping empty labels handles both the root name and a trailing dot
+    for label in domain_name.split('.').filter(|label| !label.is_empty()) {
         bytes.push(label.len() as u8);
         bytes.extend_from_slice(label.as_bytes());
     }
@@ -66,22 +122,62 @@ pub fn encode_domain_name(domain_name: &str) -> Vec<u8> {
 
 #[cfg(test)]
 mod test {
+    use std::io::Cursor;
 
     use bytes::Bytes;
 
     use super::*;
 
+    #[test]
+    fn test_hex() {
+        assert_eq!(to_hex(&[0x00, 0x8a, 0xff]), "008AFF");
+        assert_eq!(from_hex("008aFF").unwrap(), vec![0x00, 0x8a, 0xff]);
+        assert!(from_hex("008").is_err());
+        assert!(from_hex("0g").is_err());
+        assert!(from_hex("é0").is_err());
+    }
+
     #[test]
     fn test_decode_domain() {
         let decoded = decode_name_simple(&mut Bytes::from_static(
             b"\x03www\x07example\x03com\x00\x00\x01",
         ));
-        assert_eq!("www.example.com", decoded)
+        assert_eq!(Some("www.example.com".to_string()), decoded);
+        assert_eq!(decode_name_simple(&mut &b"\x03www\x07exa"[..]), None);
+        assert_eq!(decode_name_simple(&mut &b"\x03www"[..]), None);
+        assert_eq!(decode_name_simple(&mut &b"\xc0\x0c"[..]), None);
+    }
+
+    #[test]
+    fn test_decode_compressed() {
+        let message = b"\x03www\x07example\x03com\x00\x04mail\xc0\x04\xff";
+        let mut cursor = Cursor::new(Bytes::from_static(message));
+        assert_eq!(decode_name(&mut cursor).unwrap(), "www.example.com");
+        assert_eq!(decode_name(&mut cursor).unwrap(), "mail.example.com");
+        assert_eq!(cursor.get_u8(), 0xff);
+
+        // Loops, forward pointers and names running off the end
+        for message in [
+            &b"\xc0\x00"[..],
+            b"\x03www\xc0\x00",
+            b"\xc0\x02\x00",
+            b"\x03ww",
+            b"\x03www",
+            b"\xc0",
+            b"\x80",
+        ] {
+            let mut cursor = Cursor::new(Bytes::from_static(message));
+            assert!(decode_name(&mut cursor).is_err(), "{message:?}");
+        }
+        let long = [&b"\x3f"[..], &[b'a'; 63]].concat().repeat(4);
+        assert!(decode_name(&mut Cursor::new(Bytes::from(long))).is_err());
     }
 
     #[test]
     fn test_encode_dns_name() {
         let xs = encode_domain_name("google.com");
         assert_eq!(xs, b"\x06google\x03com\x00");
+        assert_eq!(encode_domain_name("google.com."), xs);
+        assert_eq!(encode_domain_name(""), b"\x00");
     }
 }
@@ -0,0 +1,91 @@
+use std::{fmt, net::SocketAddr, time::Duration};
+
+use crate::{record::DNSRecord, types::type_to_string};
+
+/// Failures of a query that aren't plain network errors.
+///
+/// Query functions return these inside an [`eyre::Report`], use
+/// `report.downcast_ref::<QueryError>()` to tell them apart from I/O errors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryError {
+    /// Datagrams arrived but none of them answered our query.
+    NoMatchingResponse {
+        server: SocketAddr,
+        discarded: usize,
+    },
+    /// Nothing arrived in any of the attempts.
+    Timeout {
+        server: SocketAddr,
+        attempts: u32,
+        elapsed: Duration,
+    },
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::NoMatchingResponse { server, discarded } => write!(
+                f,
+                "no matching response from {server} ({discarded} mismatched responses discarded)"
+            ),
+            QueryError::Timeout {
+                server,
+                attempts,
+                elapsed,
+            } => write!(
+                f,
+                "no response from {server} after {attempts} attempts in {elapsed:.1?}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+/// Authoritative denials, see RFC 2308. Resolvers return these inside an
+/// [`eyre::Report`] like [`QueryError`].
+///
+/// The TTL of the zone's SOA record is how long the denial may be cached, and
+/// is lowered for cached denials like any other TTL.
+#[derive(Debug, Clone)]
+pub enum ResolveError {
+    /// The name doesn't exist, and neither does anything below it.
+    NxDomain { name: String, soa: DNSRecord },
+    /// The name exists but has no records of the type asked for.
+    NoData {
+        name: String,
+        qtype: u16,
+        soa: DNSRecord,
+    },
+}
+
+impl ResolveError {
+    pub fn soa(&self) -> &DNSRecord {
+        match self {
+            ResolveError::NxDomain { soa, .. } | ResolveError::NoData { soa, .. } => soa,
+        }
+    }
+
+    /// The same denial reported for `name`, which led to the denied name
+    /// through a CNAME chain.
+    pub(crate) fn for_alias(self, name: &str) -> Self {
+        let name = name.to_string();
+        match self {
+            ResolveError::NxDomain { soa, .. } => ResolveError::NxDomain { name, soa },
+            ResolveError::NoData { qtype, soa, .. } => ResolveError::NoData { name, qtype, soa },
+        }
+    }
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolveError::NxDomain { name, .. } => write!(f, "{name} does not exist"),
+            ResolveError::NoData { name, qtype, .. } => {
+                write!(f, "{name} has no {} records", type_to_string(*qtype))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {}
@@ -1,6 +1,21 @@
+use std::fmt;
+
 use bytes::Buf;
 
-#[derive(Debug)]
+use crate::{
+    encoding::ensure_remaining,
+    types::{opcode_to_string, rcode_to_string},
+};
+
+pub const FLAG_QR: u16 = 0x8000;
+pub const FLAG_AA: u16 = 0x0400;
+pub const FLAG_TC: u16 = 0x0200;
+pub const FLAG_RD: u16 = 0x0100;
+pub const FLAG_RA: u16 = 0x0080;
+pub const FLAG_AD: u16 = 0x0020;
+pub const FLAG_CD: u16 = 0x0010;
+
+#[derive(Debug, Clone)]
 pub struct DNSHeader {
     pub id: u16,
     pub flags: u16,
@@ -34,20 +49,64 @@ impl DNSHeader {
         bytes
     }
 
-    pub fn parse_header<B: Buf>(bytes: &mut B) -> Self {
+    pub fn parse_header<B: Buf>(bytes: &mut B) -> eyre::Result<Self> {
+        ensure_remaining(bytes, 12)?;
         let id = bytes.get_u16();
         let flags = bytes.get_u16();
         let num_questions = bytes.get_u16();
         let num_answers = bytes.get_u16();
         let num_authorities = bytes.get_u16();
         let num_additionals = bytes.get_u16();
-        Self {
+        Ok(Self {
             id,
             flags,
             num_questions,
             num_answers,
             num_authorities,
             num_additionals,
-        }
+        })
+    }
+
+    pub fn opcode(&self) -> u8 {
+        ((self.flags >> 11) & 0xf) as u8
+    }
+
+    pub fn rcode(&self) -> u8 {
+        (self.flags & 0xf) as u8
+    }
+
+    pub fn has_flag(&self, flag: u16) -> bool {
+        self.flags & flag != 0
+    }
+}
+
+impl fmt::Display for DNSHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            ";; ->>HEADER<<- opcode: {}, status: {}, id: {}",
+            opcode_to_string(self.opcode()),
+            rcode_to_string(self.rcode()),
+            self.id
+        )?;
+        let flags = [
+            (FLAG_QR, "qr"),
+            (FLAG_AA, "aa"),
+            (FLAG_TC, "tc"),
+            (FLAG_RD, "rd"),
+            (FLAG_RA, "ra"),
+            (FLAG_AD, "ad"),
+            (FLAG_CD, "cd"),
+        ]
+        .iter()
+        .filter(|(flag, _)| self.has_flag(*flag))
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join(" ");
+        write!(
+            f,
+            ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            flags, self.num_questions, self.num_answers, self.num_authorities, self.num_additionals
+        )
     }
 }
@@ -0,0 +1,301 @@
+//! Root server hints, where iterative resolution starts.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+use rand::seq::SliceRandom;
+
+use crate::{
+    packet::DNSPacket,
+    r#async::query::send_query_any_async,
+    record::{DNSRecord, DNSRecordResult},
+    simple::query::{send_query_any, QueryOptions},
+    RCODE_NOERROR, TYPE_A, TYPE_AAAA, TYPE_NS,
+};
+
+/// IANA's copy, see <https://www.iana.org/domains/root/files>.
+const NAMED_ROOT: &str = include_str!("named.root");
+
+static ROOT_HINTS: RwLock<Option<Arc<RootHints>>> = RwLock::new(None);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RootServer {
+    pub name: String,
+    pub addresses: Vec<IpAddr>,
+}
+
+/// The root servers to start resolving from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RootHints {
+    servers: Vec<RootServer>,
+}
+
+impl Default for RootHints {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+impl RootHints {
+    /// The 13 root servers with their IPv4 and IPv6 addresses, as published by
+    /// IANA.
+    pub fn builtin() -> Self {
+        Self::from_named_root(NAMED_ROOT).expect("built-in root hints are valid")
+    }
+
+    /// Parses a `named.root` file: NS records for the root and A/AAAA records
+    /// for the servers they name, in zone file syntax.
+    pub fn from_named_root(text: &str) -> eyre::Result<Self> {
+        let records = text
+            .lines()
+            .filter_map(|line| line.split(';').next())
+            .filter(|line| !line.trim().is_empty())
+            .map(str::parse)
+            .collect::<eyre::Result<Vec<DNSRecord>>>()?;
+        Self::from_records(&records)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> eyre::Result<Self> {
+        Self::from_named_root(&std::fs::read_to_string(path)?)
+    }
+
+    /// Pairs the root NS records with the addresses found for them. Servers
+    /// without any address can't be contacted and are left out.
+    pub(crate) fn from_records(records: &[DNSRecord]) -> eyre::Result<Self> {
+        let mut addresses: HashMap<String, Vec<IpAddr>> = HashMap::new();
+        for record in records {
+            if let (TYPE_A | TYPE_AAAA, DNSRecordResult::Address(ip)) =
+                (record.qtype(), record.res())
+            {
+                let name = record.name().to_ascii_lowercase();
+                addresses.entry(name).or_default().push(*ip);
+            }
+        }
+        let servers: Vec<_> = records
+            .iter()
+            .filter(|record| record.qtype() == TYPE_NS && record.name().is_empty())
+            .filter_map(|record| match record.res() {
+                DNSRecordResult::NameServer(name) => Some(name.to_ascii_lowercase()),
+                _ => None,
+            })
+            .filter_map(|name| {
+                let addresses = addresses.get(&name)?.clone();
+                Some(RootServer { name, addresses })
+            })
+            .collect();
+        if servers.is_empty() {
+            eyre::bail!("no root servers with addresses found");
+        }
+        Ok(RootHints { servers })
+    }
+
+    pub fn servers(&self) -> &[RootServer] {
+        &self.servers
+    }
+
+    /// Every address in random order, to spread queries over the roots and
+    /// fail over to the next one. IPv4 addresses come first since IPv6 isn't
+    /// reachable everywhere.
+    pub fn shuffled<R: rand::Rng>(&self, rng: &mut R) -> Vec<IpAddr> {
+        let (mut v4, mut v6): (Vec<_>, Vec<_>) = self
+            .servers
+            .iter()
+            .flat_map(|server| &server.addresses)
+            .partition(|ip| ip.is_ipv4());
+        v4.shuffle(rng);
+        v6.shuffle(rng);
+        v4.into_iter().chain(v6).collect()
+    }
+
+    fn from_priming_response(response: &DNSPacket) -> eyre::Result<Self> {
+        if response.header().rcode() != RCODE_NOERROR {
+            eyre::bail!(
+                "priming query failed with rcode {}",
+                response.header().rcode()
+            );
+        }
+        let records: Vec<_> = response
+            .answers()
+            .iter()
+            .chain(response.additionals())
+            .cloned()
+            .collect();
+        Self::from_records(&records)
+    }
+
+    /// Asks the roots for the current root NS set and their addresses, see
+    /// RFC 8109. The answer doesn't fit in 512 bytes, so `options` should
+    /// allow for EDNS or TCP.
+    pub fn prime<R: rand::Rng>(&self, rng: &mut R, options: &QueryOptions) -> eyre::Result<Self> {
+        let servers = self.shuffled(rng);
+        let response = send_query_any(rng, &servers, "", TYPE_NS, options)?;
+        Self::from_priming_response(&response)
+    }
+
+    /// See [`RootHints::prime`].
+    pub async fn prime_async<R: rand::Rng>(
+        &self,
+        rng: &mut R,
+        options: &QueryOptions,
+    ) -> eyre::Result<Self> {
+        let servers = self.shuffled(rng);
+        let response = send_query_any_async(rng, &servers, "", TYPE_NS, options).await?;
+        Self::from_priming_response(&response)
+    }
+}
+
+/// The hints both resolvers start from, the built-in ones unless replaced
+/// with [`set_root_hints`].
+pub fn root_hints() -> Arc<RootHints> {
+    if let Some(hints) = ROOT_HINTS.read().unwrap().as_ref() {
+        return hints.clone();
+    }
+    ROOT_HINTS
+        .write()
+        .unwrap()
+        .get_or_insert_with(|| Arc::new(RootHints::builtin()))
+        .clone()
+}
+
+pub fn set_root_hints(hints: RootHints) {
+    *ROOT_HINTS.write().unwrap() = Some(Arc::new(hints));
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Cursor, time::Duration};
+
+    use bytes::Bytes;
+    use rand::{rngs::SmallRng, SeedableRng};
+    use tokio::net::UdpSocket;
+
+    use crate::{builder::MessageBuilder, CLASS_IN};
+
+    use super::*;
+
+    #[test]
+    fn test_builtin_hints() {
+        let hints = RootHints::builtin();
+        assert_eq!(hints.servers().len(), 13);
+        assert_eq!(hints.servers()[0].name, "a.root-servers.net");
+        assert_eq!(
+            hints.servers()[0].addresses,
+            [
+                "198.41.0.4".parse::<IpAddr>().unwrap(),
+                "2001:503:ba3e::2:30".parse().unwrap()
+            ]
+        );
+        for server in hints.servers() {
+            assert!(
+                server.addresses.iter().any(IpAddr::is_ipv4),
+                "{}",
+                server.name
+            );
+            assert!(
+                server.addresses.iter().any(IpAddr::is_ipv6),
+                "{}",
+                server.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_load_named_root() {
+        let text = "; a comment\n\
+            .              3600000  NS  A.EXAMPLE.\n\
+            .              3600000  NS  B.EXAMPLE.  ; no glue, so dropped\n\
+            A.EXAMPLE.     3600000  A   192.0.2.1\n";
+        let path = std::env::temp_dir().join(format!("diaw-named-root-{}", std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        let hints = RootHints::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            hints.servers(),
+            [RootServer {
+                name: "a.example".to_string(),
+                addresses: vec!["192.0.2.1".parse().unwrap()],
+            }]
+        );
+
+        assert!(RootHints::from_named_root(".  3600000  NS  B.EXAMPLE.\n").is_err());
+        assert!(RootHints::from_named_root("not a record\n").is_err());
+    }
+
+    #[test]
+    fn test_shuffled() {
+        let hints = RootHints::builtin();
+        let first = hints.shuffled(&mut SmallRng::seed_from_u64(1));
+        let second = hints.shuffled(&mut SmallRng::seed_from_u64(2));
+        assert_eq!(first.len(), 26);
+        assert_ne!(first, second);
+        assert!(first[..13].iter().all(IpAddr::is_ipv4));
+        assert!(first[13..].iter().all(IpAddr::is_ipv6));
+        let mut sorted = first.clone();
+        sorted.sort();
+        let mut expected: Vec<_> = hints
+            .servers()
+            .iter()
+            .flat_map(|server| server.addresses.clone())
+            .collect();
+        expected.sort();
+        assert_eq!(sorted, expected);
+    }
+
+    #[tokio::test]
+    async fn test_prime() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let options = QueryOptions {
+            port: server.local_addr().unwrap().port(),
+            timeout: Duration::from_millis(50),
+            retries: 0,
+            ..QueryOptions::iterative()
+        };
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (len, from) = server.recv_from(&mut buf).await.unwrap();
+            let query =
+                DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::copy_from_slice(&buf[..len])))
+                    .unwrap();
+            let record = |name: &str, qtype, res| {
+                DNSRecord::new(name.to_string(), qtype, CLASS_IN, 518400, res)
+            };
+            let response = MessageBuilder::response_to(&query)
+                .answer(record(
+                    "",
+                    TYPE_NS,
+                    DNSRecordResult::NameServer("a.root.test".to_string()),
+                ))
+                .additional(record(
+                    "a.root.test",
+                    TYPE_AAAA,
+                    DNSRecordResult::Address("2001:db8::1".parse().unwrap()),
+                ))
+                .to_be_bytes();
+            server.send_to(&response, from).await.unwrap();
+        });
+
+        // Nothing answers on 127.0.0.2, whichever root is tried first
+        let hints = RootHints::from_named_root(
+            ".  3600000  NS  a.test.\n\
+             .  3600000  NS  b.test.\n\
+             a.test.  3600000  A  127.0.0.1\n\
+             b.test.  3600000  A  127.0.0.2\n",
+        )
+        .unwrap();
+        let primed = hints
+            .prime_async(&mut SmallRng::seed_from_u64(0), &options)
+            .await
+            .unwrap();
+        assert_eq!(
+            primed.servers(),
+            [RootServer {
+                name: "a.root.test".to_string(),
+                addresses: vec!["2001:db8::1".parse().unwrap()],
+            }]
+        );
+    }
+}
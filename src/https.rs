@@ -0,0 +1,435 @@
+//! DNS over HTTPS, see RFC 8484. Enabled with the `https` feature.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::Bytes;
+use h2::{server::SendResponse, RecvStream};
+use http::{header, Request, Response, StatusCode};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+
+use crate::{
+    cache::{negative_response, negative_ttl},
+    packet::DNSPacket,
+    r#async::listener::handle_request,
+    tls::TlsConfig,
+    TYPE_OPT,
+};
+
+/// Well-known port for DNS over HTTPS.
+pub const DOH_PORT: u16 = 443;
+
+/// Path of the DoH endpoint, the one suggested by RFC 8484.
+pub const DOH_PATH: &str = "/dns-query";
+
+const ALPN_H2: &[u8] = b"h2";
+
+const CONTENT_TYPE: &str = "application/dns-message";
+
+/// How a DoH client sends its queries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Method {
+    /// The message is the request body.
+    #[default]
+    Post,
+    /// The message goes base64url encoded in the `dns` query parameter,
+    /// which HTTP caches can make use of.
+    Get,
+}
+
+/// Where to send DoH queries and how to authenticate the server.
+#[derive(Debug, Clone)]
+pub struct HttpsConfig {
+    tls: TlsConfig,
+    path: String,
+    method: Method,
+}
+
+impl HttpsConfig {
+    /// Queries `https://<server name><path>`, authenticating the server the
+    /// same way as DNS over TLS.
+    pub fn new(tls: TlsConfig, path: &str, method: Method) -> Self {
+        HttpsConfig {
+            tls: tls.with_alpn(ALPN_H2),
+            path: path.to_string(),
+            method,
+        }
+    }
+
+    fn request(&self, message: &[u8]) -> eyre::Result<Request<()>> {
+        let uri = format!("https://{}{}", self.tls.server_name(), self.path);
+        let request = match self.method {
+            Method::Post => Request::post(uri)
+                .header(header::CONTENT_TYPE, CONTENT_TYPE)
+                .header(header::CONTENT_LENGTH, message.len()),
+            Method::Get => Request::get(format!("{uri}?dns={}", URL_SAFE_NO_PAD.encode(message))),
+        };
+        Ok(request.header(header::ACCEPT, CONTENT_TYPE).body(())?)
+    }
+
+    /// Sends `message` over a new HTTP/2 connection to `addr` and returns the
+    /// body of the response.
+    pub(crate) async fn exchange(&self, addr: SocketAddr, message: &[u8]) -> eyre::Result<Vec<u8>> {
+        let stream = TcpStream::connect(addr).await?;
+        let stream = self.tls.connect_async(stream).await?;
+        let (client, connection) = h2::client::handshake(stream).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                tracing::debug!("HTTP/2 connection to {} failed: {}", addr, e);
+            }
+        });
+        let mut client = client.ready().await?;
+        let request = self.request(message)?;
+        let (response, mut body) = client.send_request(request, self.method == Method::Get)?;
+        if self.method == Method::Post {
+            body.send_data(Bytes::copy_from_slice(message), true)?;
+        }
+        let response = response.await?;
+        if response.status() != StatusCode::OK {
+            eyre::bail!("{addr} answered with HTTP status {}", response.status());
+        }
+        read_body(response.into_body(), u16::MAX as usize).await
+    }
+}
+
+async fn read_body(mut body: RecvStream, limit: usize) -> eyre::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if data.len() + chunk.len() > limit {
+            eyre::bail!("body is longer than {limit} bytes");
+        }
+        data.extend_from_slice(&chunk);
+        body.flow_control().release_capacity(chunk.len())?;
+    }
+    Ok(data)
+}
+
+/// Builds the server side configuration, see [`crate::tls::server_config`].
+pub fn server_config(cert_pem: &[u8], key_pem: &[u8]) -> eyre::Result<Arc<rustls::ServerConfig>> {
+    let mut config = (*crate::tls::server_config(cert_pem, key_pem)?).clone();
+    config.alpn_protocols = vec![ALPN_H2.to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// How long clients may cache `response`: the smallest TTL in it, or the
+/// negative caching TTL of a denial, see RFC 8484 section 5.1.
+fn max_age(response: &[u8]) -> u32 {
+    let Ok(response) =
+        DNSPacket::parse_dns_packet(&mut std::io::Cursor::new(Bytes::copy_from_slice(response)))
+    else {
+        return 0;
+    };
+    // Whatever the zone, this is the answer the client gets
+    if let Some(question) = response.questions().first() {
+        if let Some(denial) = negative_response("", &question.name, question.qtype, &response) {
+            return negative_ttl(denial.soa());
+        }
+    }
+    response
+        .answers()
+        .iter()
+        .chain(response.authorities())
+        .chain(response.additionals())
+        .filter(|record| record.qtype() != TYPE_OPT)
+        .map(|record| record.ttl())
+        .min()
+        .unwrap_or(0)
+}
+
+/// Pulls the DNS message out of a DoH request, or returns the status to
+/// reject it with.
+async fn query_message(request: Request<RecvStream>) -> Result<Vec<u8>, StatusCode> {
+    if request.uri().path() != DOH_PATH {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let message = match *request.method() {
+        http::Method::GET => request
+            .uri()
+            .query()
+            .into_iter()
+            .flat_map(|query| query.split('&'))
+            .find_map(|param| param.strip_prefix("dns="))
+            .and_then(|dns| URL_SAFE_NO_PAD.decode(dns).ok())
+            .ok_or(StatusCode::BAD_REQUEST)?,
+        http::Method::POST => {
+            if request.headers().get(header::CONTENT_TYPE)
+                != Some(&header::HeaderValue::from_static(CONTENT_TYPE))
+            {
+                return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            }
+            read_body(request.into_body(), u16::MAX as usize)
+                .await
+                .map_err(|_| StatusCode::PAYLOAD_TOO_LARGE)?
+        }
+        _ => return Err(StatusCode::METHOD_NOT_ALLOWED),
+    };
+    // Anything shorter can't even hold a header
+    if message.len() < 12 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(message)
+}
+
+async fn respond(
+    request: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+) -> eyre::Result<()> {
+    let message = match query_message(request).await {
+        Ok(message) => message,
+        Err(status) => {
+            let response = Response::builder().status(status).body(())?;
+            respond.send_response(response, true)?;
+            return Ok(());
+        }
+    };
+    let answer = handle_request(&message, None).await;
+    let response = Response::builder()
+        .header(header::CONTENT_TYPE, CONTENT_TYPE)
+        .header(header::CONTENT_LENGTH, answer.len())
+        .header(
+            header::CACHE_CONTROL,
+            format!("max-age={}", max_age(&answer)),
+        )
+        .body(())?;
+    let mut body = respond.send_response(response, false)?;
+    body.send_data(Bytes::from(answer), true)?;
+    Ok(())
+}
+
+/// Serves DoH requests from one HTTP/2 connection until the client goes away
+/// or stays idle.
+pub(crate) async fn serve_https<S>(
+    stream: S,
+    client: SocketAddr,
+    idle_timeout: Duration,
+) -> eyre::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut connection = h2::server::handshake(stream).await?;
+    loop {
+        let (request, response) =
+            match tokio::time::timeout(idle_timeout, connection.accept()).await {
+                Ok(Some(accepted)) => accepted?,
+                Ok(None) => break,
+                Err(_) => {
+                    tracing::debug!("Closing idle connection from {}", client);
+                    // Let requests still being answered finish
+                    connection.graceful_shutdown();
+                    while connection.accept().await.is_some() {}
+                    break;
+                }
+            };
+        tracing::info!(
+            "Received {} {} from {}",
+            request.method(),
+            request.uri(),
+            client
+        );
+        tokio::spawn(async move {
+            if let Err(e) = respond(request, response).await {
+                tracing::debug!("Failed to answer {}: {}", client, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Cursor, net::IpAddr};
+
+    use rand::rngs::mock::StepRng;
+    use rcgen::{generate_simple_self_signed, CertifiedKey};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    use crate::{
+        builder::MessageBuilder,
+        edns::Edns,
+        r#async::{
+            listener::{https_listener, ListenerConfig},
+            query::send_query_async,
+        },
+        record::{DNSRecord, DNSRecordResult},
+        simple::query::QueryOptions,
+        CLASS_IN, RCODE_FORMERR, RCODE_NXDOMAIN, TYPE_A, TYPE_AAAA, TYPE_NS, TYPE_SOA,
+    };
+
+    use super::*;
+
+    fn self_signed() -> (CertifiedKey, Arc<rustls::ServerConfig>) {
+        let cert = generate_simple_self_signed(vec!["dns.test".to_string()]).unwrap();
+        let config = server_config(
+            cert.cert.pem().as_bytes(),
+            cert.key_pair.serialize_pem().as_bytes(),
+        )
+        .unwrap();
+        (cert, config)
+    }
+
+    fn client_config(cert: &CertifiedKey, method: Method) -> HttpsConfig {
+        let tls = TlsConfig::new("dns.test", Some(cert.cert.pem().as_bytes()), &[]).unwrap();
+        HttpsConfig::new(tls, DOH_PATH, method)
+    }
+
+    fn parse(message: Vec<u8>) -> DNSPacket {
+        DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::from(message))).unwrap()
+    }
+
+    /// Answers every DoH request with an empty response to the query in it,
+    /// and checks the query used ID 0.
+    async fn echo_server(config: Arc<rustls::ServerConfig>) -> SocketAddr {
+        let acceptor = TlsAcceptor::from(config);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let stream = acceptor.accept(stream).await.unwrap();
+                let mut connection = h2::server::handshake(stream).await.unwrap();
+                while let Some(Ok((request, mut respond))) = connection.accept().await {
+                    let query = parse(query_message(request).await.unwrap());
+                    assert_eq!(query.header().id, 0);
+                    let answer = MessageBuilder::response_to(&query).to_be_bytes();
+                    let response = Response::builder().body(()).unwrap();
+                    let mut body = respond.send_response(response, false).unwrap();
+                    body.send_data(Bytes::from(answer), true).unwrap();
+                }
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn test_max_age() {
+        let record =
+            |qtype, ttl, res| DNSRecord::new("example.com".to_string(), qtype, CLASS_IN, ttl, res);
+        let response = MessageBuilder::new()
+            .answer(record(
+                TYPE_A,
+                300,
+                DNSRecordResult::Address("10.0.0.1".parse().unwrap()),
+            ))
+            .authority(record(
+                TYPE_NS,
+                60,
+                DNSRecordResult::NameServer("ns.example.com".to_string()),
+            ))
+            .edns(Edns::default())
+            .to_be_bytes();
+        assert_eq!(max_age(&response), 60);
+        assert_eq!(max_age(&MessageBuilder::new().to_be_bytes()), 0);
+        assert_eq!(max_age(&response[..response.len() - 1]), 0);
+
+        // Denials are fresh for the SOA's negative caching TTL, not its TTL
+        let soa: DNSRecord = "example.com. 3600 IN SOA ns.example.com. hostmaster.example.com. 1 7200 3600 1209600 300"
+            .parse()
+            .unwrap();
+        let nxdomain = MessageBuilder::new()
+            .rcode(RCODE_NXDOMAIN)
+            .question("www.example.com", TYPE_A, CLASS_IN)
+            .authority(soa.clone())
+            .to_be_bytes();
+        assert_eq!(max_age(&nxdomain), 300);
+        let nodata = MessageBuilder::new()
+            .question("example.com", TYPE_AAAA, CLASS_IN)
+            .authority(DNSRecord::new(
+                "example.com".to_string(),
+                TYPE_SOA,
+                CLASS_IN,
+                60,
+                soa.res().clone(),
+            ))
+            .to_be_bytes();
+        assert_eq!(max_age(&nodata), 60);
+    }
+
+    #[tokio::test]
+    async fn test_send_query_over_https() {
+        let (cert, config) = self_signed();
+        let address = echo_server(config).await;
+        for method in [Method::Post, Method::Get] {
+            let options = QueryOptions {
+                port: address.port(),
+                retries: 0,
+                ..QueryOptions::https(client_config(&cert, method))
+            };
+            let mut mock_rng = StepRng::new(0x8298, 0);
+            let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+            let response = send_query_async(
+                &mut mock_rng,
+                localhost,
+                "www.example.com",
+                TYPE_A,
+                &options,
+            )
+            .await
+            .unwrap();
+            assert_eq!(response.header().id, 0);
+            assert_eq!(response.questions()[0].name, "www.example.com");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_https_listener() {
+        let (cert, config) = self_signed();
+        let (address, server) = https_listener(ListenerConfig::default(), config).unwrap();
+        tokio::spawn(server);
+
+        // Queries without a question are answered with FORMERR, no resolving needed
+        let query = MessageBuilder::new().to_be_bytes();
+        for method in [Method::Post, Method::Get] {
+            let response = client_config(&cert, method)
+                .exchange(address, &query)
+                .await
+                .unwrap();
+            assert_eq!(parse(response).header().rcode(), RCODE_FORMERR);
+        }
+
+        // Check the headers and the rejections on a raw HTTP/2 connection
+        let tls = client_config(&cert, Method::Post).tls;
+        let stream = tls
+            .connect_async(TcpStream::connect(address).await.unwrap())
+            .await
+            .unwrap();
+        let (client, connection) = h2::client::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        let mut client = client.ready().await.unwrap();
+        let get = |path: &str| {
+            Request::get(format!("https://dns.test{path}"))
+                .body(())
+                .unwrap()
+        };
+        let requests = [
+            (
+                get(&format!(
+                    "{DOH_PATH}?dns={}",
+                    URL_SAFE_NO_PAD.encode(&query)
+                )),
+                StatusCode::OK,
+            ),
+            (get("/other"), StatusCode::NOT_FOUND),
+            (get(&format!("{DOH_PATH}?dns=!")), StatusCode::BAD_REQUEST),
+            (
+                Request::post(format!("https://dns.test{DOH_PATH}"))
+                    .body(())
+                    .unwrap(),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ),
+        ];
+        for (request, status) in requests {
+            let (response, _) = client.send_request(request, true).unwrap();
+            let response = response.await.unwrap();
+            assert_eq!(response.status(), status);
+            if status == StatusCode::OK {
+                assert_eq!(response.headers()[header::CONTENT_TYPE], CONTENT_TYPE);
+                assert_eq!(response.headers()[header::CACHE_CONTROL], "max-age=0");
+            }
+        }
+    }
+}
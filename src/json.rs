@@ -0,0 +1,458 @@
+//! JSON representation of DNS messages following the member names of
+//! [RFC 8427](https://www.rfc-editor.org/rfc/rfc8427).
+//!
+//! Enabled with the `serde` feature. Known record types carry their RDATA as
+//! `rdataA`, `rdataAAAA` or `rdataNS`, everything else as `RDATAHEX`. For a
+//! lossless representation that keeps the original wire bytes use
+//! [`LosslessPacket`], which adds `messageOctetsHEX`.
+
+use std::{io::Cursor, net::IpAddr};
+
+use bytes::Bytes;
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    encoding::{from_hex, to_hex},
+    header::{DNSHeader, FLAG_AA, FLAG_AD, FLAG_CD, FLAG_QR, FLAG_RA, FLAG_RD, FLAG_TC},
+    packet::DNSPacket,
+    question::DNSQuestion,
+    record::{DNSRecord, DNSRecordResult},
+    CLASS_IN,
+};
+
+#[derive(Serialize, Deserialize)]
+struct HeaderJson {
+    #[serde(rename = "ID")]
+    id: u16,
+    #[serde(rename = "QR", default, deserialize_with = "flag")]
+    qr: bool,
+    #[serde(rename = "Opcode", default)]
+    opcode: u8,
+    #[serde(rename = "AA", default, deserialize_with = "flag")]
+    aa: bool,
+    #[serde(rename = "TC", default, deserialize_with = "flag")]
+    tc: bool,
+    #[serde(rename = "RD", default, deserialize_with = "flag")]
+    rd: bool,
+    #[serde(rename = "RA", default, deserialize_with = "flag")]
+    ra: bool,
+    #[serde(rename = "AD", default, deserialize_with = "flag")]
+    ad: bool,
+    #[serde(rename = "CD", default, deserialize_with = "flag")]
+    cd: bool,
+    #[serde(rename = "RCODE", default)]
+    rcode: u8,
+    #[serde(rename = "QDCOUNT", default)]
+    qdcount: u16,
+    #[serde(rename = "ANCOUNT", default)]
+    ancount: u16,
+    #[serde(rename = "NSCOUNT", default)]
+    nscount: u16,
+    #[serde(rename = "ARCOUNT", default)]
+    arcount: u16,
+}
+
+#[derive(Serialize, Deserialize)]
+struct QuestionJson {
+    #[serde(rename = "NAME")]
+    name: String,
+    #[serde(rename = "TYPE")]
+    qtype: u16,
+    #[serde(rename = "CLASS")]
+    class: u16,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RecordJson {
+    #[serde(rename = "NAME")]
+    name: String,
+    #[serde(rename = "TYPE")]
+    qtype: u16,
+    #[serde(rename = "CLASS")]
+    class: u16,
+    #[serde(rename = "TTL")]
+    ttl: u32,
+    #[serde(rename = "rdataA", skip_serializing_if = "Option::is_none", default)]
+    rdata_a: Option<String>,
+    #[serde(rename = "rdataAAAA", skip_serializing_if = "Option::is_none", default)]
+    rdata_aaaa: Option<String>,
+    #[serde(rename = "rdataNS", skip_serializing_if = "Option::is_none", default)]
+    rdata_ns: Option<String>,
+    #[serde(rename = "RDATAHEX", skip_serializing_if = "Option::is_none", default)]
+    rdata_hex: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MessageJson {
+    #[serde(flatten)]
+    header: HeaderJson,
+    #[serde(rename = "questionRRs", default)]
+    questions: Vec<QuestionJson>,
+    /// The shorthand for a single question, see RFC 8427 section 2.3.
+    #[serde(rename = "QNAME", skip_serializing_if = "Option::is_none", default)]
+    qname: Option<String>,
+    #[serde(rename = "QTYPE", skip_serializing_if = "Option::is_none", default)]
+    qtype: Option<u16>,
+    #[serde(rename = "QCLASS", skip_serializing_if = "Option::is_none", default)]
+    qclass: Option<u16>,
+    #[serde(rename = "answerRRs", default)]
+    answers: Vec<RecordJson>,
+    #[serde(rename = "authorityRRs", default)]
+    authorities: Vec<RecordJson>,
+    #[serde(rename = "additionalRRs", default)]
+    additionals: Vec<RecordJson>,
+    #[serde(
+        rename = "messageOctetsHEX",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    octets: Option<String>,
+}
+
+/// RFC 8427 writes flags as 0 or 1, booleans are accepted too.
+fn flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Flag {
+        Bool(bool),
+        Number(u64),
+    }
+    match Flag::deserialize(deserializer)? {
+        Flag::Bool(set) => Ok(set),
+        Flag::Number(0) => Ok(false),
+        Flag::Number(1) => Ok(true),
+        Flag::Number(n) => Err(D::Error::custom(format!("flag must be 0 or 1, not {n}"))),
+    }
+}
+
+impl From<&DNSHeader> for HeaderJson {
+    fn from(header: &DNSHeader) -> Self {
+        HeaderJson {
+            id: header.id,
+            qr: header.has_flag(FLAG_QR),
+            opcode: header.opcode(),
+            aa: header.has_flag(FLAG_AA),
+            tc: header.has_flag(FLAG_TC),
+            rd: header.has_flag(FLAG_RD),
+            ra: header.has_flag(FLAG_RA),
+            ad: header.has_flag(FLAG_AD),
+            cd: header.has_flag(FLAG_CD),
+            rcode: header.rcode(),
+            qdcount: header.num_questions,
+            ancount: header.num_answers,
+            nscount: header.num_authorities,
+            arcount: header.num_additionals,
+        }
+    }
+}
+
+impl From<HeaderJson> for DNSHeader {
+    fn from(json: HeaderJson) -> Self {
+        let flags = [
+            (json.qr, FLAG_QR),
+            (json.aa, FLAG_AA),
+            (json.tc, FLAG_TC),
+            (json.rd, FLAG_RD),
+            (json.ra, FLAG_RA),
+            (json.ad, FLAG_AD),
+            (json.cd, FLAG_CD),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |acc, (_, flag)| acc | flag);
+        let flags = flags | ((json.opcode as u16 & 0xf) << 11) | (json.rcode as u16 & 0xf);
+        DNSHeader {
+            id: json.id,
+            flags,
+            num_questions: json.qdcount,
+            num_answers: json.ancount,
+            num_authorities: json.nscount,
+            num_additionals: json.arcount,
+        }
+    }
+}
+
+impl From<&DNSQuestion> for QuestionJson {
+    fn from(question: &DNSQuestion) -> Self {
+        QuestionJson {
+            name: question.name.clone(),
+            qtype: question.qtype,
+            class: question.class,
+        }
+    }
+}
+
+impl From<QuestionJson> for DNSQuestion {
+    fn from(json: QuestionJson) -> Self {
+        DNSQuestion {
+            name: json.name,
+            qtype: json.qtype,
+            class: json.class,
+        }
+    }
+}
+
+impl From<&DNSRecord> for RecordJson {
+    fn from(record: &DNSRecord) -> Self {
+        let mut json = RecordJson {
+            name: record.name().to_string(),
+            qtype: record.qtype(),
+            class: record.class(),
+            ttl: record.ttl(),
+            rdata_a: None,
+            rdata_aaaa: None,
+            rdata_ns: None,
+            rdata_hex: None,
+        };
+        match record.res() {
+            DNSRecordResult::Address(ip @ IpAddr::V4(_)) => json.rdata_a = Some(ip.to_string()),
+            DNSRecordResult::Address(ip @ IpAddr::V6(_)) => json.rdata_aaaa = Some(ip.to_string()),
+            DNSRecordResult::NameServer(name) => json.rdata_ns = Some(name.clone()),
+            DNSRecordResult::CanonicalName(_)
+            | DNSRecordResult::Pointer(_)
+            | DNSRecordResult::MailExchange { .. }
+            | DNSRecordResult::Soa(_) => json.rdata_hex = Some(to_hex(&record.res().to_rdata())),
+            DNSRecordResult::Unknown(data) => json.rdata_hex = Some(to_hex(data)),
+        }
+        json
+    }
+}
+
+impl RecordJson {
+    fn into_record<E: Error>(self) -> Result<DNSRecord, E> {
+        let res = if let Some(ip) = self.rdata_a.or(self.rdata_aaaa) {
+            DNSRecordResult::Address(ip.parse().map_err(E::custom)?)
+        } else if let Some(name) = self.rdata_ns {
+            DNSRecordResult::NameServer(name)
+        } else if let Some(hex) = self.rdata_hex {
+            DNSRecordResult::from_rdata(self.qtype, from_hex(&hex).map_err(E::custom)?)
+        } else {
+            return Err(E::custom("resource record has no RDATA member"));
+        };
+        Ok(DNSRecord::new(
+            self.name, self.qtype, self.class, self.ttl, res,
+        ))
+    }
+}
+
+impl MessageJson {
+    fn from_packet(packet: &DNSPacket) -> Self {
+        MessageJson {
+            header: packet.header().into(),
+            questions: packet.questions().iter().map(Into::into).collect(),
+            qname: None,
+            qtype: None,
+            qclass: None,
+            answers: packet.answers().iter().map(Into::into).collect(),
+            authorities: packet.authorities().iter().map(Into::into).collect(),
+            additionals: packet.additionals().iter().map(Into::into).collect(),
+            octets: None,
+        }
+    }
+
+    fn into_packet<E: Error>(self) -> Result<DNSPacket, E> {
+        let collect = |records: Vec<RecordJson>| {
+            records
+                .into_iter()
+                .map(RecordJson::into_record)
+                .collect::<Result<Vec<_>, E>>()
+        };
+        let mut header = DNSHeader::from(self.header);
+        let mut questions: Vec<DNSQuestion> = self.questions.into_iter().map(Into::into).collect();
+        if let Some(name) = self.qname {
+            let qtype = self.qtype.ok_or_else(|| E::missing_field("QTYPE"))?;
+            let class = self.qclass.unwrap_or(CLASS_IN);
+            questions.push(DNSQuestion { name, qtype, class });
+        }
+        let answers = collect(self.answers)?;
+        let authorities = collect(self.authorities)?;
+        let additionals = collect(self.additionals)?;
+        // The sections are the source of truth, the counts are informational
+        header.num_questions = questions.len() as u16;
+        header.num_answers = answers.len() as u16;
+        header.num_authorities = authorities.len() as u16;
+        header.num_additionals = additionals.len() as u16;
+        Ok(DNSPacket::from_parts(
+            header,
+            questions,
+            answers,
+            authorities,
+            additionals,
+        ))
+    }
+}
+
+impl Serialize for DNSHeader {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        HeaderJson::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DNSHeader {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        HeaderJson::deserialize(deserializer).map(Into::into)
+    }
+}
+
+impl Serialize for DNSQuestion {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        QuestionJson::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DNSQuestion {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        QuestionJson::deserialize(deserializer).map(Into::into)
+    }
+}
+
+impl Serialize for DNSRecord {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        RecordJson::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DNSRecord {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        RecordJson::deserialize(deserializer)?.into_record()
+    }
+}
+
+impl Serialize for DNSPacket {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        MessageJson::from_packet(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DNSPacket {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        MessageJson::deserialize(deserializer)?.into_packet()
+    }
+}
+
+/// A parsed packet together with the wire bytes it was parsed from.
+///
+/// Serializes like [`DNSPacket`] plus `messageOctetsHEX`. When deserializing,
+/// the octets are authoritative and the packet is re-parsed from them.
+#[derive(Debug)]
+pub struct LosslessPacket {
+    packet: DNSPacket,
+    octets: Vec<u8>,
+}
+
+impl LosslessPacket {
+    pub fn from_wire(octets: &[u8]) -> eyre::Result<Self> {
+        let packet = DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::copy_from_slice(octets)))?;
+        Ok(LosslessPacket {
+            packet,
+            octets: octets.to_vec(),
+        })
+    }
+
+    pub fn packet(&self) -> &DNSPacket {
+        &self.packet
+    }
+
+    pub fn octets(&self) -> &[u8] {
+        &self.octets
+    }
+}
+
+impl Serialize for LosslessPacket {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut json = MessageJson::from_packet(&self.packet);
+        json.octets = Some(to_hex(&self.octets));
+        json.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for LosslessPacket {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let json = MessageJson::deserialize(deserializer)?;
+        let octets = json
+            .octets
+            .ok_or_else(|| D::Error::missing_field("messageOctetsHEX"))?;
+        LosslessPacket::from_wire(&from_hex(&octets).map_err(D::Error::custom)?)
+            .map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const RESPONSE: &[u8] = b"\x12\x34\x81\x80\x00\x01\x00\x01\x00\x00\x00\x00\
+        \x03www\x07example\x03com\x00\x00\x01\x00\x01\
+        \xc0\x0c\x00\x01\x00\x01\x00\x00\x01\x2c\x00\x04\x5d\xb8\xd8\x22";
+
+    #[test]
+    fn test_packet_json() {
+        let packet = LosslessPacket::from_wire(RESPONSE).unwrap();
+        let json = serde_json::to_value(packet.packet()).unwrap();
+        assert_eq!(json["ID"], 4660);
+        assert_eq!(json["QR"], true);
+        assert_eq!(json["RD"], true);
+        assert_eq!(json["AA"], false);
+        assert_eq!(json["ANCOUNT"], 1);
+        assert_eq!(json["questionRRs"][0]["NAME"], "www.example.com");
+        assert_eq!(json["answerRRs"][0]["TTL"], 300);
+        assert_eq!(json["answerRRs"][0]["rdataA"], "93.184.216.34");
+        assert!(json.get("messageOctetsHEX").is_none());
+
+        let parsed: DNSPacket = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.to_string(), packet.packet().to_string());
+    }
+
+    #[test]
+    fn test_rfc8427_example() {
+        // The query and response from RFC 8427 section 6.1
+        let query = r#"{ "ID": 19678, "QR": 0, "Opcode": 0, "AA": 0, "TC": 0,
+            "RD": 0, "RA": 0, "AD": 0, "CD": 0, "RCODE": 0, "QDCOUNT": 1,
+            "ANCOUNT": 0, "NSCOUNT": 0, "ARCOUNT": 0, "QNAME": "example.com",
+            "QTYPE": 1, "QCLASS": 1 }"#;
+        let query: DNSPacket = serde_json::from_str(query).unwrap();
+        assert_eq!(query.header().id, 19678);
+        assert!(!query.header().has_flag(FLAG_QR));
+        assert_eq!(query.questions()[0].name, "example.com");
+        assert_eq!(query.questions()[0].qtype, 1);
+
+        let response = r#"{ "ID": 32784, "QR": 1, "AA": 1, "RCODE": 0,
+            "QDCOUNT": 1, "ANCOUNT": 2, "NSCOUNT": 1, "ARCOUNT": 0,
+            "answerRRs": [ { "NAME": "example.com.", "TYPE": 1, "CLASS": 1,
+                "TTL": 3600, "RDATAHEX": "C0000201" },
+              { "NAME": "example.com.", "TYPE": 1, "CLASS": 1, "TTL": 3600,
+                "RDATAHEX": "C000AA01" } ],
+            "authorityRRs": [ { "NAME": "ns.example.com.", "TYPE": 1,
+                "CLASS": 1, "TTL": 28800, "RDATAHEX": "CB007181" } ] }"#;
+        let response: DNSPacket = serde_json::from_str(response).unwrap();
+        assert!(response.header().has_flag(FLAG_QR));
+        assert!(response.header().has_flag(FLAG_AA));
+        assert_eq!(response.answers().len(), 2);
+        assert!(matches!(
+            response.answers()[1].res(),
+            DNSRecordResult::Address(ip) if ip.to_string() == "192.0.170.1"
+        ));
+        assert_eq!(response.authorities().len(), 1);
+
+        assert!(serde_json::from_str::<DNSPacket>(r#"{ "ID": 1, "QR": 2 }"#).is_err());
+        assert!(serde_json::from_str::<DNSPacket>(r#"{ "ID": 1, "QNAME": "a" }"#).is_err());
+    }
+
+    #[test]
+    fn test_unknown_rdata_json() {
+        let json = r#"{"NAME": "example.com", "TYPE": 65534, "CLASS": 1, "TTL": 60, "RDATAHEX": "0A000001"}"#;
+        let record: DNSRecord = serde_json::from_str(json).unwrap();
+        assert!(matches!(record.res(), DNSRecordResult::Unknown(data) if data == &[10, 0, 0, 1]));
+        let value = serde_json::to_value(&record).unwrap();
+        assert_eq!(value["RDATAHEX"], "0A000001");
+    }
+
+    #[test]
+    fn test_lossless_json() {
+        let packet = LosslessPacket::from_wire(RESPONSE).unwrap();
+        let json = serde_json::to_string(&packet).unwrap();
+        assert!(json.contains(&to_hex(RESPONSE)));
+        let parsed: LosslessPacket = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.octets(), RESPONSE);
+    }
+}
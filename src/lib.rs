@@ -1,50 +1,83 @@
 pub mod r#async;
+pub mod builder;
+pub mod cache;
+pub mod edns;
 mod encoding;
-mod header;
+pub mod error;
+pub mod header;
+pub mod hints;
+#[cfg(feature = "https")]
+pub mod https;
+#[cfg(feature = "serde")]
+pub mod json;
+pub mod lookup;
 pub mod packet;
 mod question;
-mod record;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod record;
+pub mod resolution;
+pub mod resolver;
+pub mod selection;
 pub mod simple;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod transport;
+mod types;
 
 pub const TYPE_A: u16 = 1;
 pub const TYPE_NS: u16 = 2;
+pub const TYPE_CNAME: u16 = 5;
+pub const TYPE_SOA: u16 = 6;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_MX: u16 = 15;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_OPT: u16 = 41;
 pub const CLASS_IN: u16 = 1;
 
+pub const RCODE_NOERROR: u8 = 0;
+pub const RCODE_FORMERR: u8 = 1;
+pub const RCODE_SERVFAIL: u8 = 2;
+pub const RCODE_NXDOMAIN: u8 = 3;
+pub const RCODE_NOTIMP: u8 = 4;
+pub const RCODE_REFUSED: u8 = 5;
+
 #[cfg(test)]
 mod test {
-    use rand::SeedableRng;
-    use std::{net::IpAddr, str::FromStr};
+    use std::net::IpAddr;
 
-    use crate::simple::query::send_query;
+    use crate::{
+        simple::query::QueryOptions,
+        transport::{FixtureTransport, Fixtures, Transport},
+    };
 
     use super::*;
 
-    #[test]
-    fn test_main() {
-        let rng = &mut rand::rngs::SmallRng::from_entropy();
-        let ip_addr = IpAddr::from_str("198.41.0.4").unwrap();
+    #[tokio::test]
+    async fn test_main() {
+        let fixtures = Fixtures::parse(include_str!("../tests/fixtures/resolve.txt")).unwrap();
+        let transport = FixtureTransport::new(fixtures);
         let domain_name = "www.google.com";
         let record_type = TYPE_A;
-        let response = send_query(rng, ip_addr, domain_name, record_type).unwrap();
-        println!("Authorities {:#?}", response.authorities());
-        println!("Additionals {:#?}", response.additionals());
+        let options = QueryOptions::iterative();
+        let query = |server: &str| {
+            let server: IpAddr = server.parse().unwrap();
+            transport.query(server, domain_name, record_type, &options)
+        };
 
-        let response = send_query(
-            rng,
-            "192.12.94.30".parse().unwrap(),
-            domain_name,
-            record_type,
-        )
-        .unwrap();
-        println!("Authorities {:#?}", response.authorities());
-        println!("Additionals {:#?}", response.additionals());
-        let response = send_query(
-            rng,
-            "216.239.32.10".parse().unwrap(),
-            domain_name,
-            record_type,
-        )
-        .unwrap();
-        println!("Answer {:#?}", response.answers());
+        // The root and then com refer us further down
+        let response = query("198.41.0.4").await.unwrap();
+        assert!(response
+            .get_nameserver_ips()
+            .contains(&"192.12.94.30".parse().unwrap()));
+        let response = query("192.12.94.30").await.unwrap();
+        assert!(response
+            .get_nameserver_ips()
+            .contains(&"216.239.32.10".parse().unwrap()));
+        let response = query("216.239.32.10").await.unwrap();
+        assert_eq!(response.answers().len(), 1);
     }
 }
@@ -0,0 +1,168 @@
+//! What the resolvers return: every record of the type asked for, and how they
+//! were found.
+
+use std::net::IpAddr;
+
+use crate::{
+    record::{DNSRecord, DNSRecordResult},
+    TYPE_CNAME,
+};
+
+/// Longer CNAME chains are treated as loops. BIND gives up after 16 too.
+pub(crate) const MAX_CNAMES: usize = 16;
+
+/// The nameserver that gave the final answer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Authority {
+    /// The zone it was asked as a nameserver for, "" for the root.
+    pub zone: String,
+    pub server: IpAddr,
+}
+
+/// The result of resolving `name` for records of type `qtype`.
+#[derive(Debug, Clone)]
+pub struct Lookup {
+    pub name: String,
+    pub qtype: u16,
+    /// The CNAME records followed from `name`, in order.
+    pub cnames: Vec<DNSRecord>,
+    /// The whole RRset found at the end of the CNAME chain.
+    pub records: Vec<DNSRecord>,
+    /// `None` when the answer came from the cache.
+    pub authority: Option<Authority>,
+}
+
+impl Lookup {
+    /// The name the records belong to, which differs from `name` when CNAMEs
+    /// were followed.
+    pub fn canonical_name(&self) -> &str {
+        self.cnames
+            .last()
+            .and_then(cname_target)
+            .unwrap_or(&self.name)
+    }
+
+    /// The CNAME chain followed by the records, as they'd appear in the answer
+    /// section of a response.
+    pub fn answers(&self) -> impl Iterator<Item = &DNSRecord> {
+        self.cnames.iter().chain(&self.records)
+    }
+
+    /// How long the whole answer may be cached, the lowest TTL in the chain.
+    pub fn ttl(&self) -> u32 {
+        self.answers().map(DNSRecord::ttl).min().unwrap_or(0)
+    }
+
+    pub fn addresses(&self) -> Vec<IpAddr> {
+        self.records
+            .iter()
+            .filter_map(|record| match record.res() {
+                DNSRecordResult::Address(ip) => Some(*ip),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+pub(crate) fn cname_target(record: &DNSRecord) -> Option<&str> {
+    match record.res() {
+        DNSRecordResult::CanonicalName(target) => Some(target),
+        _ => None,
+    }
+}
+
+/// Follows CNAMEs from `name` through `answers`. Returns the chain, and the
+/// RRset of `qtype` at its end if the answers go that far.
+pub(crate) fn follow_chain(
+    name: &str,
+    qtype: u16,
+    answers: &[DNSRecord],
+) -> (Vec<DNSRecord>, Option<Vec<DNSRecord>>) {
+    let mut cnames: Vec<DNSRecord> = Vec::new();
+    let mut owner = name;
+    loop {
+        let owned = |record: &&DNSRecord| record.name().eq_ignore_ascii_case(owner);
+        let records: Vec<_> = answers
+            .iter()
+            .filter(owned)
+            .filter(|record| record.qtype() == qtype)
+            .cloned()
+            .collect();
+        if !records.is_empty() {
+            return (cnames, Some(records));
+        }
+        let cname = answers
+            .iter()
+            .filter(owned)
+            .find(|record| record.qtype() == TYPE_CNAME);
+        match cname {
+            Some(cname) if cnames.len() < MAX_CNAMES => {
+                cnames.push(cname.clone());
+                owner = cname_target(cname).unwrap_or_default();
+            }
+            _ => return (cnames, None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::TYPE_A;
+
+    use super::*;
+
+    fn records(lines: &[&str]) -> Vec<DNSRecord> {
+        lines.iter().map(|line| line.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_follow_chain() {
+        let answers = records(&[
+            "www.example.com. 300 CNAME web.example.com.",
+            "WEB.example.com. 60 CNAME cdn.example.net.",
+            "cdn.example.net. 20 A 192.0.2.1",
+            "cdn.example.net. 20 A 192.0.2.2",
+            "other.example.net. 20 A 192.0.2.3",
+        ]);
+        let (cnames, found) = follow_chain("www.example.com", TYPE_A, &answers);
+        let lookup = Lookup {
+            name: "www.example.com".to_string(),
+            qtype: TYPE_A,
+            cnames,
+            records: found.unwrap(),
+            authority: None,
+        };
+        assert_eq!(lookup.cnames.len(), 2);
+        assert_eq!(lookup.canonical_name(), "cdn.example.net");
+        assert_eq!(
+            lookup.addresses(),
+            [
+                "192.0.2.1".parse::<IpAddr>().unwrap(),
+                "192.0.2.2".parse().unwrap()
+            ]
+        );
+        assert_eq!(lookup.ttl(), 20);
+        assert_eq!(lookup.answers().count(), 4);
+
+        // Asking for the CNAME itself doesn't follow it
+        let (cnames, found) = follow_chain("www.example.com", TYPE_CNAME, &answers);
+        assert!(cnames.is_empty());
+        assert_eq!(found.unwrap().len(), 1);
+
+        // The chain leaves the response
+        let (cnames, found) = follow_chain("www.example.com", TYPE_A, &answers[..2]);
+        assert_eq!(cnames.len(), 2);
+        assert!(found.is_none());
+    }
+
+    #[test]
+    fn test_cname_loop() {
+        let answers = records(&[
+            "a.example. 300 CNAME b.example.",
+            "b.example. 300 CNAME a.example.",
+        ]);
+        let (cnames, found) = follow_chain("a.example", TYPE_A, &answers);
+        assert_eq!(cnames.len(), MAX_CNAMES);
+        assert!(found.is_none());
+    }
+}
@@ -1,15 +1,16 @@
-use std::io::Seek;
+use std::{fmt, io::Seek, net::IpAddr};
 
 use bytes::Buf;
 
 use crate::{
+    edns::Edns,
     header::DNSHeader,
     question::DNSQuestion,
     record::{DNSRecord, DNSRecordResult},
-    TYPE_A, TYPE_NS,
+    TYPE_A, TYPE_AAAA, TYPE_NS, TYPE_OPT,
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DNSPacket {
     header: DNSHeader,
     questions: Vec<DNSQuestion>,
@@ -18,7 +19,7 @@ pub struct DNSPacket {
     additionals: Vec<DNSRecord>,
 }
 
-fn parse_records<B>(data: &mut B, num_records: u16) -> Vec<DNSRecord>
+fn parse_records<B>(data: &mut B, num_records: u16) -> eyre::Result<Vec<DNSRecord>>
 where
     B: Buf + Seek,
 {
@@ -28,40 +29,110 @@ where
 }
 
 impl DNSPacket {
-    pub fn parse_dns_packet<B>(data: &mut B) -> Self
+    pub(crate) fn from_parts(
+        header: DNSHeader,
+        questions: Vec<DNSQuestion>,
+        answers: Vec<DNSRecord>,
+        authorities: Vec<DNSRecord>,
+        additionals: Vec<DNSRecord>,
+    ) -> Self {
+        DNSPacket {
+            header,
+            questions,
+            answers,
+            authorities,
+            additionals,
+        }
+    }
+
+    /// Parses a message off the wire, failing on anything malformed or cut
+    /// short instead of trusting the counts and lengths in it.
+    pub fn parse_dns_packet<B>(data: &mut B) -> eyre::Result<Self>
     where
         B: Buf + Seek,
     {
-        let header = DNSHeader::parse_header(data);
+        let header = DNSHeader::parse_header(data)?;
         let questions = (0..header.num_questions)
             .map(|_| DNSQuestion::parse_question(data))
-            .collect();
-        let answers = parse_records(data, header.num_answers);
-        let authorities = parse_records(data, header.num_authorities);
-        let additionals = parse_records(data, header.num_additionals);
-        DNSPacket {
+            .collect::<eyre::Result<_>>()?;
+        let answers = parse_records(data, header.num_answers)?;
+        let authorities = parse_records(data, header.num_authorities)?;
+        let additionals = parse_records(data, header.num_additionals)?;
+        Ok(DNSPacket {
             header,
             questions,
             answers,
             authorities,
             additionals,
+        })
+    }
+
+    /// Encodes the packet without name compression.
+    pub fn to_be_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.header.to_be_bytes();
+        for question in &self.questions {
+            bytes.extend(question.to_be_bytes());
         }
+        for record in self
+            .answers
+            .iter()
+            .chain(&self.authorities)
+            .chain(&self.additionals)
+        {
+            bytes.extend(record.to_be_bytes());
+        }
+        bytes
     }
 
-    /// Returns the first A record in the answers section
+    /// Returns the EDNS options from the OPT record in the additionals section
+    pub fn edns(&self) -> Option<Edns> {
+        self.additionals
+            .iter()
+            .find(|r| r.qtype() == TYPE_OPT)
+            .and_then(Edns::from_record)
+    }
+
+    /// Returns the first A or AAAA record in the answers section
     pub fn get_answer(&self) -> Option<&DNSRecordResult> {
+        self.get_answer_record().map(|r| r.res())
+    }
+
+    /// Like [`DNSPacket::get_answer`], but with the owner name and TTL
+    pub fn get_answer_record(&self) -> Option<&DNSRecord> {
         self.answers
             .iter()
-            .find(|r| r.qtype() == TYPE_A)
-            .map(|r| r.res())
+            .find(|r| matches!(r.qtype(), TYPE_A | TYPE_AAAA))
     }
 
-    /// Returns the first A record in the additionals section
+    /// Returns the first A record in the additionals section, or the first
+    /// AAAA record if there is no IPv4 glue
     pub fn get_nameserver_ip(&self) -> Option<&DNSRecordResult> {
-        self.additionals
+        let glue = |qtype| self.additionals.iter().find(move |r| r.qtype() == qtype);
+        glue(TYPE_A).or_else(|| glue(TYPE_AAAA)).map(|r| r.res())
+    }
+
+    /// Returns every glue address for the nameservers in the authority
+    /// section, IPv4 first
+    pub fn get_nameserver_ips(&self) -> Vec<IpAddr> {
+        let names: Vec<_> = self
+            .authorities
             .iter()
-            .find(|r| r.qtype() == TYPE_A)
-            .map(|r| r.res())
+            .filter_map(|r| match (r.qtype(), r.res()) {
+                (TYPE_NS, DNSRecordResult::NameServer(ns)) => Some(ns),
+                _ => None,
+            })
+            .collect();
+        let glue = |qtype| {
+            self.additionals
+                .iter()
+                .filter(move |r| r.qtype() == qtype)
+                .filter(|r| names.iter().any(|ns| ns.eq_ignore_ascii_case(r.name())))
+                .filter_map(|r| match r.res() {
+                    DNSRecordResult::Address(ip) => Some(*ip),
+                    _ => None,
+                })
+        };
+        glue(TYPE_A).chain(glue(TYPE_AAAA)).collect()
     }
 
     pub fn get_nameserver(&self) -> Option<&DNSRecordResult> {
@@ -71,6 +142,14 @@ impl DNSPacket {
             .map(|r| r.res())
     }
 
+    pub fn header(&self) -> &DNSHeader {
+        &self.header
+    }
+
+    pub fn questions(&self) -> &[DNSQuestion] {
+        self.questions.as_ref()
+    }
+
     pub fn answers(&self) -> &[DNSRecord] {
         self.answers.as_ref()
     }
@@ -83,3 +162,149 @@ impl DNSPacket {
         self.additionals.as_ref()
     }
 }
+
+fn fmt_section(f: &mut fmt::Formatter<'_>, title: &str, records: &[DNSRecord]) -> fmt::Result {
+    if records.is_empty() {
+        return Ok(());
+    }
+    write!(f, "\n\n;; {title} SECTION:")?;
+    for record in records {
+        write!(f, "\n{record}")?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for DNSPacket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.header)?;
+        write!(f, "\n\n;; QUESTION SECTION:")?;
+        for question in &self.questions {
+            write!(f, "\n{question}")?;
+        }
+        fmt_section(f, "ANSWER", &self.answers)?;
+        fmt_section(f, "AUTHORITY", &self.authorities)?;
+        fmt_section(f, "ADDITIONAL", &self.additionals)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use bytes::Bytes;
+
+    use crate::builder::MessageBuilder;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_compressed_questions() {
+        // Two questions, the second pointing back into the first, followed by
+        // an answer whose name points at the second question
+        let mut data = Cursor::new(Bytes::from_static(
+            b"\x00\x01\x81\x80\x00\x02\x00\x01\x00\x00\x00\x00\
+              \x03www\x07example\x03com\x00\x00\x01\x00\x01\
+              \x04mail\xc0\x10\x00\x01\x00\x01\
+              \xc0\x21\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04\x0a\x00\x00\x01",
+        ));
+        let packet = DNSPacket::parse_dns_packet(&mut data).unwrap();
+        let names: Vec<_> = packet.questions().iter().map(|q| q.name.as_str()).collect();
+        assert_eq!(names, ["www.example.com", "mail.example.com"]);
+        assert_eq!(packet.answers()[0].name(), "mail.example.com");
+        assert_eq!(packet.answers()[0].ttl(), 60);
+    }
+
+    #[test]
+    fn test_display_packet() {
+        let mut data = Cursor::new(Bytes::from_static(
+            b"\x12\x34\x81\x80\x00\x01\x00\x01\x00\x00\x00\x00\
+              \x03www\x07example\x03com\x00\x00\x01\x00\x01\
+              \xc0\x0c\x00\x01\x00\x01\x00\x00\x01\x2c\x00\x04\x5d\xb8\xd8\x22",
+        ));
+        let packet = DNSPacket::parse_dns_packet(&mut data).unwrap();
+        assert_eq!(
+            packet.to_string(),
+            ";; ->>HEADER<<- opcode: QUERY, status: NOERROR, id: 4660\n\
+             ;; flags: qr rd ra; QUERY: 1, ANSWER: 1, AUTHORITY: 0, ADDITIONAL: 0\n\
+             \n\
+             ;; QUESTION SECTION:\n\
+             ;www.example.com.\t\tIN\tA\n\
+             \n\
+             ;; ANSWER SECTION:\n\
+             www.example.com.\t300\tIN\tA\t93.184.216.34"
+        );
+    }
+
+    #[test]
+    fn test_ipv6_glue() {
+        let record = |qtype, ip: &str| {
+            DNSRecord::new(
+                "ns.example.com".to_string(),
+                qtype,
+                crate::CLASS_IN,
+                3600,
+                DNSRecordResult::Address(ip.parse().unwrap()),
+            )
+        };
+        let v6_only = MessageBuilder::new()
+            .additional(record(TYPE_AAAA, "2001:db8::53"))
+            .to_be_bytes();
+        let v6_only = DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::from(v6_only))).unwrap();
+        assert_eq!(
+            v6_only.get_nameserver_ip().unwrap().to_rdata(),
+            "2001:db8::53"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets()
+        );
+        assert_eq!(
+            v6_only.additionals()[0].to_string(),
+            "ns.example.com.\t3600\tIN\tAAAA\t2001:db8::53"
+        );
+
+        let both = MessageBuilder::new()
+            .additional(record(TYPE_AAAA, "2001:db8::53"))
+            .additional(record(TYPE_A, "192.0.2.53"))
+            .build();
+        assert_eq!(
+            both.get_nameserver_ip().unwrap().to_rdata(),
+            [192, 0, 2, 53]
+        );
+        // Without NS records there's nothing the glue belongs to
+        assert!(both.get_nameserver_ips().is_empty());
+    }
+
+    #[test]
+    fn test_nameserver_ips() {
+        let record = |name: &str, qtype, res| {
+            DNSRecord::new(name.to_string(), qtype, crate::CLASS_IN, 3600, res)
+        };
+        let address = |name, qtype, ip: &str| {
+            record(name, qtype, DNSRecordResult::Address(ip.parse().unwrap()))
+        };
+        let referral = MessageBuilder::new()
+            .authority(record(
+                "example.com",
+                TYPE_NS,
+                DNSRecordResult::NameServer("a.ns.example.com".to_string()),
+            ))
+            .authority(record(
+                "example.com",
+                TYPE_NS,
+                DNSRecordResult::NameServer("b.ns.example.com".to_string()),
+            ))
+            .additional(address("a.ns.example.com", TYPE_AAAA, "2001:db8::53"))
+            .additional(address("a.ns.example.com", TYPE_A, "192.0.2.53"))
+            .additional(address("B.NS.example.com", TYPE_A, "192.0.2.54"))
+            .additional(address("unrelated.example.net", TYPE_A, "192.0.2.99"))
+            .build();
+        assert_eq!(
+            referral.get_nameserver_ips(),
+            [
+                "192.0.2.53".parse::<IpAddr>().unwrap(),
+                "192.0.2.54".parse().unwrap(),
+                "2001:db8::53".parse().unwrap(),
+            ]
+        );
+    }
+}
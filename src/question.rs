@@ -1,34 +1,49 @@
+use std::{fmt, io::Seek};
+
 use bytes::{Buf, BufMut};
 
-use crate::encoding::decode_name_simple;
+use crate::{
+    encoding::{decode_name, encode_domain_name, ensure_remaining},
+    types::{class_to_string, name_to_string, type_to_string},
+};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DNSQuestion {
-    pub name: Vec<u8>,
+    pub name: String,
     pub qtype: u16,
     pub class: u16,
 }
 
 impl DNSQuestion {
     pub fn to_be_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::with_capacity(4 + self.name.len());
-        bytes.put_slice(&self.name);
+        let name = encode_domain_name(&self.name);
+        let mut bytes = Vec::with_capacity(4 + name.len());
+        bytes.put_slice(&name);
         bytes.put_u16(self.qtype);
         bytes.put_u16(self.class);
         bytes
     }
 
-    pub fn parse_question<B>(bytes: &mut B) -> Self
+    pub fn parse_question<B>(bytes: &mut B) -> eyre::Result<Self>
     where
-        B: Buf,
+        B: Buf + Seek,
     {
-        let name = decode_name_simple(bytes);
+        let name = decode_name(bytes)?;
+        ensure_remaining(bytes, 4)?;
         let qtype = bytes.get_u16();
         let class = bytes.get_u16();
-        DNSQuestion {
-            name: name.into_bytes(),
-            qtype,
-            class,
-        }
+        Ok(DNSQuestion { name, qtype, class })
+    }
+}
+
+impl fmt::Display for DNSQuestion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            ";{}\t\t{}\t{}",
+            name_to_string(&self.name),
+            class_to_string(self.class),
+            type_to_string(self.qtype)
+        )
     }
 }
@@ -0,0 +1,276 @@
+//! DNS over QUIC, see RFC 9250. Enabled with the `quic` feature.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use quinn::{
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    Connection, Endpoint, RecvStream, SendStream, VarInt,
+};
+
+use crate::{r#async::listener::handle_request, simple::query::tcp_frame, tls::TlsConfig};
+
+/// Well-known port for DNS over QUIC, on UDP.
+pub const DOQ_PORT: u16 = 853;
+
+const ALPN_DOQ: &[u8] = b"doq";
+
+/// Error codes for closing connections and streams, RFC 9250 section 4.3.
+const DOQ_NO_ERROR: u32 = 0x0;
+const DOQ_INTERNAL_ERROR: u32 = 0x1;
+const DOQ_PROTOCOL_ERROR: u32 = 0x2;
+
+/// Framed messages are at most this long, two length bytes included.
+const MAX_FRAME_SIZE: usize = u16::MAX as usize + 2;
+
+/// How to reach and authenticate a DNS over QUIC server.
+#[derive(Debug, Clone)]
+pub struct QuicConfig {
+    server_name: String,
+    config: quinn::ClientConfig,
+    zero_rtt: bool,
+}
+
+impl QuicConfig {
+    /// Authenticates the server the same way as DNS over TLS. 0-RTT is
+    /// disabled, see [`QuicConfig::with_zero_rtt`].
+    pub fn new(tls: TlsConfig) -> eyre::Result<Self> {
+        Self::build(tls, false)
+    }
+
+    /// Like [`QuicConfig::new`], but sends queries as 0-RTT data when
+    /// resuming an earlier session. Such data can be replayed by an attacker,
+    /// so only use this for queries that are safe to repeat, see RFC 9250
+    /// section 4.5.
+    pub fn with_zero_rtt(tls: TlsConfig) -> eyre::Result<Self> {
+        Self::build(tls, true)
+    }
+
+    fn build(tls: TlsConfig, zero_rtt: bool) -> eyre::Result<Self> {
+        let mut client = (*tls.with_alpn(ALPN_DOQ).client_config()).clone();
+        client.enable_early_data = zero_rtt;
+        let crypto = QuicClientConfig::try_from(client)?;
+        Ok(QuicConfig {
+            server_name: tls.server_name().into_owned(),
+            config: quinn::ClientConfig::new(Arc::new(crypto)),
+            zero_rtt,
+        })
+    }
+
+    /// Sends `message` on a new stream of a new connection to `addr` and
+    /// returns the response.
+    pub(crate) async fn exchange(&self, addr: SocketAddr, message: &[u8]) -> eyre::Result<Vec<u8>> {
+        let local = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let endpoint = Endpoint::client(local.parse()?)?;
+        let connecting = endpoint.connect_with(self.config.clone(), addr, &self.server_name)?;
+        let connection = if self.zero_rtt {
+            match connecting.into_0rtt() {
+                Ok((connection, _)) => connection,
+                // No session to resume yet
+                Err(connecting) => connecting.await?,
+            }
+        } else {
+            connecting.await?
+        };
+        let (mut send, mut recv) = connection.open_bi().await?;
+        send.write_all(&tcp_frame(message)).await?;
+        send.finish()?;
+        let response = recv.read_to_end(MAX_FRAME_SIZE).await;
+        connection.close(VarInt::from_u32(DOQ_NO_ERROR), b"");
+        // Let the close reach the server without holding up the caller
+        tokio::spawn(async move { endpoint.wait_idle().await });
+        unframe(&response?).ok_or_else(|| eyre::eyre!("malformed DoQ response from {addr}"))
+    }
+}
+
+/// Strips the length prefix off a message that takes up a whole stream.
+fn unframe(data: &[u8]) -> Option<Vec<u8>> {
+    let (len, message) = data.split_first_chunk::<2>()?;
+    (u16::from_be_bytes(*len) as usize == message.len()).then(|| message.to_vec())
+}
+
+/// Builds the server side configuration, see [`crate::tls::server_config`].
+pub fn server_config(cert_pem: &[u8], key_pem: &[u8]) -> eyre::Result<quinn::ServerConfig> {
+    let mut tls = (*crate::tls::server_config(cert_pem, key_pem)?).clone();
+    tls.alpn_protocols = vec![ALPN_DOQ.to_vec()];
+    let crypto = QuicServerConfig::try_from(tls)?;
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(crypto)))
+}
+
+/// Answers the query on one stream. Returns the error code to close the
+/// connection with if the client broke the protocol.
+async fn answer(mut send: SendStream, mut recv: RecvStream) -> Result<(), u32> {
+    let data = recv
+        .read_to_end(MAX_FRAME_SIZE)
+        .await
+        .map_err(|_| DOQ_PROTOCOL_ERROR)?;
+    let query = unframe(&data).ok_or(DOQ_PROTOCOL_ERROR)?;
+    // Queries must use ID 0, RFC 9250 section 4.2.1
+    if query.len() < 12 || query[..2] != [0, 0] {
+        return Err(DOQ_PROTOCOL_ERROR);
+    }
+    let response = handle_request(&query, None).await;
+    send.write_all(&tcp_frame(&response))
+        .await
+        .map_err(|_| DOQ_INTERNAL_ERROR)?;
+    send.finish().map_err(|_| DOQ_INTERNAL_ERROR)
+}
+
+async fn serve_connection(connection: Connection, idle_timeout: Duration) {
+    let client = connection.remote_address();
+    loop {
+        let (send, recv) = match tokio::time::timeout(idle_timeout, connection.accept_bi()).await {
+            Ok(Ok(streams)) => streams,
+            Ok(Err(e)) => {
+                tracing::debug!("QUIC connection from {} closed: {}", client, e);
+                return;
+            }
+            Err(_) => {
+                tracing::debug!("Closing idle connection from {}", client);
+                connection.close(VarInt::from_u32(DOQ_NO_ERROR), b"");
+                return;
+            }
+        };
+        tracing::info!("Received a query stream from {}", client);
+        let connection = connection.clone();
+        tokio::spawn(async move {
+            if let Err(code) = answer(send, recv).await {
+                tracing::debug!("Closing connection from {} with error {}", client, code);
+                connection.close(VarInt::from_u32(code), b"");
+            }
+        });
+    }
+}
+
+/// Serves DNS over QUIC until the endpoint is closed, with one task per
+/// connection and one per query stream. Connections are refused once
+/// `max_connections` are open.
+pub(crate) async fn serve_quic(
+    endpoint: Endpoint,
+    max_connections: usize,
+    idle_timeout: Duration,
+) -> eyre::Result<()> {
+    tracing::debug!("Listening on quic://{}", endpoint.local_addr()?);
+    let connections = Arc::new(tokio::sync::Semaphore::new(max_connections));
+    while let Some(incoming) = endpoint.accept().await {
+        let client = incoming.remote_address();
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+            tracing::warn!("Too many QUIC connections, refusing {}", client);
+            incoming.refuse();
+            continue;
+        };
+        tokio::spawn(async move {
+            match incoming.await {
+                Ok(connection) => serve_connection(connection, idle_timeout).await,
+                Err(e) => tracing::debug!("QUIC handshake with {} failed: {}", client, e),
+            }
+            drop(permit);
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Cursor, net::IpAddr};
+
+    use bytes::Bytes;
+    use rand::rngs::mock::StepRng;
+    use rcgen::{generate_simple_self_signed, CertifiedKey};
+
+    use crate::{
+        builder::MessageBuilder,
+        packet::DNSPacket,
+        r#async::{
+            listener::{quic_listener, ListenerConfig},
+            query::send_query_async,
+        },
+        simple::query::QueryOptions,
+        RCODE_FORMERR, TYPE_A,
+    };
+
+    use super::*;
+
+    fn self_signed() -> (CertifiedKey, quinn::ServerConfig) {
+        let cert = generate_simple_self_signed(vec!["dns.test".to_string()]).unwrap();
+        let config = server_config(
+            cert.cert.pem().as_bytes(),
+            cert.key_pair.serialize_pem().as_bytes(),
+        )
+        .unwrap();
+        (cert, config)
+    }
+
+    fn client_config(cert: &CertifiedKey) -> QuicConfig {
+        let tls = TlsConfig::new("dns.test", Some(cert.cert.pem().as_bytes()), &[]).unwrap();
+        QuicConfig::new(tls).unwrap()
+    }
+
+    fn parse(message: Vec<u8>) -> DNSPacket {
+        DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::from(message))).unwrap()
+    }
+
+    #[test]
+    fn test_unframe() {
+        assert_eq!(unframe(&[0, 2, 1, 2]), Some(vec![1, 2]));
+        assert_eq!(unframe(&[0, 3, 1, 2]), None);
+        assert_eq!(unframe(&[0]), None);
+    }
+
+    #[tokio::test]
+    async fn test_quic_listener() {
+        let (cert, config) = self_signed();
+        let (address, server) = quic_listener(ListenerConfig::default(), config).unwrap();
+        tokio::spawn(server);
+        let client = client_config(&cert);
+
+        // Queries without a question are answered with FORMERR, no resolving needed
+        let query = MessageBuilder::new().to_be_bytes();
+        let response = parse(client.exchange(address, &query).await.unwrap());
+        assert_eq!(response.header().id, 0);
+        assert_eq!(response.header().rcode(), RCODE_FORMERR);
+
+        // Any other ID is a protocol error
+        let query = MessageBuilder::new().id(1).to_be_bytes();
+        assert!(client.exchange(address, &query).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_query_over_quic() {
+        let (cert, config) = self_signed();
+        let endpoint = Endpoint::server(config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = endpoint.local_addr().unwrap();
+        // Answers every query with an empty response
+        tokio::spawn(async move {
+            let connection = endpoint.accept().await.unwrap().await.unwrap();
+            let (mut send, mut recv) = connection.accept_bi().await.unwrap();
+            let query = parse(unframe(&recv.read_to_end(MAX_FRAME_SIZE).await.unwrap()).unwrap());
+            let response = MessageBuilder::response_to(&query).to_be_bytes();
+            send.write_all(&tcp_frame(&response)).await.unwrap();
+            send.finish().unwrap();
+            connection.closed().await;
+        });
+
+        let options = QueryOptions {
+            port: address.port(),
+            retries: 0,
+            ..QueryOptions::quic(client_config(&cert))
+        };
+        let mut mock_rng = StepRng::new(0x8298, 0);
+        let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+        let response = send_query_async(
+            &mut mock_rng,
+            localhost,
+            "www.example.com",
+            TYPE_A,
+            &options,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.header().id, 0);
+        assert_eq!(response.questions()[0].name, "www.example.com");
+    }
+}
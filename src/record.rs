@@ -1,17 +1,202 @@
-use std::{io::Seek, net::IpAddr};
+use std::{fmt, io::Seek, net::IpAddr, str::FromStr};
 
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut};
 
-use crate::encoding::{self, encode_domain_name};
+use crate::{
+    encoding::{self, encode_domain_name, ensure_remaining},
+    types::{class_from_str, class_to_string, name_to_string, type_from_str, type_to_string},
+    CLASS_IN, TYPE_A, TYPE_AAAA, TYPE_CNAME, TYPE_MX, TYPE_NS, TYPE_PTR, TYPE_SOA,
+};
 
 #[derive(Debug, Clone)]
 pub enum DNSRecordResult {
     NameServer(String),
     Address(IpAddr),
+    /// The target of a CNAME record.
+    CanonicalName(String),
+    Pointer(String),
+    MailExchange {
+        preference: u16,
+        exchange: String,
+    },
+    Soa(Soa),
     Unknown(Vec<u8>),
 }
 
-#[derive(Debug)]
+/// Start of authority, see RFC 1035 section 3.3.13. Negative answers carry
+/// the zone's SOA to say how long they may be cached.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Soa {
+    pub mname: String,
+    pub rname: String,
+    pub serial: u32,
+    pub refresh: u32,
+    pub retry: u32,
+    pub expire: u32,
+    pub minimum: u32,
+}
+
+impl Soa {
+    fn parse<B: Buf>(bytes: &mut B, mname: String, rname: String) -> Self {
+        Soa {
+            mname,
+            rname,
+            serial: bytes.get_u32(),
+            refresh: bytes.get_u32(),
+            retry: bytes.get_u32(),
+            expire: bytes.get_u32(),
+            minimum: bytes.get_u32(),
+        }
+    }
+
+    fn from_rdata(mut data: &[u8]) -> Option<Self> {
+        let mname = encoding::decode_name_simple(&mut data)?;
+        let rname = encoding::decode_name_simple(&mut data)?;
+        (data.len() == 20).then(|| Self::parse(&mut data, mname, rname))
+    }
+
+    fn from_text(text: &str) -> eyre::Result<Self> {
+        let fields: Vec<_> = text.split_whitespace().collect();
+        let [mname, rname, numbers @ ..] = &fields[..] else {
+            eyre::bail!("SOA needs 7 fields: {text:?}");
+        };
+        let [serial, refresh, retry, expire, minimum] = numbers
+            .iter()
+            .map(|n| n.parse())
+            .collect::<Result<Vec<u32>, _>>()?[..]
+        else {
+            eyre::bail!("SOA needs 7 fields: {text:?}");
+        };
+        Ok(Soa {
+            mname: parse_name(mname),
+            rname: parse_name(rname),
+            serial,
+            refresh,
+            retry,
+            expire,
+            minimum,
+        })
+    }
+}
+
+impl DNSRecordResult {
+    /// Interprets uncompressed RDATA for the given type, keeping anything we
+    /// can't make sense of as [`DNSRecordResult::Unknown`].
+    pub fn from_rdata(qtype: u16, data: Vec<u8>) -> Self {
+        let parsed = match (qtype, data.len()) {
+            (TYPE_A, 4) => Some(DNSRecordResult::Address(
+                <[u8; 4]>::try_from(&data[..]).unwrap().into(),
+            )),
+            (TYPE_AAAA, 16) => Some(DNSRecordResult::Address(
+                <[u8; 16]>::try_from(&data[..]).unwrap().into(),
+            )),
+            (TYPE_NS, _) => name_rdata(&data).map(DNSRecordResult::NameServer),
+            (TYPE_CNAME, _) => name_rdata(&data).map(DNSRecordResult::CanonicalName),
+            (TYPE_PTR, _) => name_rdata(&data).map(DNSRecordResult::Pointer),
+            (TYPE_MX, 3..) => {
+                name_rdata(&data[2..]).map(|exchange| DNSRecordResult::MailExchange {
+                    preference: u16::from_be_bytes([data[0], data[1]]),
+                    exchange,
+                })
+            }
+            (TYPE_SOA, _) => Soa::from_rdata(&data).map(DNSRecordResult::Soa),
+            _ => None,
+        };
+        parsed.unwrap_or(DNSRecordResult::Unknown(data))
+    }
+
+    /// Parses RDATA in presentation format. Any type accepts the RFC 3597
+    /// generic form `\# <length> <hex>`.
+    pub fn from_text(qtype: u16, text: &str) -> eyre::Result<Self> {
+        let text = text.trim();
+        if let Some(generic) = text.strip_prefix("\\#") {
+            return Ok(Self::from_rdata(qtype, parse_generic_rdata(generic)?));
+        }
+        match qtype {
+            TYPE_A => Ok(DNSRecordResult::Address(IpAddr::V4(text.parse()?))),
+            TYPE_AAAA => Ok(DNSRecordResult::Address(IpAddr::V6(text.parse()?))),
+            TYPE_NS => Ok(DNSRecordResult::NameServer(parse_name(text))),
+            TYPE_CNAME => Ok(DNSRecordResult::CanonicalName(parse_name(text))),
+            TYPE_PTR => Ok(DNSRecordResult::Pointer(parse_name(text))),
+            TYPE_MX => {
+                let (preference, exchange) = split_field(text)
+                    .ok_or_else(|| eyre::eyre!("MX needs a preference and an exchange"))?;
+                Ok(DNSRecordResult::MailExchange {
+                    preference: preference.parse()?,
+                    exchange: parse_name(exchange),
+                })
+            }
+            TYPE_SOA => Ok(DNSRecordResult::Soa(Soa::from_text(text)?)),
+            _ => Err(eyre::eyre!(
+                "RDATA for {} must use the \\# generic syntax",
+                type_to_string(qtype)
+            )),
+        }
+    }
+
+    /// Uncompressed wire form of the RDATA.
+    pub fn to_rdata(&self) -> Vec<u8> {
+        match self {
+            DNSRecordResult::Address(IpAddr::V4(ip)) => ip.octets().to_vec(),
+            DNSRecordResult::Address(IpAddr::V6(ip)) => ip.octets().to_vec(),
+            DNSRecordResult::NameServer(name)
+            | DNSRecordResult::CanonicalName(name)
+            | DNSRecordResult::Pointer(name) => encode_domain_name(name),
+            DNSRecordResult::MailExchange {
+                preference,
+                exchange,
+            } => {
+                let mut data = preference.to_be_bytes().to_vec();
+                data.put_slice(&encode_domain_name(exchange));
+                data
+            }
+            DNSRecordResult::Soa(soa) => {
+                let mut data = encode_domain_name(&soa.mname);
+                data.put_slice(&encode_domain_name(&soa.rname));
+                for n in [soa.serial, soa.refresh, soa.retry, soa.expire, soa.minimum] {
+                    data.put_u32(n);
+                }
+                data
+            }
+            DNSRecordResult::Unknown(data) => data.clone(),
+        }
+    }
+}
+
+/// RDATA that is one name and nothing else.
+fn name_rdata(mut data: &[u8]) -> Option<String> {
+    let name = encoding::decode_name_simple(&mut data)?;
+    data.is_empty().then_some(name)
+}
+
+fn parse_generic_rdata(text: &str) -> eyre::Result<Vec<u8>> {
+    let mut fields = text.split_whitespace();
+    let len: usize = fields
+        .next()
+        .ok_or_else(|| eyre::eyre!("missing RDATA length after \\#"))?
+        .parse()?;
+    let hex: String = fields.collect();
+    if hex.len() != len * 2 {
+        return Err(eyre::eyre!(
+            "RDATA length is {len} but {} hex digits were given",
+            hex.len()
+        ));
+    }
+    (0..len)
+        .map(|i| {
+            hex.get(i * 2..i * 2 + 2)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| eyre::eyre!("invalid hex in RDATA: {hex}"))
+        })
+        .collect()
+}
+
+/// Domain names are stored without the trailing dot, with the root as "".
+fn parse_name(text: &str) -> String {
+    text.strip_suffix('.').unwrap_or(text).to_string()
+}
+
+#[derive(Debug, Clone)]
 pub struct DNSRecord {
     name: String,
     qtype: u16,
@@ -21,83 +206,355 @@ pub struct DNSRecord {
 }
 
 impl DNSRecord {
-    pub fn parse_record<B>(bytes: &mut B) -> Self
+    pub(crate) fn new(
+        name: String,
+        qtype: u16,
+        class: u16,
+        ttl: u32,
+        res: DNSRecordResult,
+    ) -> Self {
+        DNSRecord {
+            name,
+            qtype,
+            class,
+            ttl,
+            res,
+        }
+    }
+
+    pub fn parse_record<B>(bytes: &mut B) -> eyre::Result<Self>
     where
         B: Buf + Seek,
     {
-        let name = encoding::decode_name(bytes);
+        let name = encoding::decode_name(bytes)?;
+        ensure_remaining(bytes, 10)?;
         let qtype = bytes.get_u16();
         let class = bytes.get_u16();
         let ttl = bytes.get_u32();
-
-        // Note: data_len isn't used for NameServer records, but value will be 4
         let data_len = bytes.get_u16() as usize;
+        ensure_remaining(bytes, data_len)?;
+        let end = bytes.stream_position()? + data_len as u64;
 
         let res = match qtype {
-            crate::TYPE_A => {
-                let mut data = vec![0; data_len];
-                bytes.copy_to_slice(&mut data);
-                let raw_ip: [u8; 4] = data.as_slice().try_into().unwrap();
-                let ip = IpAddr::from(raw_ip);
-                DNSRecordResult::Address(ip)
+            TYPE_NS => DNSRecordResult::NameServer(encoding::decode_name(bytes)?),
+            TYPE_CNAME => DNSRecordResult::CanonicalName(encoding::decode_name(bytes)?),
+            TYPE_PTR => DNSRecordResult::Pointer(encoding::decode_name(bytes)?),
+            TYPE_MX => {
+                ensure_remaining(bytes, 2)?;
+                let preference = bytes.get_u16();
+                let exchange = encoding::decode_name(bytes)?;
+                DNSRecordResult::MailExchange {
+                    preference,
+                    exchange,
+                }
             }
-            crate::TYPE_NS => {
-                let name = encoding::decode_name(bytes);
-                DNSRecordResult::NameServer(name)
+            // Names in SOA RDATA may be compressed too
+            TYPE_SOA => {
+                let mname = encoding::decode_name(bytes)?;
+                let rname = encoding::decode_name(bytes)?;
+                ensure_remaining(bytes, 20)?;
+                DNSRecordResult::Soa(Soa::parse(bytes, mname, rname))
             }
             _ => {
                 let mut data = vec![0; data_len];
                 bytes.copy_to_slice(&mut data);
-                DNSRecordResult::Unknown(data)
+                DNSRecordResult::from_rdata(qtype, data)
             }
         };
-        DNSRecord {
+        if bytes.stream_position()? != end {
+            eyre::bail!(
+                "RDATA of {} {} doesn't fill its {data_len} bytes",
+                name_to_string(&name),
+                type_to_string(qtype)
+            );
+        }
+        Ok(DNSRecord {
             name,
             qtype,
             class,
             ttl,
             res,
-        }
+        })
     }
 
-    fn to_record<B>(&self) -> BytesMut {
-        let mut bytes = BytesMut::new();
-        let name = encode_domain_name(&self.name);
-        bytes.put_slice(&name);
+    pub fn to_be_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.put_slice(&encode_domain_name(&self.name));
         bytes.put_u16(self.qtype);
         bytes.put_u16(self.class);
         bytes.put_u32(self.ttl);
-        match self.res {
-            DNSRecordResult::Address(ip) => match ip {
-                IpAddr::V4(ip) => {
-                    bytes.put_u16(4);
-                    bytes.put_slice(&ip.octets());
-                }
-                IpAddr::V6(ip) => {
-                    bytes.put_u16(16);
-                    bytes.put_slice(&ip.octets());
-                }
-            },
-            DNSRecordResult::NameServer(ref name) => {
-                todo!()
-                // let name = encode_domain_name(name);
-                // bytes.put_u16(name.len() as u16);
-                // bytes.put_slice(&name);
-            }
-            DNSRecordResult::Unknown(ref data) => {
-                todo!()
-                // bytes.put_u16(data.len() as u16);
-                // bytes.put_slice(data);
-            }
-        }
-        todo!()
+        let rdata = self.res.to_rdata();
+        bytes.put_u16(rdata.len() as u16);
+        bytes.put_slice(&rdata);
+        bytes
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
     }
 
     pub fn qtype(&self) -> u16 {
         self.qtype
     }
 
+    pub fn class(&self) -> u16 {
+        self.class
+    }
+
+    pub fn ttl(&self) -> u32 {
+        self.ttl
+    }
+
     pub fn res(&self) -> &DNSRecordResult {
         &self.res
     }
+
+    /// A copy of the record with a different owner name.
+    pub(crate) fn with_name(&self, name: &str) -> Self {
+        DNSRecord {
+            name: name.to_string(),
+            ..self.clone()
+        }
+    }
+
+    /// A copy of the record with a different TTL.
+    pub(crate) fn with_ttl(&self, ttl: u32) -> Self {
+        DNSRecord {
+            ttl,
+            ..self.clone()
+        }
+    }
+}
+
+/// Parses a zone file style line, `name [ttl] [class] type rdata`.
+impl FromStr for DNSRecord {
+    type Err = eyre::Report;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let (name, mut rest) = split_field(line).ok_or_else(|| eyre::eyre!("empty record"))?;
+        let mut ttl = 0;
+        let mut class = CLASS_IN;
+        loop {
+            let (field, remainder) =
+                split_field(rest).ok_or_else(|| eyre::eyre!("missing record type in {line:?}"))?;
+            if let Ok(value) = field.parse::<u32>() {
+                ttl = value;
+            } else if let Some(value) = class_from_str(field) {
+                class = value;
+            } else if let Some(qtype) = type_from_str(field) {
+                let res = DNSRecordResult::from_text(qtype, remainder)?;
+                return Ok(DNSRecord::new(parse_name(name), qtype, class, ttl, res));
+            } else {
+                return Err(eyre::eyre!("unknown record type {field:?}"));
+            }
+            rest = remainder;
+        }
+    }
+}
+
+fn split_field(text: &str) -> Option<(&str, &str)> {
+    let text = text.trim_start();
+    if text.is_empty() {
+        return None;
+    }
+    match text.split_once(char::is_whitespace) {
+        Some((field, rest)) => Some((field, rest.trim_start())),
+        None => Some((text, "")),
+    }
+}
+
+impl fmt::Display for DNSRecordResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DNSRecordResult::NameServer(name)
+            | DNSRecordResult::CanonicalName(name)
+            | DNSRecordResult::Pointer(name) => write!(f, "{}", name_to_string(name)),
+            DNSRecordResult::MailExchange {
+                preference,
+                exchange,
+            } => write!(f, "{} {}", preference, name_to_string(exchange)),
+            DNSRecordResult::Address(ip) => write!(f, "{ip}"),
+            DNSRecordResult::Soa(soa) => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                name_to_string(&soa.mname),
+                name_to_string(&soa.rname),
+                soa.serial,
+                soa.refresh,
+                soa.retry,
+                soa.expire,
+                soa.minimum
+            ),
+            DNSRecordResult::Unknown(data) => {
+                write!(f, "\\# {}", data.len())?;
+                if !data.is_empty() {
+                    write!(f, " ")?;
+                    for byte in data {
+                        write!(f, "{byte:02x}")?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for DNSRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}",
+            name_to_string(&self.name),
+            self.ttl,
+            class_to_string(self.class),
+            type_to_string(self.qtype),
+            self.res
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use bytes::Bytes;
+
+    use super::*;
+
+    #[test]
+    fn test_unknown_record_round_trip() {
+        let wire =
+            b"\x07example\x03com\x00\xff\xfe\x00\x01\x00\x00\x0e\x10\x00\x04\x0a\x00\x00\x01";
+        let record = DNSRecord::parse_record(&mut Cursor::new(Bytes::from_static(wire))).unwrap();
+        assert!(matches!(record.res(), DNSRecordResult::Unknown(_)));
+        assert_eq!(record.to_be_bytes(), wire);
+    }
+
+    #[test]
+    fn test_parse_generic_record() {
+        let record: DNSRecord = "example.com. 3600 IN TYPE65534 \\# 4 0a000001"
+            .parse()
+            .unwrap();
+        assert_eq!(record.qtype(), 65534);
+        assert_eq!(record.ttl(), 3600);
+        assert_eq!(
+            record.to_string(),
+            "example.com.\t3600\tIN\tTYPE65534\t\\# 4 0a000001"
+        );
+
+        // Known types accept the generic syntax too
+        let record: DNSRecord = "a.root-servers.net. 3600000 A \\# 4 c6 29 00 04"
+            .parse()
+            .unwrap();
+        assert!(
+            matches!(record.res(), DNSRecordResult::Address(ip) if ip.to_string() == "198.41.0.4")
+        );
+
+        let record: DNSRecord = ". 3600000 NS a.root-servers.net.".parse().unwrap();
+        assert_eq!(record.name(), "");
+        assert_eq!(
+            record.to_string(),
+            ".\t3600000\tIN\tNS\ta.root-servers.net."
+        );
+
+        assert!("example.com. TYPE65534 \\# 4 0a00"
+            .parse::<DNSRecord>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_aaaa_record_round_trip() {
+        let record: DNSRecord = "a.root-servers.net. 3600000 AAAA 2001:503:ba3e::2:30"
+            .parse()
+            .unwrap();
+        let wire = record.to_be_bytes();
+        let parsed = DNSRecord::parse_record(&mut Cursor::new(Bytes::from(wire))).unwrap();
+        assert_eq!(
+            parsed.to_string(),
+            "a.root-servers.net.\t3600000\tIN\tAAAA\t2001:503:ba3e::2:30"
+        );
+    }
+
+    #[test]
+    fn test_soa_record() {
+        let text = "example.com.\t3600\tIN\tSOA\tns.icann.org. noc.dns.icann.org. \
+            2024081401 7200 3600 1209600 3600";
+        let record: DNSRecord = text.parse().unwrap();
+        let DNSRecordResult::Soa(soa) = record.res() else {
+            panic!("not parsed as SOA: {:?}", record.res());
+        };
+        assert_eq!(soa.mname, "ns.icann.org");
+        assert_eq!(soa.minimum, 3600);
+        assert_eq!(record.to_string(), text);
+
+        let wire = record.to_be_bytes();
+        let parsed = DNSRecord::parse_record(&mut Cursor::new(Bytes::from(wire))).unwrap();
+        assert_eq!(parsed.to_string(), text);
+        let DNSRecordResult::Soa(parsed_soa) = parsed.res() else {
+            panic!("not parsed as SOA");
+        };
+        assert_eq!(parsed_soa, soa);
+
+        let mut truncated = record.res().to_rdata();
+        truncated.truncate(truncated.len() - 4);
+        assert!(matches!(
+            DNSRecordResult::from_rdata(TYPE_SOA, truncated),
+            DNSRecordResult::Unknown(_)
+        ));
+        assert!("example.com. SOA ns.icann.org. 1 2 3"
+            .parse::<DNSRecord>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_name_records() {
+        for text in [
+            "www.example.com.\t300\tIN\tCNAME\texample.com.",
+            "1.2.0.192.in-addr.arpa.\t300\tIN\tPTR\twww.example.com.",
+            "example.com.\t300\tIN\tMX\t10 mail.example.com.",
+        ] {
+            let record: DNSRecord = text.parse().unwrap();
+            assert!(!matches!(record.res(), DNSRecordResult::Unknown(_)));
+            assert_eq!(record.to_string(), text);
+            let wire = record.to_be_bytes();
+            let parsed = DNSRecord::parse_record(&mut Cursor::new(Bytes::from(wire))).unwrap();
+            assert_eq!(parsed.to_string(), text);
+            let rdata = DNSRecordResult::from_rdata(record.qtype(), record.res().to_rdata());
+            assert_eq!(rdata.to_string(), record.res().to_string());
+        }
+    }
+
+    #[test]
+    fn test_truncated_name_rdata() {
+        for text in [
+            "example.com. 300 NS \\# 1 05",
+            "example.com. 300 CNAME \\# 4 03777777",
+            "example.com. 300 PTR \\# 2 c00c",
+            "example.com. 300 MX \\# 3 000a05",
+            "example.com. 300 SOA \\# 3 010000",
+            "example.com. 300 NS \\# 3 000000",
+        ] {
+            let record: DNSRecord = text.parse().unwrap();
+            assert!(
+                matches!(record.res(), DNSRecordResult::Unknown(_)),
+                "{text} parsed as {:?}",
+                record.res()
+            );
+        }
+    }
+
+    #[test]
+    fn test_display_unknown_record() {
+        let record = DNSRecord {
+            name: "example.com".to_string(),
+            qtype: 65534,
+            class: crate::CLASS_IN,
+            ttl: 3600,
+            res: DNSRecordResult::Unknown(vec![0x0a, 0x00, 0x00, 0x01]),
+        };
+        assert_eq!(
+            record.to_string(),
+            "example.com.\t3600\tIN\tTYPE65534\t\\# 4 0a000001"
+        );
+    }
 }
@@ -0,0 +1,685 @@
+//! Iterative resolution as a state machine that does no I/O of its own, for
+//! running in an event loop of your own.
+//!
+//! A [`Resolution`] says which query to send where next, and is told what
+//! came back, or that nothing did in time. It never reads the clock, the
+//! current time is passed in with every call.
+//!
+//! ```no_run
+//! # fn send_and_wait(query: &diaw::resolution::Query) -> Option<diaw::packet::DNSPacket> { None }
+//! use std::time::Instant;
+//!
+//! use diaw::{resolution::Step, resolver::Resolver, TYPE_A};
+//!
+//! let mut resolution = Resolver::default().resolution("example.com", TYPE_A);
+//! let mut step = resolution.start(Instant::now());
+//! let lookup = loop {
+//!     match step {
+//!         Step::Query(query) => {
+//!             step = match send_and_wait(&query) {
+//!                 Some(response) => resolution.handle_response(Instant::now(), response),
+//!                 None => resolution.handle_timeout(Instant::now()),
+//!             }
+//!         }
+//!         Step::Done(result) => break result,
+//!     }
+//! };
+//! ```
+
+use std::{
+    mem,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::SmallRng, SeedableRng};
+
+use crate::{
+    cache::{follow_chain_in, is_ancestor, negative_response, RecordCache},
+    error::{QueryError, ResolveError},
+    hints::RootHints,
+    lookup::{cname_target, Authority, Lookup, MAX_CNAMES},
+    packet::DNSPacket,
+    record::{DNSRecord, DNSRecordResult},
+    selection::ServerSelection,
+    CLASS_IN, RCODE_NOERROR, RCODE_NXDOMAIN, TYPE_A, TYPE_CNAME, TYPE_NS,
+};
+
+/// What to do next.
+#[derive(Debug)]
+pub enum Step {
+    /// Send the query and pass what happened to
+    /// [`Resolution::handle_response`], [`Resolution::handle_error`] or, once
+    /// its deadline has passed, [`Resolution::handle_timeout`].
+    Query(Query),
+    Done(eyre::Result<Lookup>),
+}
+
+/// A query to send, see [`crate::simple::query::build_query`] for the
+/// message. Only one is outstanding at a time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query {
+    pub server: IpAddr,
+    pub name: String,
+    pub qtype: u16,
+    /// When to give up waiting for the response.
+    pub deadline: Instant,
+}
+
+#[derive(Debug)]
+enum State {
+    /// Checking the cache for the target, or else picking the nameservers
+    /// to start from.
+    Start,
+    /// Asking the nameservers of `zone` one after the other.
+    Querying { zone: String, servers: Vec<IpAddr> },
+    /// Looking up the addresses of the nameservers of `zone`, the ones not
+    /// tried yet in `pending`. The lookup is the frame above.
+    Nameservers { zone: String, pending: Vec<String> },
+}
+
+/// One name being resolved, either the one asked for or the address of a
+/// nameserver without glue.
+#[derive(Debug)]
+struct Frame {
+    name: String,
+    qtype: u16,
+    /// Ignore a cached answer for `name` itself, to refresh it.
+    refresh: bool,
+    /// Where the CNAME chain has got to.
+    target: String,
+    cnames: Vec<DNSRecord>,
+    state: State,
+    /// Why the last server asked didn't answer.
+    error: Option<eyre::Report>,
+}
+
+impl Frame {
+    fn new(name: &str, qtype: u16, refresh: bool) -> Self {
+        Frame {
+            name: name.to_string(),
+            qtype,
+            refresh,
+            target: name.to_string(),
+            cnames: Vec::new(),
+            state: State::Start,
+            error: None,
+        }
+    }
+
+    fn lookup(&mut self, records: Vec<DNSRecord>, authority: Option<Authority>) -> Lookup {
+        Lookup {
+            name: self.name.clone(),
+            qtype: self.qtype,
+            cnames: mem::take(&mut self.cnames),
+            records,
+            authority,
+        }
+    }
+
+    /// After a CNAME the denial is about the name at the end of the chain,
+    /// but the answer is for the name asked about.
+    fn deny(&self, negative: ResolveError) -> eyre::Report {
+        if self.cnames.is_empty() {
+            negative.into()
+        } else {
+            negative.for_alias(&self.name).into()
+        }
+    }
+}
+
+/// What a response meant for the frame on top.
+enum Outcome {
+    /// The frame's result.
+    Done(eyre::Result<Lookup>),
+    /// More queries to send.
+    Continue,
+    /// The server doesn't serve the zone it was asked about, so the next one
+    /// should be tried.
+    Lame(eyre::Report),
+}
+
+/// Where a query was sent and when.
+#[derive(Debug)]
+struct Outstanding {
+    server: IpAddr,
+    sent: Instant,
+}
+
+/// The resolution of one name, see the [module docs](self). Created with
+/// [`crate::resolver::Resolver::resolution`], whose cache, server selection
+/// and root hints it uses.
+#[derive(Debug)]
+pub struct Resolution {
+    cache: Arc<RecordCache>,
+    selection: Arc<ServerSelection>,
+    hints: Arc<RootHints>,
+    timeout: Duration,
+    port: u16,
+    max_depth: usize,
+    rng: SmallRng,
+    /// Asked about every name instead of the authorities, if any.
+    forwarders: Vec<IpAddr>,
+    stack: Vec<Frame>,
+    outstanding: Option<Outstanding>,
+}
+
+impl Resolution {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        cache: Arc<RecordCache>,
+        selection: Arc<ServerSelection>,
+        hints: Arc<RootHints>,
+        timeout: Duration,
+        port: u16,
+        max_depth: usize,
+        name: &str,
+        qtype: u16,
+    ) -> Self {
+        Resolution {
+            cache,
+            selection,
+            hints,
+            timeout,
+            port,
+            max_depth,
+            rng: SmallRng::from_entropy(),
+            forwarders: Vec::new(),
+            stack: vec![Frame::new(name, qtype, false)],
+            outstanding: None,
+        }
+    }
+
+    /// Ignores a cached answer for the name asked about and asks its
+    /// authorities again, for prefetching.
+    pub(crate) fn refreshing(mut self) -> Self {
+        self.stack[0].refresh = true;
+        self
+    }
+
+    /// Asks `forwarders` instead of the authorities, trusting them for every
+    /// zone. The query options have to ask for recursion.
+    pub(crate) fn forwarding(mut self, forwarders: &[IpAddr]) -> Self {
+        self.forwarders = forwarders.to_vec();
+        self
+    }
+
+    /// The first step, which may be the answer straight from the cache.
+    pub fn start(&mut self, now: Instant) -> Step {
+        self.advance(now)
+    }
+
+    /// Takes the response to the outstanding query.
+    pub fn handle_response(&mut self, now: Instant, response: DNSPacket) -> Step {
+        // Servers that fail or refuse to answer for the zone are no use, same
+        // as ones that can't be reached
+        let rcode = response.header().rcode();
+        if !matches!(rcode, RCODE_NOERROR | RCODE_NXDOMAIN) && self.outstanding.is_some() {
+            return self.handle_error(now, eyre::eyre!("Server answered with rcode {rcode}"));
+        }
+        let Some(Outstanding { server, sent }) = self.outstanding.take() else {
+            return Step::Done(Err(eyre::eyre!("No query outstanding")));
+        };
+        tracing::debug!("Response from {}: {:?}", server, response);
+        let outcome = self.process(server, &response);
+        if let Outcome::Lame(error) = outcome {
+            return self.fail_over(now, server, error);
+        }
+        self.selection
+            .record_rtt(server, now.saturating_duration_since(sent));
+        if let Outcome::Done(result) = outcome {
+            if let Some(step) = self.finish(result) {
+                return step;
+            }
+        }
+        self.advance(now)
+    }
+
+    /// Gives up on the outstanding query after it failed, and asks the next
+    /// server.
+    pub fn handle_error(&mut self, now: Instant, error: eyre::Report) -> Step {
+        let Some(Outstanding { server, .. }) = self.outstanding.take() else {
+            return Step::Done(Err(eyre::eyre!("No query outstanding")));
+        };
+        self.fail_over(now, server, error)
+    }
+
+    /// Counts `server` as failed for `error`, and asks the next server.
+    fn fail_over(&mut self, now: Instant, server: IpAddr, error: eyre::Report) -> Step {
+        tracing::warn!("Query to {server} failed, trying the next server: {error}");
+        self.selection.record_failure(server);
+        if let Some(frame) = self.stack.last_mut() {
+            frame.error = Some(error);
+        }
+        self.advance(now)
+    }
+
+    /// Gives up on the outstanding query once its deadline has passed.
+    pub fn handle_timeout(&mut self, now: Instant) -> Step {
+        let Some(Outstanding { server, sent }) = &self.outstanding else {
+            return Step::Done(Err(eyre::eyre!("No query outstanding")));
+        };
+        let error = QueryError::Timeout {
+            server: SocketAddr::new(*server, self.port),
+            attempts: 1,
+            elapsed: now.saturating_duration_since(*sent),
+        };
+        self.handle_error(now, error.into())
+    }
+
+    /// Works through the frame on top until it needs a query sent or the
+    /// whole resolution is done.
+    fn advance(&mut self, now: Instant) -> Step {
+        loop {
+            let Some(frame) = self.stack.last_mut() else {
+                return Step::Done(Err(eyre::eyre!("Resolution already finished")));
+            };
+            let result = match &mut frame.state {
+                State::Start => match self.check_cache() {
+                    Some(result) => result,
+                    None => continue,
+                },
+                State::Querying { servers, .. } if !servers.is_empty() => {
+                    let server = servers.remove(0);
+                    self.outstanding = Some(Outstanding { server, sent: now });
+                    return Step::Query(Query {
+                        server,
+                        name: frame.target.clone(),
+                        qtype: frame.qtype,
+                        deadline: now + self.timeout,
+                    });
+                }
+                State::Querying { .. } => Err(frame
+                    .error
+                    .take()
+                    .unwrap_or_else(|| eyre::eyre!("no servers to query for {}", frame.target))),
+                State::Nameservers { .. } => unreachable!("nameserver lookups are on top"),
+            };
+            if let Some(step) = self.finish(result) {
+                return step;
+            }
+        }
+    }
+
+    /// Answers the frame on top from the cache as far as possible. Otherwise
+    /// picks the nameservers to ask and returns `None`.
+    fn check_cache(&mut self) -> Option<eyre::Result<Lookup>> {
+        let frame = self.stack.last_mut()?;
+        let cache = &self.cache;
+        loop {
+            if frame.cnames.len() > MAX_CNAMES {
+                return Some(Err(eyre::eyre!(
+                    "CNAME chain from {} is too long",
+                    frame.name
+                )));
+            }
+            let target = &frame.target;
+            if let Some(negative) = cache.negative(target, frame.qtype, CLASS_IN) {
+                tracing::debug!("Negative cache hit for {}: {}", target, negative);
+                return Some(Err(frame.deny(negative)));
+            }
+            if frame.refresh && frame.cnames.is_empty() {
+                break;
+            }
+            if let Some(records) = cache.get(target, frame.qtype, CLASS_IN) {
+                tracing::debug!("Cache hit for {} type {}", target, frame.qtype);
+                return Some(Ok(frame.lookup(records, None)));
+            }
+            match cache.get(target, TYPE_CNAME, CLASS_IN) {
+                Some(cname) if frame.qtype != TYPE_CNAME => {
+                    tracing::debug!("Cache hit for {} type CNAME", target);
+                    frame.target = cname_target(&cname[0]).unwrap_or_default().to_string();
+                    frame.cnames.extend(cname);
+                }
+                _ => break,
+            }
+        }
+
+        let (zone, servers) = if !self.forwarders.is_empty() {
+            (String::new(), self.forwarders.clone())
+        } else {
+            match cache.closest_nameservers(&frame.target) {
+                Some((zone, addresses)) => {
+                    tracing::debug!(
+                        "Starting from cached zone cut {:?} for {}",
+                        zone,
+                        frame.target
+                    );
+                    (zone, addresses)
+                }
+                None => (String::new(), self.hints.shuffled(&mut self.rng)),
+            }
+        };
+        let servers = self.selection.order(&mut self.rng, &servers);
+        frame.state = State::Querying { zone, servers };
+        None
+    }
+
+    /// Handles a response from `server` for the frame on top.
+    fn process(&mut self, server: IpAddr, response: &DNSPacket) -> Outcome {
+        let Some(frame) = self.stack.last_mut() else {
+            return Outcome::Continue;
+        };
+        let State::Querying { zone, .. } = &mut frame.state else {
+            unreachable!("queries are only sent while querying");
+        };
+        let (target, qtype) = (frame.target.as_str(), frame.qtype);
+        self.cache.cache_response(zone, target, qtype, response);
+        if let Some(negative) = negative_response(zone, target, qtype, response) {
+            return Outcome::Done(Err(frame.deny(negative)));
+        }
+
+        let (chain, records) = follow_chain_in(zone, target, qtype, response.answers());
+        if let Some(records) = records {
+            let authority = Authority {
+                zone: mem::take(zone),
+                server,
+            };
+            frame.cnames.extend(chain);
+            return Outcome::Done(Ok(frame.lookup(records, Some(authority))));
+        }
+        if let Some(last) = chain.last() {
+            // The rest of the chain is elsewhere or outside the zone, start
+            // over from its end
+            frame.target = cname_target(last).unwrap_or_default().to_string();
+            frame.cnames.extend(chain);
+            frame.state = State::Start;
+            return Outcome::Continue;
+        }
+
+        let delegation: Vec<_> = response
+            .authorities()
+            .iter()
+            .filter(|record| record.qtype() == TYPE_NS)
+            .collect();
+        let Some(next_zone) = delegation.first().map(|r| normalize_zone(r.name())) else {
+            return Outcome::Lame(eyre::eyre!("No answer or nameserver found"));
+        };
+        // Referrals have to lead further down, or we'd go round in circles
+        if next_zone == *zone || !is_ancestor(zone, &next_zone) {
+            return Outcome::Lame(eyre::eyre!(
+                "Lame referral to {next_zone:?} from {server} for zone {zone:?}"
+            ));
+        }
+        let glue = response.get_nameserver_ips();
+        if !glue.is_empty() {
+            let servers = self.selection.order(&mut self.rng, &glue);
+            frame.state = State::Querying {
+                zone: next_zone,
+                servers,
+            };
+            return Outcome::Continue;
+        }
+        let pending = delegation
+            .iter()
+            .rev()
+            .filter_map(|record| match record.res() {
+                DNSRecordResult::NameServer(ns) => Some(ns.clone()),
+                _ => None,
+            })
+            .collect();
+        frame.state = State::Nameservers {
+            zone: next_zone,
+            pending,
+        };
+        match self.next_nameserver() {
+            Ok(()) => Outcome::Continue,
+            Err(e) => Outcome::Done(Err(e)),
+        }
+    }
+
+    /// Starts looking up the address of the next nameserver the frame on top
+    /// is waiting for.
+    fn next_nameserver(&mut self) -> eyre::Result<()> {
+        let depth = self.stack.len();
+        let Some(Frame {
+            state: State::Nameservers { zone, pending },
+            ..
+        }) = self.stack.last_mut()
+        else {
+            unreachable!("only frames waiting for nameservers have pending ones");
+        };
+        while let Some(ns) = pending.pop() {
+            if depth > self.max_depth {
+                tracing::debug!("Too many nested nameserver lookups for {}", ns);
+                continue;
+            }
+            self.stack.push(Frame::new(&ns, TYPE_A, false));
+            return Ok(());
+        }
+        eyre::bail!("No addresses found for the nameservers of {zone:?}")
+    }
+
+    /// Ends the frame on top with `result`. Returns the last step if that was
+    /// the name asked about, otherwise hands the result to the frame below.
+    fn finish(&mut self, mut result: eyre::Result<Lookup>) -> Option<Step> {
+        loop {
+            self.stack.pop();
+            let Some(parent) = self.stack.last_mut() else {
+                return Some(Step::Done(result));
+            };
+            let State::Nameservers { zone, .. } = &mut parent.state else {
+                unreachable!("only nameserver lookups are nested");
+            };
+            match result {
+                Ok(lookup) if !lookup.addresses().is_empty() => {
+                    let servers = self.selection.order(&mut self.rng, &lookup.addresses());
+                    parent.state = State::Querying {
+                        zone: mem::take(zone),
+                        servers,
+                    };
+                    return None;
+                }
+                Ok(lookup) => tracing::debug!("No address for nameserver {}", lookup.name),
+                Err(e) => tracing::debug!("No address for a nameserver of {:?}: {}", zone, e),
+            }
+            match self.next_nameserver() {
+                Ok(()) => return None,
+                Err(e) => result = Err(e),
+            }
+        }
+    }
+}
+
+fn normalize_zone(name: &str) -> String {
+    name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{builder::MessageBuilder, header::FLAG_AA};
+
+    use super::*;
+
+    fn resolution(name: &str, cache: Arc<RecordCache>) -> Resolution {
+        let hints = RootHints::from_named_root(
+            ". 3600000 NS a.root.test.\n\
+             . 3600000 NS b.root.test.\n\
+             a.root.test. 3600000 A 10.0.0.1\n\
+             b.root.test. 3600000 A 10.0.0.2",
+        )
+        .unwrap();
+        Resolution::new(
+            cache,
+            Arc::default(),
+            Arc::new(hints),
+            Duration::from_secs(2),
+            53,
+            4,
+            name,
+            TYPE_A,
+        )
+    }
+
+    fn response(query: &Query, answers: &[&str], authorities: &[&str]) -> DNSPacket {
+        let parse = |line: &&str| line.parse::<DNSRecord>().unwrap();
+        let builder = MessageBuilder::new().question(&query.name, query.qtype, CLASS_IN);
+        let builder = answers
+            .iter()
+            .map(parse)
+            .fold(builder, MessageBuilder::answer);
+        authorities
+            .iter()
+            .map(parse)
+            .fold(builder, MessageBuilder::authority)
+            .flag(FLAG_AA, !answers.is_empty())
+            .build()
+    }
+
+    fn expect_query(step: Step) -> Query {
+        match step {
+            Step::Query(query) => query,
+            Step::Done(result) => panic!("expected a query, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn test_timeout_fails_over() {
+        let mut resolution = resolution("www.test", Arc::default());
+        let now = Instant::now();
+        let first = expect_query(resolution.start(now));
+        assert_eq!(first.name, "www.test");
+        assert_eq!(first.deadline, now + Duration::from_secs(2));
+
+        let now = first.deadline;
+        let second = expect_query(resolution.handle_timeout(now));
+        assert_ne!(second.server, first.server);
+        assert!(resolution.selection.srtt(first.server).is_some());
+
+        let answer = response(&second, &["www.test. 300 A 192.0.2.1"], &[]);
+        let Step::Done(Ok(lookup)) =
+            resolution.handle_response(now + Duration::from_millis(30), answer)
+        else {
+            panic!("expected the answer");
+        };
+        assert_eq!(lookup.authority.unwrap().server, second.server);
+        assert_eq!(
+            resolution.selection.srtt(second.server),
+            Some(Duration::from_millis(30))
+        );
+        assert!(matches!(resolution.handle_timeout(now), Step::Done(Err(_))));
+    }
+
+    #[test]
+    fn test_all_servers_fail() {
+        let mut resolution = resolution("www.test", Arc::default());
+        let now = Instant::now();
+        expect_query(resolution.start(now));
+        expect_query(resolution.handle_error(now, eyre::eyre!("unreachable")));
+        let Step::Done(Err(error)) = resolution.handle_timeout(now) else {
+            panic!("expected an error");
+        };
+        assert!(matches!(
+            error.downcast_ref::<QueryError>(),
+            Some(QueryError::Timeout { attempts: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_from_cache() {
+        let cache = Arc::new(RecordCache::default());
+        cache.insert(vec!["www.test. 300 A 192.0.2.1".parse().unwrap()]);
+        let mut resolution = resolution("WWW.test.", cache);
+        let Step::Done(Ok(lookup)) = resolution.start(Instant::now()) else {
+            panic!("expected the cached answer");
+        };
+        assert_eq!(lookup.authority, None);
+        assert_eq!(lookup.records.len(), 1);
+    }
+
+    #[test]
+    fn test_glueless_referral() {
+        let mut resolution = resolution("www.test", Arc::default());
+        let now = Instant::now();
+        let query = expect_query(resolution.start(now));
+        let referral = response(&query, &[], &["test. 3600 NS ns.test.net."]);
+
+        // The nameserver's address is looked up from the root too
+        let query = expect_query(resolution.handle_response(now, referral));
+        assert_eq!((query.name.as_str(), query.qtype), ("ns.test.net", TYPE_A));
+        let address = response(&query, &["ns.test.net. 3600 A 10.0.0.53"], &[]);
+
+        let query = expect_query(resolution.handle_response(now, address));
+        assert_eq!(query.server, "10.0.0.53".parse::<IpAddr>().unwrap());
+        assert_eq!(query.name, "www.test");
+        let answer = response(&query, &["www.test. 300 A 192.0.2.1"], &[]);
+        let Step::Done(Ok(lookup)) = resolution.handle_response(now, answer) else {
+            panic!("expected the answer");
+        };
+        assert_eq!(lookup.authority.unwrap().zone, "test");
+    }
+
+    #[test]
+    fn test_out_of_zone_cname_target() {
+        let cache = Arc::new(RecordCache::default());
+        cache.insert(vec!["test. 3600 NS ns.test.".parse().unwrap()]);
+        cache.insert(vec!["ns.test. 3600 A 10.0.0.53".parse().unwrap()]);
+        let mut resolution = resolution("www.test", cache.clone());
+        let now = Instant::now();
+        let query = expect_query(resolution.start(now));
+        assert_eq!(query.server, "10.0.0.53".parse::<IpAddr>().unwrap());
+        // The server for test vouches for an address in example as well
+        let spoofed = response(
+            &query,
+            &[
+                "www.test. 300 CNAME www.example.",
+                "www.example. 300 A 192.0.2.66",
+            ],
+            &[],
+        );
+
+        let query = expect_query(resolution.handle_response(now, spoofed));
+        assert_eq!(query.name, "www.example");
+        assert_ne!(query.server, "10.0.0.53".parse::<IpAddr>().unwrap());
+        assert!(cache.answer("www.example", TYPE_A).is_none());
+        let answer = response(&query, &["www.example. 300 A 192.0.2.1"], &[]);
+        let Step::Done(Ok(lookup)) = resolution.handle_response(now, answer) else {
+            panic!("expected the answer");
+        };
+        assert_eq!(lookup.cnames.len(), 1);
+        assert_eq!(lookup.addresses(), ["192.0.2.1".parse::<IpAddr>().unwrap()]);
+    }
+
+    #[test]
+    fn test_upward_referral_fails_over() {
+        let cache = Arc::new(RecordCache::default());
+        cache.insert(vec![
+            "test. 3600 NS ns1.test.".parse().unwrap(),
+            "test. 3600 NS ns2.test.".parse().unwrap(),
+        ]);
+        cache.insert(vec!["ns1.test. 3600 A 10.0.0.53".parse().unwrap()]);
+        cache.insert(vec!["ns2.test. 3600 A 10.0.0.54".parse().unwrap()]);
+        let mut resolution = resolution("www.test", cache);
+        let now = Instant::now();
+        let first = expect_query(resolution.start(now));
+        // Sends us back up to the root instead of answering
+        let upward = response(&first, &[], &[". 3600 NS a.root.test."]);
+
+        let second = expect_query(resolution.handle_response(now, upward));
+        assert_eq!(second.name, "www.test");
+        assert_ne!(second.server, first.server);
+        assert!(resolution.selection.srtt(first.server).is_some());
+        let answer = response(&second, &["www.test. 300 A 192.0.2.1"], &[]);
+        let Step::Done(Ok(lookup)) = resolution.handle_response(now, answer) else {
+            panic!("expected the answer");
+        };
+        assert_eq!(lookup.authority.unwrap().server, second.server);
+    }
+
+    #[test]
+    fn test_empty_response_fails_over() {
+        let mut resolution = resolution("www.test", Arc::default());
+        let now = Instant::now();
+        let first = expect_query(resolution.start(now));
+        let empty = response(&first, &[], &[]);
+        let second = expect_query(resolution.handle_response(now, empty));
+        assert_eq!(second.name, "www.test");
+        assert_ne!(second.server, first.server);
+        let Step::Done(Err(error)) = resolution.handle_response(now, response(&second, &[], &[]))
+        else {
+            panic!("expected an error");
+        };
+        assert_eq!(error.to_string(), "No answer or nameserver found");
+    }
+}
@@ -0,0 +1,549 @@
+//! The iterative resolver: a [`Resolution`] driven over a [`Transport`],
+//! through a blocking or an async front end.
+
+use std::{
+    net::IpAddr,
+    sync::{mpsc, Arc, Mutex, RwLock},
+    time::Instant,
+};
+
+use crate::{
+    cache::{record_cache, RecordCache},
+    error::ResolveError,
+    hints::{root_hints, RootHints},
+    lookup::Lookup,
+    resolution::{Resolution, Step},
+    selection::{server_selection, ServerSelection},
+    simple::query::QueryOptions,
+    transport::{Transport, UdpTransport},
+    CLASS_IN,
+};
+
+static RESOLVER: RwLock<Option<Resolver>> = RwLock::new(None);
+
+/// The thread refreshing answers for the blocking front end, started on the
+/// first prefetch.
+static PREFETCHER: Mutex<Option<mpsc::Sender<(Resolver, String, u16)>>> = Mutex::new(None);
+
+#[derive(Debug, Clone)]
+pub struct ResolverConfig {
+    /// Options for every query sent, iterative ones by default. The
+    /// transport decides which protocol is used.
+    pub query: QueryOptions,
+    /// How deep looking up the addresses of nameservers without glue may
+    /// nest, which in turn may need nameservers looked up.
+    pub max_depth: usize,
+    /// Resolvers to send every query to instead of starting from the root
+    /// hints, failing over between them. Empty to resolve iteratively.
+    pub forwarders: Vec<IpAddr>,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        ResolverConfig {
+            query: QueryOptions::iterative(),
+            max_depth: 4,
+            forwarders: Vec::new(),
+        }
+    }
+}
+
+impl ResolverConfig {
+    /// Forwards every query to `forwarders`, with recursion desired.
+    pub fn forwarding(forwarders: &[IpAddr]) -> Self {
+        ResolverConfig {
+            query: QueryOptions::default(),
+            forwarders: forwarders.to_vec(),
+            ..Self::default()
+        }
+    }
+}
+
+/// Resolves names by following referrals from the root hints, or by asking
+/// forwarders, caching what it learns on the way.
+///
+/// By default resolvers share the process-wide cache, server selection and
+/// root hints, see the `with_` methods to give them their own.
+#[derive(Debug, Clone)]
+pub struct Resolver {
+    config: ResolverConfig,
+    transport: Arc<dyn Transport>,
+    cache: Arc<RecordCache>,
+    selection: Arc<ServerSelection>,
+    hints: Arc<RootHints>,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new(ResolverConfig::default(), UdpTransport)
+    }
+}
+
+impl Resolver {
+    pub fn new<T: Transport + 'static>(config: ResolverConfig, transport: T) -> Self {
+        Resolver {
+            config,
+            transport: Arc::new(transport),
+            cache: record_cache(),
+            selection: server_selection(),
+            hints: root_hints(),
+        }
+    }
+
+    pub fn with_cache(self, cache: Arc<RecordCache>) -> Self {
+        Resolver { cache, ..self }
+    }
+
+    pub fn with_selection(self, selection: Arc<ServerSelection>) -> Self {
+        Resolver { selection, ..self }
+    }
+
+    pub fn with_root_hints(self, hints: Arc<RootHints>) -> Self {
+        Resolver { hints, ..self }
+    }
+
+    pub fn config(&self) -> &ResolverConfig {
+        &self.config
+    }
+
+    pub fn cache(&self) -> &Arc<RecordCache> {
+        &self.cache
+    }
+
+    /// Resolves `name` for every record of `qtype`, following CNAMEs. Answers
+    /// from the cache carry the TTL they have left.
+    ///
+    /// Popular answers are refreshed on a background thread shortly before
+    /// they expire, and recently expired answers are served when the
+    /// authorities can't be reached, see [`crate::cache::CacheConfig`].
+    ///
+    /// Blocks on the transport's [`Transport::query_blocking`].
+    pub fn lookup(&self, name: &str, qtype: u16) -> eyre::Result<Lookup> {
+        if let Some(lookup) = self.cached(name, qtype) {
+            if self.cache.should_prefetch(name, qtype) {
+                tracing::debug!("Prefetching {} type {}", name, qtype);
+                prefetch_in_background(self.clone(), name, qtype);
+            }
+            return Ok(lookup);
+        }
+        let result = self.run_blocking(self.resolution(name, qtype));
+        self.or_stale(name, qtype, result)
+    }
+
+    /// Like [`Resolver::lookup`], prefetching on a new task instead.
+    pub async fn lookup_async(&self, name: &str, qtype: u16) -> eyre::Result<Lookup> {
+        if let Some(lookup) = self.cached(name, qtype) {
+            if self.cache.should_prefetch(name, qtype) {
+                tracing::debug!("Prefetching {} type {}", name, qtype);
+                let resolver = self.clone();
+                let name = name.to_string();
+                tokio::spawn(async move { resolver.prefetch(&name, qtype).await });
+            }
+            return Ok(lookup);
+        }
+        self.lookup_uncached(name, qtype).await
+    }
+
+    fn cached(&self, name: &str, qtype: u16) -> Option<Lookup> {
+        let records = self.cache.get(name, qtype, CLASS_IN)?;
+        tracing::debug!("Cache hit for {} type {}", name, qtype);
+        Some(Lookup {
+            name: name.to_string(),
+            qtype,
+            cnames: Vec::new(),
+            records,
+            authority: None,
+        })
+    }
+
+    async fn prefetch(&self, name: &str, qtype: u16) {
+        if let Err(e) = self.run(self.resolution(name, qtype).refreshing()).await {
+            tracing::debug!("Prefetching {} failed: {}", name, e);
+        }
+    }
+
+    fn prefetch_blocking(&self, name: &str, qtype: u16) {
+        if let Err(e) = self.run_blocking(self.resolution(name, qtype).refreshing()) {
+            tracing::debug!("Prefetching {} failed: {}", name, e);
+        }
+    }
+
+    /// Resolves from the authorities, falling back to a stale answer when they
+    /// can't be reached.
+    async fn lookup_uncached(&self, name: &str, qtype: u16) -> eyre::Result<Lookup> {
+        let result = self.run(self.resolution(name, qtype)).await;
+        self.or_stale(name, qtype, result)
+    }
+
+    /// A stale answer in place of `result` when resolving failed for want of
+    /// a response, rather than with one.
+    fn or_stale(
+        &self,
+        name: &str,
+        qtype: u16,
+        result: eyre::Result<Lookup>,
+    ) -> eyre::Result<Lookup> {
+        match result {
+            Err(e) if e.downcast_ref::<ResolveError>().is_none() => {
+                match self.cache.get_stale(name, qtype, CLASS_IN) {
+                    Some(records) => {
+                        tracing::warn!("Serving stale answer for {}: {}", name, e);
+                        Ok(Lookup {
+                            name: name.to_string(),
+                            qtype,
+                            cnames: Vec::new(),
+                            records,
+                            authority: None,
+                        })
+                    }
+                    None => Err(e),
+                }
+            }
+            result => result,
+        }
+    }
+
+    /// The state machine for resolving `name`, to drive with I/O of your own.
+    /// It uses this resolver's cache, server selection, root hints and
+    /// forwarders, and the timeout in its query options.
+    pub fn resolution(&self, name: &str, qtype: u16) -> Resolution {
+        Resolution::new(
+            self.cache.clone(),
+            self.selection.clone(),
+            self.hints.clone(),
+            self.config.query.timeout,
+            self.config.query.port,
+            self.config.max_depth,
+            name,
+            qtype,
+        )
+        .forwarding(&self.config.forwarders)
+    }
+
+    /// Drives `resolution` with the transport. The transport keeps its own
+    /// time, so the deadlines of the queries are ignored.
+    async fn run(&self, mut resolution: Resolution) -> eyre::Result<Lookup> {
+        let mut step = resolution.start(Instant::now());
+        loop {
+            step = match step {
+                Step::Query(query) => {
+                    let response = self
+                        .transport
+                        .query(query.server, &query.name, query.qtype, &self.config.query)
+                        .await;
+                    match response {
+                        Ok(response) => resolution.handle_response(Instant::now(), response),
+                        Err(e) => resolution.handle_error(Instant::now(), e),
+                    }
+                }
+                Step::Done(result) => return result,
+            }
+        }
+    }
+
+    /// Like [`Resolver::run`], blocking on the transport instead.
+    fn run_blocking(&self, mut resolution: Resolution) -> eyre::Result<Lookup> {
+        let mut step = resolution.start(Instant::now());
+        loop {
+            step = match step {
+                Step::Query(query) => {
+                    let response = self.transport.query_blocking(
+                        query.server,
+                        &query.name,
+                        query.qtype,
+                        &self.config.query,
+                    );
+                    match response {
+                        Ok(response) => resolution.handle_response(Instant::now(), response),
+                        Err(e) => resolution.handle_error(Instant::now(), e),
+                    }
+                }
+                Step::Done(result) => return result,
+            }
+        }
+    }
+}
+
+/// Queues a refresh of `name` on the prefetch thread, starting it if it isn't
+/// running yet.
+fn prefetch_in_background(resolver: Resolver, name: &str, qtype: u16) {
+    let mut prefetcher = PREFETCHER.lock().unwrap();
+    let mut job = (resolver, name.to_string(), qtype);
+    if let Some(sender) = prefetcher.as_ref() {
+        match sender.send(job) {
+            Ok(()) => return,
+            Err(mpsc::SendError(unsent)) => job = unsent,
+        }
+    }
+    let (sender, receiver) = mpsc::channel::<(Resolver, String, u16)>();
+    let spawned = std::thread::Builder::new()
+        .name("prefetch".to_string())
+        .spawn(move || {
+            for (resolver, name, qtype) in receiver {
+                resolver.prefetch_blocking(&name, qtype);
+            }
+        });
+    match spawned {
+        Ok(_) => {
+            // The receiver is alive until the sender is dropped
+            sender.send(job).unwrap();
+            *prefetcher = Some(sender);
+        }
+        Err(e) => tracing::warn!("Could not start the prefetch thread: {}", e),
+    }
+}
+
+/// The resolver behind [`crate::simple::resolve`], [`crate::r#async::resolve`]
+/// and the listeners. A default one over UDP unless replaced with
+/// [`set_resolver`].
+pub fn resolver() -> Resolver {
+    RESOLVER.read().unwrap().clone().unwrap_or_default()
+}
+
+pub fn set_resolver(resolver: Resolver) {
+    *RESOLVER.write().unwrap() = Some(resolver);
+}
+
+/// A resolver answering from `tests/fixtures/resolve.txt`, starting from
+/// a.root-servers.net alone.
+#[cfg(test)]
+pub(crate) fn fixture_resolver() -> Resolver {
+    use crate::transport::{FixtureTransport, Fixtures};
+
+    let fixtures = Fixtures::parse(include_str!("../tests/fixtures/resolve.txt")).unwrap();
+    fixture_resolver_with(FixtureTransport::new(fixtures))
+}
+
+/// A resolver over `transport` starting from a.root-servers.net alone, with a
+/// cache and server selection of its own.
+#[cfg(test)]
+fn fixture_resolver_with<T: Transport + 'static>(transport: T) -> Resolver {
+    let hints = RootHints::from_named_root(
+        ". 3600000 NS a.root-servers.net.\na.root-servers.net. 3600000 A 198.41.0.4",
+    )
+    .unwrap();
+    Resolver::new(ResolverConfig::default(), transport)
+        .with_cache(Arc::default())
+        .with_selection(Arc::default())
+        .with_root_hints(Arc::new(hints))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use std::net::IpAddr;
+
+    use crate::{
+        builder::MessageBuilder,
+        header::FLAG_AA,
+        lookup::Authority,
+        packet::DNSPacket,
+        record::DNSRecord,
+        transport::{MockTransport, Recorder},
+        RCODE_NXDOMAIN, TYPE_A,
+    };
+
+    use super::*;
+
+    const ROOT: &str = "10.0.0.1";
+    const TEST: &str = "10.0.0.2";
+    const OTHER: &str = "10.0.0.3";
+    const SUB: &str = "10.0.0.4";
+
+    fn records(lines: &[&str]) -> Vec<DNSRecord> {
+        lines.iter().map(|line| line.parse().unwrap()).collect()
+    }
+
+    fn referral(name: &str, qtype: u16, ns: &[&str], glue: &[&str]) -> DNSPacket {
+        let builder = MessageBuilder::new().question(name, qtype, CLASS_IN);
+        let builder = records(ns)
+            .into_iter()
+            .fold(builder, MessageBuilder::authority);
+        records(glue)
+            .into_iter()
+            .fold(builder, MessageBuilder::additional)
+            .build()
+    }
+
+    fn answer(name: &str, qtype: u16, answers: &[&str]) -> DNSPacket {
+        let builder = MessageBuilder::new()
+            .question(name, qtype, CLASS_IN)
+            .flag(FLAG_AA, true);
+        records(answers)
+            .into_iter()
+            .fold(builder, MessageBuilder::answer)
+            .build()
+    }
+
+    /// The root delegates `test` and `other`. `www.test` is a CNAME into
+    /// `other`, and `sub.test` is served by a nameserver in `other`, without
+    /// glue.
+    fn hierarchy(server: IpAddr, name: &str, qtype: u16) -> eyre::Result<DNSPacket> {
+        let name = name.to_ascii_lowercase();
+        let response = match (server.to_string().as_str(), name.as_str()) {
+            (ROOT, name) if name.ends_with("test") => referral(
+                name,
+                qtype,
+                &["test. 3600 NS ns.test."],
+                &["ns.test. 3600 A 10.0.0.2"],
+            ),
+            (ROOT, name) if name.ends_with("other") => referral(
+                name,
+                qtype,
+                &["other. 3600 NS ns.other."],
+                &["ns.other. 3600 A 10.0.0.3"],
+            ),
+            (TEST, "www.test") => answer(&name, qtype, &["www.test. 300 CNAME web.other."]),
+            (TEST, "gone.test") => MessageBuilder::new()
+                .question(&name, qtype, CLASS_IN)
+                .flag(FLAG_AA, true)
+                .rcode(RCODE_NXDOMAIN)
+                .authority(records(&["test. 300 SOA ns.test. admin.test. 1 2 3 4 60"])[0].clone())
+                .build(),
+            (TEST, name) if name.ends_with("sub.test") => {
+                referral(name, qtype, &["sub.test. 3600 NS ns.sub.other."], &[])
+            }
+            (OTHER, "web.other") => answer(
+                &name,
+                qtype,
+                &["web.other. 60 A 192.0.2.1", "web.other. 60 A 192.0.2.2"],
+            ),
+            (OTHER, "ns.sub.other") => answer(&name, qtype, &["ns.sub.other. 3600 A 10.0.0.4"]),
+            (SUB, "host.sub.test") => answer(&name, qtype, &["host.sub.test. 300 A 192.0.2.9"]),
+            _ => eyre::bail!("{server} has nothing for {name}"),
+        };
+        Ok(response)
+    }
+
+    fn resolver(queries: Arc<AtomicUsize>) -> Resolver {
+        let hints = RootHints::from_named_root(
+            ". 3600000 NS a.root.test.\na.root.test. 3600000 A 10.0.0.1",
+        )
+        .unwrap();
+        let transport = MockTransport::new(move |server, name, qtype| {
+            queries.fetch_add(1, Ordering::SeqCst);
+            hierarchy(server, name, qtype)
+        });
+        Resolver::new(ResolverConfig::default(), transport)
+            .with_cache(Arc::default())
+            .with_selection(Arc::default())
+            .with_root_hints(Arc::new(hints))
+    }
+
+    #[test]
+    fn test_lookup() {
+        let queries = Arc::new(AtomicUsize::new(0));
+        let resolver = resolver(queries.clone());
+        let lookup = resolver.lookup("www.test", TYPE_A).unwrap();
+        assert_eq!(lookup.cnames.len(), 1);
+        assert_eq!(lookup.canonical_name(), "web.other");
+        assert_eq!(lookup.addresses().len(), 2);
+        assert_eq!(
+            lookup.authority,
+            Some(Authority {
+                zone: "other".to_string(),
+                server: OTHER.parse().unwrap()
+            })
+        );
+        // Root, test, root again for other, and other
+        assert_eq!(queries.load(Ordering::SeqCst), 4);
+
+        // From the cache, CNAME and all
+        let lookup = resolver.lookup("www.test", TYPE_A).unwrap();
+        assert_eq!(lookup.cnames.len(), 1);
+        assert_eq!(lookup.authority, None);
+        assert_eq!(queries.load(Ordering::SeqCst), 4);
+    }
+
+    /// Rewrites `tests/fixtures/resolve.txt` with what the internet answers
+    /// now.
+    #[test]
+    #[ignore = "queries the root servers and rewrites the fixtures"]
+    fn test_regenerate_fixtures() {
+        const HEADER: &str = "\
+; Responses to iterative A queries from the root down, replayed by
+; diaw::transport::FixtureTransport. Regenerate with
+; cargo test test_regenerate_fixtures -- --ignored
+";
+        let recorder = Arc::new(Recorder::new(UdpTransport));
+        // A cache of its own for each name, so each is recorded from the root
+        for name in ["twitter.com", "www.example.com", "www.google.com"] {
+            fixture_resolver_with(recorder.clone())
+                .lookup(name, TYPE_A)
+                .unwrap();
+        }
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/resolve.txt");
+        recorder.save(path).unwrap();
+        let recorded = std::fs::read_to_string(path).unwrap();
+        std::fs::write(path, format!("{HEADER}{recorded}")).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_lookup_in_runtime() {
+        // The blocking front end doesn't start a runtime, so it may be called
+        // from within one
+        let resolver = resolver(Arc::new(AtomicUsize::new(0)));
+        let lookup = resolver.lookup("host.sub.test", TYPE_A).unwrap();
+        assert_eq!(lookup.addresses(), ["192.0.2.9".parse::<IpAddr>().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_async() {
+        let resolver = resolver(Arc::new(AtomicUsize::new(0)));
+        let lookup = resolver
+            .lookup_async("host.sub.test", TYPE_A)
+            .await
+            .unwrap();
+        assert_eq!(lookup.addresses(), ["192.0.2.9".parse::<IpAddr>().unwrap()]);
+        assert_eq!(
+            lookup.authority,
+            Some(Authority {
+                zone: "sub.test".to_string(),
+                server: SUB.parse().unwrap()
+            })
+        );
+
+        let error = resolver
+            .lookup_async("gone.test", TYPE_A)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ResolveError>(),
+            Some(ResolveError::NxDomain { .. })
+        ));
+        assert!(resolver.lookup_async("nowhere.test", TYPE_A).await.is_err());
+    }
+
+    #[test]
+    fn test_forwarding() {
+        const DOWN: &str = "10.0.0.8";
+        const FORWARDER: &str = "10.0.0.9";
+        let transport =
+            MockTransport::new(
+                |server, name, qtype| match (server.to_string().as_str(), name) {
+                    (FORWARDER, "www.test") => Ok(MessageBuilder::new()
+                        .question(name, qtype, CLASS_IN)
+                        .answer(records(&["www.test. 300 CNAME web.other."])[0].clone())
+                        .answer(records(&["web.other. 60 A 192.0.2.1"])[0].clone())
+                        .build()),
+                    _ => eyre::bail!("{server} is down"),
+                },
+            );
+        let forwarders = [DOWN.parse().unwrap(), FORWARDER.parse().unwrap()];
+        let resolver = Resolver::new(ResolverConfig::forwarding(&forwarders), transport)
+            .with_cache(Arc::default())
+            .with_selection(Arc::default());
+        assert!(resolver.config().query.recursion_desired);
+        let lookup = resolver.lookup("www.test", TYPE_A).unwrap();
+        assert_eq!(lookup.canonical_name(), "web.other");
+        assert_eq!(lookup.addresses(), ["192.0.2.1".parse::<IpAddr>().unwrap()]);
+        assert_eq!(
+            lookup.authority,
+            Some(Authority {
+                zone: String::new(),
+                server: FORWARDER.parse().unwrap()
+            })
+        );
+    }
+}
@@ -0,0 +1,265 @@
+//! Picks which of a zone's nameservers to ask, from the round trip times and
+//! failures seen so far, in the spirit of BIND and Unbound.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+/// What a server we haven't heard from yet is assumed to take, so that known
+/// fast servers are preferred but unknown ones still beat slow ones.
+const UNKNOWN_RTT: Duration = Duration::from_millis(376);
+
+/// Failures double the smoothed RTT, up to this.
+const MAX_RTT: Duration = Duration::from_secs(120);
+
+static SERVER_SELECTION: RwLock<Option<Arc<ServerSelection>>> = RwLock::new(None);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectionConfig {
+    /// How long to skip a server after `max_failures` failures in a row.
+    pub hold_down: Duration,
+    pub max_failures: u32,
+    /// The chance of trying some other server first instead of the fastest
+    /// one, so that servers which have become faster get noticed.
+    pub explore: f64,
+}
+
+impl Default for SelectionConfig {
+    fn default() -> Self {
+        SelectionConfig {
+            hold_down: Duration::from_secs(60),
+            max_failures: 3,
+            explore: 0.05,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ServerState {
+    srtt: Duration,
+    failures: u32,
+    held_until: Option<Instant>,
+}
+
+impl Default for ServerState {
+    fn default() -> Self {
+        ServerState {
+            srtt: UNKNOWN_RTT,
+            failures: 0,
+            held_until: None,
+        }
+    }
+}
+
+/// Smoothed RTT and failure counts per server address, shared by every zone
+/// the server is authoritative for.
+#[derive(Debug, Default)]
+pub struct ServerSelection {
+    config: SelectionConfig,
+    servers: Mutex<HashMap<IpAddr, ServerState>>,
+}
+
+impl ServerSelection {
+    pub fn new(config: SelectionConfig) -> Self {
+        ServerSelection {
+            config,
+            servers: Mutex::default(),
+        }
+    }
+
+    pub fn config(&self) -> &SelectionConfig {
+        &self.config
+    }
+
+    /// The smoothed RTT of `server`, if it has been asked anything yet.
+    pub fn srtt(&self, server: IpAddr) -> Option<Duration> {
+        let servers = self.servers.lock().unwrap();
+        servers.get(&server).map(|state| state.srtt)
+    }
+
+    pub fn is_held_down(&self, server: IpAddr) -> bool {
+        let servers = self.servers.lock().unwrap();
+        servers
+            .get(&server)
+            .is_some_and(|state| Self::held(state, Instant::now()))
+    }
+
+    fn held(state: &ServerState, now: Instant) -> bool {
+        state.held_until.is_some_and(|until| now < until)
+    }
+
+    /// Orders `candidates` fastest first, keeping the given order between
+    /// servers that are equally fast, such as ones never tried. Held down
+    /// servers go last, to be tried only when all others fail.
+    pub fn order<R: rand::Rng>(&self, rng: &mut R, candidates: &[IpAddr]) -> Vec<IpAddr> {
+        let now = Instant::now();
+        let mut ordered: Vec<_> = {
+            let servers = self.servers.lock().unwrap();
+            candidates
+                .iter()
+                .map(|&ip| {
+                    let state = servers.get(&ip).cloned().unwrap_or_default();
+                    (Self::held(&state, now), state.srtt, ip)
+                })
+                .collect()
+        };
+        ordered.sort_by_key(|&(held, srtt, _)| (held, srtt));
+        let available = ordered.iter().take_while(|(held, ..)| !held).count();
+        if available > 1 && rng.gen_bool(self.config.explore) {
+            let explored = rng.gen_range(1..available);
+            tracing::debug!(
+                "Exploring {} instead of {}",
+                ordered[explored].2,
+                ordered[0].2
+            );
+            ordered[..=explored].rotate_right(1);
+        }
+        ordered.into_iter().map(|(_, _, ip)| ip).collect()
+    }
+
+    /// Records a response from `server` after `rtt`, clearing its failures.
+    pub fn record_rtt(&self, server: IpAddr, rtt: Duration) {
+        let mut servers = self.servers.lock().unwrap();
+        match servers.get_mut(&server) {
+            // BIND weighs in new samples by 30%
+            Some(state) => {
+                state.srtt = state.srtt * 7 / 10 + rtt * 3 / 10;
+                state.failures = 0;
+                state.held_until = None;
+            }
+            None => {
+                servers.insert(
+                    server,
+                    ServerState {
+                        srtt: rtt,
+                        ..ServerState::default()
+                    },
+                );
+            }
+        }
+    }
+
+    /// Records that `server` didn't answer, holding it down once it has
+    /// failed `max_failures` times in a row.
+    pub fn record_failure(&self, server: IpAddr) {
+        let mut servers = self.servers.lock().unwrap();
+        let state = servers.entry(server).or_default();
+        state.srtt = (state.srtt * 2).min(MAX_RTT);
+        state.failures += 1;
+        if state.failures >= self.config.max_failures {
+            tracing::warn!(
+                "{} failed {} times, holding it down for {:?}",
+                server,
+                state.failures,
+                self.config.hold_down
+            );
+            state.held_until = Some(Instant::now() + self.config.hold_down);
+        }
+    }
+}
+
+/// The selection both resolvers share, with the default config unless
+/// replaced with [`set_server_selection`].
+pub fn server_selection() -> Arc<ServerSelection> {
+    if let Some(selection) = SERVER_SELECTION.read().unwrap().as_ref() {
+        return selection.clone();
+    }
+    SERVER_SELECTION
+        .write()
+        .unwrap()
+        .get_or_insert_with(Arc::default)
+        .clone()
+}
+
+pub fn set_server_selection(selection: ServerSelection) {
+    *SERVER_SELECTION.write().unwrap() = Some(Arc::new(selection));
+}
+
+#[cfg(test)]
+mod test {
+    use rand::rngs::mock::StepRng;
+
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn no_exploring() -> ServerSelection {
+        ServerSelection::new(SelectionConfig {
+            explore: 0.0,
+            ..SelectionConfig::default()
+        })
+    }
+
+    #[test]
+    fn test_prefers_fastest() {
+        let selection = no_exploring();
+        let rng = &mut StepRng::new(0, 0);
+        let servers = [ip("192.0.2.1"), ip("192.0.2.2"), ip("192.0.2.3")];
+        // Unknown servers keep their order
+        assert_eq!(selection.order(rng, &servers), servers);
+
+        selection.record_rtt(servers[0], Duration::from_millis(500));
+        selection.record_rtt(servers[1], Duration::from_millis(20));
+        assert_eq!(
+            selection.order(rng, &servers),
+            [servers[1], servers[2], servers[0]]
+        );
+
+        selection.record_rtt(servers[1], Duration::from_millis(120));
+        assert_eq!(selection.srtt(servers[1]), Some(Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn test_hold_down() {
+        let selection = no_exploring();
+        let rng = &mut StepRng::new(0, 0);
+        let servers = [ip("192.0.2.1"), ip("2001:db8::1")];
+        selection.record_rtt(servers[0], Duration::from_millis(10));
+        for _ in 0..2 {
+            selection.record_failure(servers[0]);
+        }
+        assert!(!selection.is_held_down(servers[0]));
+        assert_eq!(selection.srtt(servers[0]), Some(Duration::from_millis(40)));
+        assert_eq!(selection.order(rng, &servers), servers);
+
+        selection.record_failure(servers[0]);
+        assert!(selection.is_held_down(servers[0]));
+        assert_eq!(selection.order(rng, &servers), [servers[1], servers[0]]);
+
+        selection.record_rtt(servers[0], Duration::from_millis(10));
+        assert!(!selection.is_held_down(servers[0]));
+    }
+
+    #[test]
+    fn test_hold_down_expires() {
+        let selection = ServerSelection::new(SelectionConfig {
+            hold_down: Duration::ZERO,
+            max_failures: 1,
+            explore: 0.0,
+        });
+        let server = ip("192.0.2.1");
+        selection.record_failure(server);
+        assert!(!selection.is_held_down(server));
+        assert_eq!(selection.srtt(server), Some(UNKNOWN_RTT * 2));
+    }
+
+    #[test]
+    fn test_explore() {
+        let selection = ServerSelection::new(SelectionConfig {
+            explore: 1.0,
+            ..SelectionConfig::default()
+        });
+        let servers = [ip("192.0.2.1"), ip("192.0.2.2"), ip("192.0.2.3")];
+        selection.record_rtt(servers[0], Duration::from_millis(10));
+        // The fastest server comes second, after the explored one
+        assert_eq!(
+            selection.order(&mut StepRng::new(0, 0), &servers),
+            [servers[1], servers[0], servers[2]]
+        );
+    }
+}
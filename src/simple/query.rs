@@ -1,31 +1,573 @@
 use std::{
-    io::Cursor,
-    net::{IpAddr, SocketAddr, UdpSocket},
+    fmt,
+    io::{Cursor, ErrorKind, Read, Write},
+    net::{IpAddr, SocketAddr, TcpStream, UdpSocket},
+    time::{Duration, Instant},
 };
 
-use bytes::Bytes;
+use bytes::{BufMut, Bytes};
 use socket2::{Domain, Type};
 
+#[cfg(feature = "tls")]
+use crate::tls::{TlsConfig, DOT_PORT};
 use crate::{
-    encoding::encode_domain_name, header::DNSHeader, packet::DNSPacket, question::DNSQuestion,
+    builder::MessageBuilder,
+    edns::Edns,
+    error::QueryError,
+    header::{FLAG_CD, FLAG_QR, FLAG_RD, FLAG_TC},
+    packet::DNSPacket,
+    selection::ServerSelection,
     CLASS_IN,
 };
+#[cfg(feature = "https")]
+use crate::{
+    https::{HttpsConfig, DOH_PORT},
+    r#async::query::exchange_https_async,
+};
+#[cfg(feature = "quic")]
+use crate::{
+    quic::{QuicConfig, DOQ_PORT},
+    r#async::query::exchange_quic_async,
+};
+
+/// Transport used to reach the server.
+#[derive(Debug, Clone, Default)]
+pub enum Protocol {
+    /// UDP, retrying over TCP when the response is truncated.
+    #[default]
+    Udp,
+    /// Always use TCP.
+    Tcp,
+    /// DNS over TLS, see [`crate::tls`].
+    #[cfg(feature = "tls")]
+    Tls(TlsConfig),
+    /// DNS over HTTPS, see [`crate::https`].
+    #[cfg(feature = "https")]
+    Https(HttpsConfig),
+    /// DNS over QUIC, see [`crate::quic`].
+    #[cfg(feature = "quic")]
+    Quic(QuicConfig),
+}
+
+/// Knobs for a single query, shared by [`send_query`] and
+/// [`crate::r#async::query::send_query_async`].
+///
+/// The default asks for recursion from a server on port 53, like `dig` does.
+/// Use [`QueryOptions::iterative`] when walking the delegation chain yourself.
+#[derive(Debug, Clone)]
+pub struct QueryOptions {
+    pub recursion_desired: bool,
+    pub checking_disabled: bool,
+    /// Sets the DO bit, which implies sending an OPT record.
+    pub dnssec_ok: bool,
+    pub port: u16,
+    pub class: u16,
+    /// Advertised UDP payload size. `None` sends no OPT record unless
+    /// `dnssec_ok` is set.
+    pub edns_udp_size: Option<u16>,
+    /// Randomizes the case of the query name ("DNS 0x20") and rejects
+    /// responses that don't echo it back exactly.
+    pub randomize_case: bool,
+    /// How long to wait for a matching response to the first attempt. Each
+    /// retry waits twice as long as the previous one.
+    pub timeout: Duration,
+    /// Number of attempts after the first one.
+    pub retries: u32,
+    /// Upper bound on the time spent across all attempts.
+    pub deadline: Option<Duration>,
+    pub protocol: Protocol,
+}
+
+impl Default for QueryOptions {
+    fn default() -> Self {
+        QueryOptions {
+            recursion_desired: true,
+            checking_disabled: false,
+            dnssec_ok: false,
+            port: 53,
+            class: CLASS_IN,
+            edns_udp_size: None,
+            randomize_case: false,
+            timeout: Duration::from_secs(2),
+            retries: 2,
+            deadline: Some(Duration::from_secs(10)),
+            protocol: Protocol::Udp,
+        }
+    }
+}
+
+impl QueryOptions {
+    /// Options for querying authoritative servers directly, with RD cleared.
+    pub fn iterative() -> Self {
+        QueryOptions {
+            recursion_desired: false,
+            ..Self::default()
+        }
+    }
+
+    /// Options for querying a DNS over TLS server on its well-known port.
+    #[cfg(feature = "tls")]
+    pub fn tls(config: TlsConfig) -> Self {
+        QueryOptions {
+            port: DOT_PORT,
+            protocol: Protocol::Tls(config),
+            ..Self::default()
+        }
+    }
+
+    /// Options for querying a DNS over HTTPS server on its well-known port.
+    #[cfg(feature = "https")]
+    pub fn https(config: HttpsConfig) -> Self {
+        QueryOptions {
+            port: DOH_PORT,
+            protocol: Protocol::Https(config),
+            ..Self::default()
+        }
+    }
+
+    /// Options for querying a DNS over QUIC server on its well-known port.
+    #[cfg(feature = "quic")]
+    pub fn quic(config: QuicConfig) -> Self {
+        QueryOptions {
+            port: DOQ_PORT,
+            protocol: Protocol::Quic(config),
+            ..Self::default()
+        }
+    }
 
-pub fn build_query<R: rand::Rng>(rng: &mut R, domain_name: &str, record_type: u16) -> Vec<u8> {
-    let name = encode_domain_name(domain_name);
+    fn edns(&self) -> Option<Edns> {
+        if self.edns_udp_size.is_none() && !self.dnssec_ok {
+            return None;
+        }
+        let mut edns = Edns {
+            dnssec_ok: self.dnssec_ok,
+            ..Edns::default()
+        };
+        if let Some(size) = self.edns_udp_size {
+            edns.udp_payload_size = size;
+        }
+        Some(edns)
+    }
+
+    /// Size of the buffer to receive UDP responses into.
+    pub(crate) fn recv_buffer_size(&self) -> usize {
+        self.edns()
+            .map_or(1024, |edns| (edns.udp_payload_size as usize).max(1024))
+    }
+}
+
+/// Flips the case of each ASCII letter in `name` with probability one half.
+pub fn randomize_case<R: rand::Rng>(rng: &mut R, name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if rng.gen::<bool>() {
+                c.to_ascii_uppercase()
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .collect()
+}
+
+pub(crate) fn build_query_packet<R: rand::Rng>(
+    rng: &mut R,
+    domain_name: &str,
+    record_type: u16,
+    options: &QueryOptions,
+) -> DNSPacket {
     let id = rng.gen::<u16>();
-    let recursion_desired = 0;
-    let mut header = DNSHeader::new(id, recursion_desired);
-    header.num_questions = 1;
-    let question = DNSQuestion {
-        name,
-        qtype: record_type,
-        class: CLASS_IN,
+    // DoH uses ID 0 so that HTTP caches see identical requests, RFC 8484
+    // section 4.1
+    #[cfg(feature = "https")]
+    let id = if matches!(options.protocol, Protocol::Https(_)) {
+        0
+    } else {
+        id
+    };
+    // DoQ matches responses by stream and requires ID 0, RFC 9250 section
+    // 4.2.1
+    #[cfg(feature = "quic")]
+    let id = if matches!(options.protocol, Protocol::Quic(_)) {
+        0
+    } else {
+        id
+    };
+    let name = if options.randomize_case {
+        randomize_case(rng, domain_name)
+    } else {
+        domain_name.to_string()
+    };
+    let mut builder = MessageBuilder::new()
+        .id(id)
+        .flag(FLAG_RD, options.recursion_desired)
+        .flag(FLAG_CD, options.checking_disabled)
+        .question(&name, record_type, options.class);
+    if let Some(edns) = options.edns() {
+        builder = builder.edns(edns);
+    }
+    builder.build()
+}
+
+pub fn build_query<R: rand::Rng>(
+    rng: &mut R,
+    domain_name: &str,
+    record_type: u16,
+    options: &QueryOptions,
+) -> Vec<u8> {
+    build_query_packet(rng, domain_name, record_type, options).to_be_bytes()
+}
+
+/// Whether `response` echoes the question names of `query` with the exact
+/// same case.
+pub(crate) fn case_matches(query: &DNSPacket, response: &DNSPacket) -> bool {
+    query.questions().len() == response.questions().len()
+        && query
+            .questions()
+            .iter()
+            .zip(response.questions())
+            .all(|(q, r)| q.name == r.name)
+}
+
+/// Options for the retry after a server failed to preserve 0x20 case.
+pub(crate) fn case_fallback(options: &QueryOptions) -> QueryOptions {
+    QueryOptions {
+        randomize_case: false,
+        ..options.clone()
+    }
+}
+
+/// Why a datagram was not accepted as the response to our query.
+#[derive(Debug)]
+pub(crate) enum Mismatch {
+    Source(SocketAddr),
+    TooShort(usize),
+    Id(u16),
+    NotResponse,
+    Malformed(eyre::Report),
+    Question,
+    Case,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::Source(from) => write!(f, "unexpected source {from}"),
+            Mismatch::TooShort(len) => write!(f, "only {len} bytes long"),
+            Mismatch::Id(id) => write!(f, "unexpected ID {id}"),
+            Mismatch::NotResponse => write!(f, "QR bit not set"),
+            Mismatch::Malformed(e) => write!(f, "malformed: {e}"),
+            Mismatch::Question => write!(f, "question section does not match"),
+            Mismatch::Case => write!(f, "question name case does not match (0x20)"),
+        }
+    }
+}
+
+/// Checks that `data`, received from `from`, answers `query` sent to
+/// `server`. Question names are compared case-insensitively unless
+/// `exact_case`, as needed for 0x20.
+pub(crate) fn check_response(
+    query: &DNSPacket,
+    server: SocketAddr,
+    from: SocketAddr,
+    data: &[u8],
+    exact_case: bool,
+) -> Result<DNSPacket, Mismatch> {
+    if from != server {
+        return Err(Mismatch::Source(from));
+    }
+    // Check the header before parsing the rest, datagrams that aren't meant
+    // for us needn't be parsed at all
+    if data.len() < 12 {
+        return Err(Mismatch::TooShort(data.len()));
+    }
+    let id = u16::from_be_bytes([data[0], data[1]]);
+    if id != query.header().id {
+        return Err(Mismatch::Id(id));
+    }
+    if data[2] & (FLAG_QR >> 8) as u8 == 0 {
+        return Err(Mismatch::NotResponse);
+    }
+    let response = DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::copy_from_slice(data)))
+        .map_err(Mismatch::Malformed)?;
+    let questions_match = query.questions().len() == response.questions().len()
+        && query
+            .questions()
+            .iter()
+            .zip(response.questions())
+            .all(|(q, r)| {
+                q.name.eq_ignore_ascii_case(&r.name) && q.qtype == r.qtype && q.class == r.class
+            });
+    if !questions_match {
+        return Err(Mismatch::Question);
+    }
+    if exact_case && !case_matches(query, &response) {
+        return Err(Mismatch::Case);
+    }
+    Ok(response)
+}
+
+/// The responses accepted by the attempts of one query, and the count of
+/// those thrown away.
+#[derive(Debug, Default)]
+pub(crate) struct Matching {
+    /// Whether question names must keep the case of the query, see
+    /// [`QueryOptions::randomize_case`].
+    pub(crate) exact_case: bool,
+    pub(crate) discarded: usize,
+    /// Of the discarded responses, those that only had the wrong case.
+    pub(crate) wrong_case: usize,
+}
+
+impl Matching {
+    /// Checks `data` with [`check_response`], counting it if it doesn't
+    /// match.
+    pub(crate) fn check(
+        &mut self,
+        query: &DNSPacket,
+        server: SocketAddr,
+        from: SocketAddr,
+        data: &[u8],
+    ) -> Option<DNSPacket> {
+        match check_response(query, server, from, data, self.exact_case) {
+            Ok(response) => Some(response),
+            Err(mismatch) => {
+                tracing::debug!("Discarding response from {from}: {mismatch}");
+                self.discarded += 1;
+                if matches!(mismatch, Mismatch::Case) {
+                    self.wrong_case += 1;
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Tracks the attempts of one query against its per-attempt timeouts and
+/// overall deadline.
+pub(crate) struct Attempts {
+    server: SocketAddr,
+    start: Instant,
+    deadline: Option<Instant>,
+    timeout: Duration,
+    retries: u32,
+    attempts: u32,
+    pub(crate) matching: Matching,
+}
+
+impl Attempts {
+    pub(crate) fn new(server: SocketAddr, options: &QueryOptions) -> Self {
+        let start = Instant::now();
+        Attempts {
+            server,
+            start,
+            deadline: options.deadline.map(|deadline| start + deadline),
+            timeout: options.timeout,
+            retries: options.retries,
+            attempts: 0,
+            matching: Matching {
+                exact_case: options.randomize_case,
+                ..Matching::default()
+            },
+        }
+    }
+
+    /// Deadline of the next attempt, or `None` when out of attempts or time.
+    pub(crate) fn next_deadline(&mut self) -> Option<Instant> {
+        if self.attempts > self.retries {
+            return None;
+        }
+        let now = Instant::now();
+        let timeout = self.timeout.saturating_mul(1 << self.attempts.min(16));
+        let deadline = match self.deadline {
+            Some(overall) if overall <= now => return None,
+            Some(overall) => (now + timeout).min(overall),
+            None => now + timeout,
+        };
+        if self.attempts > 0 {
+            tracing::debug!(
+                "Retrying {} (attempt {}), waiting {:?}",
+                self.server,
+                self.attempts + 1,
+                deadline - now
+            );
+        }
+        self.attempts += 1;
+        Some(deadline)
+    }
+
+    /// Gives the current attempt another go, used when falling back from 0x20
+    /// or to TCP shouldn't count against the retries.
+    pub(crate) fn repeat(&mut self) {
+        self.attempts -= 1;
+    }
+
+    /// The error to report once every attempt has run out of time.
+    pub(crate) fn error(&self) -> QueryError {
+        if self.matching.discarded > 0 {
+            QueryError::NoMatchingResponse {
+                server: self.server,
+                discarded: self.matching.discarded,
+            }
+        } else {
+            QueryError::Timeout {
+                server: self.server,
+                attempts: self.attempts,
+                elapsed: self.start.elapsed(),
+            }
+        }
+    }
+}
+
+/// Sends `query` and waits until `deadline` for a matching response.
+/// Returns `None` if none arrived in time.
+fn exchange_udp(
+    query: &DNSPacket,
+    addr: SocketAddr,
+    options: &QueryOptions,
+    deadline: Instant,
+    matching: &mut Matching,
+) -> eyre::Result<Option<DNSPacket>> {
+    let socket = socket2::Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+    let socket: UdpSocket = socket.into();
+    socket.send_to(&query.to_be_bytes(), addr)?;
+    let mut buf = vec![0; options.recv_buffer_size()];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(None);
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(response) = matching.check(query, addr, from, &buf[..len]) {
+            return Ok(Some(response));
+        }
+    }
+}
+
+/// Prefixes a message with its two byte length for TCP, see RFC 1035 section
+/// 4.2.2.
+pub(crate) fn tcp_frame(message: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(message.len() + 2);
+    framed.put_u16(message.len() as u16);
+    framed.put_slice(message);
+    framed
+}
+
+fn remaining(deadline: Instant) -> Option<Duration> {
+    Some(deadline.saturating_duration_since(Instant::now())).filter(|d| !d.is_zero())
+}
+
+/// Opens a TCP connection to `addr`, or returns `None` if that takes past
+/// `deadline`.
+fn connect_tcp(addr: SocketAddr, deadline: Instant) -> eyre::Result<Option<TcpStream>> {
+    let Some(timeout) = remaining(deadline) else {
+        return Ok(None);
+    };
+    match TcpStream::connect_timeout(&addr, timeout) {
+        Ok(stream) => Ok(Some(stream)),
+        Err(e) if e.kind() == ErrorKind::TimedOut => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Sends `query` over a connected `stream` and waits until `deadline` for a
+/// matching response. Timeouts are set on `socket`, the TCP connection
+/// underneath `stream`. Returns `None` if no response arrived in time.
+fn exchange_stream<S: Read + Write>(
+    stream: &mut S,
+    socket: &TcpStream,
+    query: &DNSPacket,
+    addr: SocketAddr,
+    deadline: Instant,
+    matching: &mut Matching,
+) -> eyre::Result<Option<DNSPacket>> {
+    let timed_out =
+        |e: &std::io::Error| matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut);
+    socket.set_write_timeout(remaining(deadline))?;
+    match stream.write_all(&tcp_frame(&query.to_be_bytes())) {
+        Err(e) if timed_out(&e) => return Ok(None),
+        written => written?,
+    }
+    loop {
+        let Some(timeout) = remaining(deadline) else {
+            return Ok(None);
+        };
+        socket.set_read_timeout(Some(timeout))?;
+        let mut len = [0; 2];
+        let read = stream.read_exact(&mut len).and_then(|_| {
+            let mut message = vec![0; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut message).map(|_| message)
+        });
+        let message = match read {
+            Ok(message) => message,
+            Err(e) if timed_out(&e) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(response) = matching.check(query, addr, addr, &message) {
+            return Ok(Some(response));
+        }
+    }
+}
+
+/// Sends `query` over a new TCP connection and waits until `deadline` for a
+/// matching response. Returns `None` if none arrived in time.
+fn exchange_tcp(
+    query: &DNSPacket,
+    addr: SocketAddr,
+    deadline: Instant,
+    matching: &mut Matching,
+) -> eyre::Result<Option<DNSPacket>> {
+    let Some(mut stream) = connect_tcp(addr, deadline)? else {
+        return Ok(None);
+    };
+    let socket = stream.try_clone()?;
+    exchange_stream(&mut stream, &socket, query, addr, deadline, matching)
+}
+
+/// Like [`exchange_tcp`], with the connection wrapped in TLS.
+#[cfg(feature = "tls")]
+fn exchange_tls(
+    query: &DNSPacket,
+    addr: SocketAddr,
+    tls: &TlsConfig,
+    deadline: Instant,
+    matching: &mut Matching,
+) -> eyre::Result<Option<DNSPacket>> {
+    let Some(stream) = connect_tcp(addr, deadline)? else {
+        return Ok(None);
+    };
+    let socket = stream.try_clone()?;
+    let mut stream = tls.connect(stream)?;
+    exchange_stream(&mut stream, &socket, query, addr, deadline, matching)
+}
+
+/// Runs an async exchange to completion, for the transports whose libraries
+/// only come in async. Inside a runtime already, it runs on a thread of its
+/// own since runtimes don't nest.
+#[cfg(any(feature = "https", feature = "quic"))]
+fn block_on<F: std::future::Future + Send>(future: F) -> eyre::Result<F::Output>
+where
+    F::Output: Send,
+{
+    let run = move || -> eyre::Result<F::Output> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        Ok(runtime.block_on(future))
     };
-    let mut acc = Vec::new();
-    acc.extend(header.to_be_bytes());
-    acc.extend(question.to_be_bytes());
-    acc
+    if tokio::runtime::Handle::try_current().is_err() {
+        return run();
+    }
+    std::thread::scope(|scope| {
+        scope
+            .spawn(run)
+            .join()
+            .map_err(|_| eyre::eyre!("exchange panicked"))?
+    })
 }
 
 pub fn send_query<R: rand::Rng>(
@@ -33,31 +575,129 @@ pub fn send_query<R: rand::Rng>(
     ip_address: IpAddr,
     domain_name: &str,
     record_type: u16,
+    options: &QueryOptions,
 ) -> eyre::Result<DNSPacket> {
-    let query = build_query(rng, domain_name, record_type);
-    let socket = socket2::Socket::new(Domain::IPV4, Type::DGRAM, None)?;
-    let socket: UdpSocket = socket.into();
-    println!("socket addr: {:?}", socket.local_addr()?);
-    let addr: SocketAddr = SocketAddr::new(ip_address, 53);
-    socket.send_to(&query, addr)?;
-    let mut buf = [0; 1024];
-    socket.recv_from(&mut buf)?;
-    let mut buf = Cursor::new(Bytes::copy_from_slice(&buf[..]));
-    Ok(DNSPacket::parse_dns_packet(&mut buf))
+    let addr: SocketAddr = SocketAddr::new(ip_address, options.port);
+    let mut options = options.clone();
+    let mut attempts = Attempts::new(addr, &options);
+    while let Some(deadline) = attempts.next_deadline() {
+        let query = build_query_packet(rng, domain_name, record_type, &options);
+        let matching = &mut attempts.matching;
+        let wrong_case = matching.wrong_case;
+        let response = match &options.protocol {
+            Protocol::Udp => exchange_udp(&query, addr, &options, deadline, matching)?,
+            Protocol::Tcp => exchange_tcp(&query, addr, deadline, matching)?,
+            #[cfg(feature = "tls")]
+            Protocol::Tls(tls) => exchange_tls(&query, addr, tls, deadline, matching)?,
+            #[cfg(feature = "https")]
+            Protocol::Https(https) => block_on(exchange_https_async(
+                &query,
+                addr,
+                https,
+                tokio::time::Instant::from_std(deadline),
+                matching,
+            ))??,
+            #[cfg(feature = "quic")]
+            Protocol::Quic(quic) => block_on(exchange_quic_async(
+                &query,
+                addr,
+                quic,
+                tokio::time::Instant::from_std(deadline),
+                matching,
+            ))??,
+        };
+        let Some(response) = response else {
+            // Only give up on 0x20 once an attempt got nothing but responses
+            // in the wrong case, a spoofed one mustn't turn it off
+            if attempts.matching.wrong_case > wrong_case {
+                tracing::warn!(
+                    "{addr} did not preserve the case of {domain_name}, retrying without 0x20"
+                );
+                options = case_fallback(&options);
+                attempts.matching.exact_case = false;
+                attempts.repeat();
+            }
+            continue;
+        };
+        if response.header().has_flag(FLAG_TC) && matches!(options.protocol, Protocol::Udp) {
+            tracing::debug!("Response from {addr} was truncated, retrying over TCP");
+            options.protocol = Protocol::Tcp;
+            attempts.repeat();
+            continue;
+        }
+        return Ok(response);
+    }
+    Err(attempts.error().into())
+}
+
+/// Tries `servers` in order until one of them answers, for failing over
+/// between servers of the same zone. Returns the last error if none did.
+pub fn send_query_any<R: rand::Rng>(
+    rng: &mut R,
+    servers: &[IpAddr],
+    domain_name: &str,
+    record_type: u16,
+    options: &QueryOptions,
+) -> eyre::Result<DNSPacket> {
+    let mut error = eyre::eyre!("no servers to query for {domain_name}");
+    for &server in servers {
+        match send_query(rng, server, domain_name, record_type, options) {
+            Ok(response) => return Ok(response),
+            Err(e) => {
+                tracing::warn!("Query to {server} failed, trying the next server: {e}");
+                error = e;
+            }
+        }
+    }
+    Err(error)
+}
+
+/// Like [`send_query_any`], but tries the servers in the order picked by
+/// `selection` and records how each of them did. Returns the server that
+/// answered along with the response.
+pub fn send_query_best<R: rand::Rng>(
+    rng: &mut R,
+    selection: &ServerSelection,
+    servers: &[IpAddr],
+    domain_name: &str,
+    record_type: u16,
+    options: &QueryOptions,
+) -> eyre::Result<(IpAddr, DNSPacket)> {
+    let mut error = eyre::eyre!("no servers to query for {domain_name}");
+    for server in selection.order(rng, servers) {
+        let start = Instant::now();
+        match send_query(rng, server, domain_name, record_type, options) {
+            Ok(response) => {
+                selection.record_rtt(server, start.elapsed());
+                return Ok((server, response));
+            }
+            Err(e) => {
+                tracing::warn!("Query to {server} failed, trying the next server: {e}");
+                selection.record_failure(server);
+                error = e;
+            }
+        }
+    }
+    Err(error)
 }
 
 #[cfg(test)]
 mod test {
     use rand::rngs::mock::StepRng;
 
-    use crate::TYPE_A;
+    use crate::{transport::Fixtures, TYPE_A};
 
     use super::*;
 
     #[test]
     fn test_build_query() {
         let mut mock_rng = StepRng::new(0x8298, 0);
-        let res = build_query(&mut mock_rng, "www.example.com", TYPE_A);
+        let res = build_query(
+            &mut mock_rng,
+            "www.example.com",
+            TYPE_A,
+            &QueryOptions::default(),
+        );
         assert_eq!(
             res,
             [
@@ -68,11 +708,277 @@ mod test {
         )
     }
 
+    #[test]
+    fn test_build_query_options() {
+        let mut mock_rng = StepRng::new(0x8298, 0);
+        let options = QueryOptions {
+            checking_disabled: true,
+            dnssec_ok: true,
+            ..QueryOptions::iterative()
+        };
+        let res = build_query(&mut mock_rng, "www.example.com", TYPE_A, &options);
+        let query = DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::from(res))).unwrap();
+        assert!(!query.header().has_flag(FLAG_RD));
+        assert!(query.header().has_flag(FLAG_CD));
+        let edns = query.edns().unwrap();
+        assert!(edns.dnssec_ok);
+        assert_eq!(edns.udp_payload_size, 1232);
+    }
+
+    #[test]
+    fn test_randomize_case() {
+        let mut mock_rng = StepRng::new(0, 1 << 31);
+        assert_eq!(
+            randomize_case(&mut mock_rng, "www.example.com"),
+            "wWw.eXaMpLe.cOm"
+        );
+
+        let options = QueryOptions {
+            randomize_case: true,
+            ..QueryOptions::default()
+        };
+        let query = build_query_packet(&mut mock_rng, "www.example.com", TYPE_A, &options);
+        let echoed = MessageBuilder::response_to(&query).build();
+        assert!(case_matches(&query, &echoed));
+        let lowercased = MessageBuilder::new()
+            .question("www.example.com", TYPE_A, CLASS_IN)
+            .build();
+        assert!(!case_matches(&query, &lowercased));
+    }
+
+    #[test]
+    fn test_check_malformed_response() {
+        let mut mock_rng = StepRng::new(0x8298, 0);
+        let options = QueryOptions::default();
+        let query = build_query_packet(&mut mock_rng, "www.example.com", TYPE_A, &options);
+        let server: SocketAddr = "127.0.0.1:53".parse().unwrap();
+        let response = MessageBuilder::response_to(&query).to_be_bytes();
+        assert!(check_response(&query, server, server, &response, false).is_ok());
+        let truncated = &response[..response.len() - 3];
+        assert!(matches!(
+            check_response(&query, server, server, truncated, false),
+            Err(Mismatch::Malformed(_))
+        ));
+        // Claims an answer that isn't there
+        let mut missing = response.clone();
+        missing[7] = 1;
+        assert!(matches!(
+            check_response(&query, server, server, &missing, false),
+            Err(Mismatch::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_send_query_timeout() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let options = QueryOptions {
+            port: server.local_addr().unwrap().port(),
+            timeout: Duration::from_millis(20),
+            retries: 3,
+            deadline: Some(Duration::from_millis(100)),
+            ..QueryOptions::default()
+        };
+        let mut mock_rng = StepRng::new(0x8298, 0);
+        let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+        let err = send_query(
+            &mut mock_rng,
+            localhost,
+            "www.example.com",
+            TYPE_A,
+            &options,
+        )
+        .unwrap_err();
+        // 20ms + 40ms fit in the deadline, the third attempt is cut short
+        let Some(QueryError::Timeout {
+            attempts, elapsed, ..
+        }) = err.downcast_ref::<QueryError>()
+        else {
+            panic!("expected a timeout, got {err}");
+        };
+        assert_eq!(*attempts, 3);
+        assert!(*elapsed < Duration::from_millis(150));
+    }
+
+    #[test]
+    fn test_send_query_ipv6() {
+        let server = UdpSocket::bind("[::1]:0").unwrap();
+        let options = QueryOptions {
+            port: server.local_addr().unwrap().port(),
+            ..QueryOptions::default()
+        };
+        let responder = std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (len, from) = server.recv_from(&mut buf).unwrap();
+            let query =
+                DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::copy_from_slice(&buf[..len])))
+                    .unwrap();
+            let response = MessageBuilder::response_to(&query).to_be_bytes();
+            server.send_to(&response, from).unwrap();
+        });
+        let mut mock_rng = StepRng::new(0x8298, 0);
+        let localhost: IpAddr = "::1".parse().unwrap();
+        let response = send_query(
+            &mut mock_rng,
+            localhost,
+            "www.example.com",
+            TYPE_A,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(response.header().id, 0x8298);
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn test_send_query_any() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let options = QueryOptions {
+            port: server.local_addr().unwrap().port(),
+            timeout: Duration::from_millis(50),
+            retries: 0,
+            ..QueryOptions::default()
+        };
+        let responder = std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (len, from) = server.recv_from(&mut buf).unwrap();
+            let query =
+                DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::copy_from_slice(&buf[..len])))
+                    .unwrap();
+            let response = MessageBuilder::response_to(&query).to_be_bytes();
+            server.send_to(&response, from).unwrap();
+        });
+        // Nothing listens on 127.0.0.2, so that attempt times out
+        let servers = ["127.0.0.2".parse().unwrap(), "127.0.0.1".parse().unwrap()];
+        let mut mock_rng = StepRng::new(0x8298, 0);
+        let response =
+            send_query_any(&mut mock_rng, &servers, "www.example.com", TYPE_A, &options).unwrap();
+        assert_eq!(response.header().id, 0x8298);
+        responder.join().unwrap();
+
+        let err = send_query_any(
+            &mut mock_rng,
+            &servers[..1],
+            "www.example.com",
+            TYPE_A,
+            &options,
+        )
+        .unwrap_err();
+        assert!(err.downcast_ref::<QueryError>().is_some());
+    }
+
+    #[test]
+    fn test_send_query_best() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let options = QueryOptions {
+            port: server.local_addr().unwrap().port(),
+            timeout: Duration::from_millis(50),
+            retries: 0,
+            ..QueryOptions::default()
+        };
+        let responder = std::thread::spawn(move || {
+            for _ in 0..2 {
+                let mut buf = [0; 512];
+                let (len, from) = server.recv_from(&mut buf).unwrap();
+                let query = DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::copy_from_slice(
+                    &buf[..len],
+                )))
+                .unwrap();
+                let response = MessageBuilder::response_to(&query).to_be_bytes();
+                server.send_to(&response, from).unwrap();
+            }
+        });
+        let selection = ServerSelection::new(crate::selection::SelectionConfig {
+            explore: 0.0,
+            ..Default::default()
+        });
+        let dead: IpAddr = "127.0.0.2".parse().unwrap();
+        let alive: IpAddr = "127.0.0.1".parse().unwrap();
+        let mut mock_rng = StepRng::new(0x8298, 0);
+        for _ in 0..2 {
+            let (server, _) = send_query_best(
+                &mut mock_rng,
+                &selection,
+                &[dead, alive],
+                "www.example.com",
+                TYPE_A,
+                &options,
+            )
+            .unwrap();
+            assert_eq!(server, alive);
+        }
+        responder.join().unwrap();
+        // The second query went straight to the server that answered
+        assert!(selection.srtt(alive).unwrap() < options.timeout);
+        assert!(selection.srtt(dead).unwrap() > options.timeout);
+        assert_eq!(
+            selection.order(&mut mock_rng, &[dead, alive]),
+            [alive, dead]
+        );
+    }
+
+    #[test]
+    fn test_send_query_tcp() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let options = QueryOptions {
+            port: listener.local_addr().unwrap().port(),
+            protocol: Protocol::Tcp,
+            ..QueryOptions::default()
+        };
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut len = [0; 2];
+            stream.read_exact(&mut len).unwrap();
+            let mut message = vec![0; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut message).unwrap();
+            let query =
+                DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::from(message))).unwrap();
+            let response = MessageBuilder::response_to(&query).to_be_bytes();
+            stream.write_all(&tcp_frame(&response)).unwrap();
+        });
+        let mut mock_rng = StepRng::new(0x8298, 0);
+        let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+        let response = send_query(
+            &mut mock_rng,
+            localhost,
+            "www.example.com",
+            TYPE_A,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(response.header().id, 0x8298);
+        server.join().unwrap();
+    }
+
     #[test]
     fn test_send_query() {
+        let fixtures = Fixtures::parse(include_str!("../../tests/fixtures/resolve.txt")).unwrap();
+        let root: IpAddr = "198.41.0.4".parse().unwrap();
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let options = QueryOptions {
+            port: server.local_addr().unwrap().port(),
+            ..QueryOptions::iterative()
+        };
+        // Stands in for a.root-servers.net
+        let replay = std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (len, from) = server.recv_from(&mut buf).unwrap();
+            let query =
+                DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::copy_from_slice(&buf[..len])))
+                    .unwrap();
+            let response = fixtures.reply(root, &query).unwrap();
+            server.send_to(&response.to_be_bytes(), from).unwrap();
+        });
         let mut mock_rng = StepRng::new(0x8298, 0);
-        let nameserver: IpAddr = "198.41.0.4".parse().unwrap();
-        let res = send_query(&mut mock_rng, nameserver, "www.example.com", TYPE_A).unwrap();
-        println!("Response: {:?}", res);
+        let res = send_query(
+            &mut mock_rng,
+            "127.0.0.1".parse().unwrap(),
+            "www.example.com",
+            TYPE_A,
+            &options,
+        )
+        .unwrap();
+        replay.join().unwrap();
+        assert_eq!(res.header().id, 0x8298);
+        assert_eq!(res.get_nameserver_ips().len(), 2);
     }
 }
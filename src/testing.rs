@@ -0,0 +1,777 @@
+//! A DNS hierarchy simulated on loopback, to test resolution without the
+//! internet.
+//!
+//! Zones are given in zone file syntax. The NS records at the apex of each
+//! zone say which servers serve it, and A records for those names anywhere in
+//! the hierarchy give their addresses. Those only tell the servers apart:
+//! every server listens on UDP and TCP on a port of its own on 198.51.100.1, and
+//! [`SimulatedTransport`] sends the queries for an address there.
+//!
+//! Only built for the crate's own tests and with the `testing` feature.
+//!
+//! ```
+//! use diaw::{testing::Hierarchy, TYPE_A};
+//!
+//! let simulation = Hierarchy::new()
+//!     .zone(".", "
+//!         . 3600 NS a.root.test.
+//!         a.root.test. 3600 A 198.51.100.1
+//!         test. 3600 NS ns.test.
+//!         ns.test. 3600 A 198.51.100.2")?
+//!     .zone("test", "
+//!         test. 3600 NS ns.test.
+//!         ns.test. 3600 A 198.51.100.2
+//!         www.test. 300 A 192.0.2.1")?
+//!     .start()?;
+//! let lookup = simulation.resolver().lookup("www.test", TYPE_A)?;
+//! assert_eq!(lookup.addresses(), ["192.0.2.1".parse::<std::net::IpAddr>()?]);
+//! # Ok::<(), eyre::Report>(())
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Cursor,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+};
+
+use crate::{
+    builder::MessageBuilder,
+    cache::{is_ancestor, normalize},
+    header::{FLAG_AA, FLAG_TC},
+    hints::RootHints,
+    packet::DNSPacket,
+    record::{DNSRecord, DNSRecordResult},
+    resolver::{Resolver, ResolverConfig},
+    simple::query::{tcp_frame, QueryOptions},
+    transport::{Transport, TransportFuture, UdpTransport},
+    RCODE_FORMERR, RCODE_NXDOMAIN, RCODE_REFUSED, TYPE_A, TYPE_AAAA, TYPE_CNAME, TYPE_NS, TYPE_SOA,
+};
+
+/// Largest UDP response for queries without EDNS.
+const MAX_UDP_SIZE: usize = 512;
+
+/// How a simulated server misbehaves. The default answers straight away.
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfig {
+    /// Answers every query like a server named in a delegation that was
+    /// never set up for the zone.
+    pub lame: Option<Lame>,
+    /// Waits this long before answering. Longer than the resolver's timeout
+    /// looks like a server that doesn't answer at all.
+    pub delay: Duration,
+    /// Answers every query over UDP with an empty truncated response, so
+    /// answers only come over TCP.
+    pub truncate: bool,
+}
+
+/// How a lame server answers, see [`ServerConfig::lame`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lame {
+    /// REFUSED, as most servers answer for zones they don't have.
+    Refused,
+    /// A referral back up to the root, as from a server that only knows the
+    /// root hints.
+    Upward,
+    /// An empty NOERROR response without the AA bit.
+    Empty,
+}
+
+/// A query a simulated server received.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Received {
+    pub server: IpAddr,
+    pub name: String,
+    pub qtype: u16,
+    pub tcp: bool,
+}
+
+#[derive(Debug)]
+struct Zone {
+    origin: String,
+    records: Vec<DNSRecord>,
+}
+
+impl Zone {
+    fn parse(origin: &str, text: &str) -> eyre::Result<Self> {
+        let origin = normalize(origin);
+        let records = text
+            .lines()
+            .filter_map(|line| line.split(';').next())
+            .filter(|line| !line.trim().is_empty())
+            .map(str::parse)
+            .collect::<eyre::Result<Vec<DNSRecord>>>()?;
+        if let Some(record) = records.iter().find(|r| !is_ancestor(&origin, r.name())) {
+            eyre::bail!("{} is outside zone {origin:?}", record.name());
+        }
+        let mut zone = Zone { origin, records };
+        let Some(primary) = zone.nameservers().next().map(str::to_string) else {
+            eyre::bail!("zone {:?} has no NS records at its apex", zone.origin);
+        };
+        if zone.rrset(&zone.origin, TYPE_SOA).next().is_none() {
+            let soa = match zone.origin.as_str() {
+                "" => format!(". 3600 SOA {primary}. hostmaster. 1 7200 3600 1209600 300"),
+                origin => format!(
+                    "{origin}. 3600 SOA {primary}. hostmaster.{origin}. 1 7200 3600 1209600 300"
+                ),
+            };
+            zone.records.push(soa.parse()?);
+        }
+        Ok(zone)
+    }
+
+    fn nameservers(&self) -> impl Iterator<Item = &str> {
+        self.rrset(&self.origin, TYPE_NS)
+            .filter_map(|record| match record.res() {
+                DNSRecordResult::NameServer(ns) => Some(ns.as_str()),
+                _ => None,
+            })
+    }
+
+    fn rrset<'a>(&'a self, name: &'a str, qtype: u16) -> impl Iterator<Item = &'a DNSRecord> {
+        self.records
+            .iter()
+            .filter(move |record| record.qtype() == qtype && same_name(record.name(), name))
+    }
+
+    /// The topmost delegation out of the zone on the way down to `name`.
+    fn cut(&self, name: &str) -> Option<&str> {
+        self.records
+            .iter()
+            .filter(|record| record.qtype() == TYPE_NS)
+            .map(DNSRecord::name)
+            .filter(|owner| !same_name(owner, &self.origin) && is_ancestor(owner, name))
+            .min_by_key(|owner| owner.len())
+    }
+
+    fn answer(&self, name: &str, qtype: u16, response: MessageBuilder) -> DNSPacket {
+        if let Some(cut) = self.cut(name) {
+            let nameservers: Vec<_> = self.rrset(cut, TYPE_NS).collect();
+            let glue = self.records.iter().filter(|record| {
+                matches!(record.qtype(), TYPE_A | TYPE_AAAA)
+                    && nameservers.iter().any(|ns| match ns.res() {
+                        DNSRecordResult::NameServer(ns) => same_name(ns, record.name()),
+                        _ => false,
+                    })
+            });
+            let response = nameservers
+                .iter()
+                .fold(response, |response, ns| response.authority((*ns).clone()));
+            return glue
+                .fold(response, |response, glue| response.additional(glue.clone()))
+                .build();
+        }
+
+        let response = response.flag(FLAG_AA, true);
+        let owned: Vec<_> = self
+            .records
+            .iter()
+            .filter(|record| same_name(record.name(), name))
+            .collect();
+        let answers: Vec<_> = owned
+            .iter()
+            .filter(|record| record.qtype() == qtype)
+            .collect();
+        // The resolver follows the CNAME itself, even within the zone
+        let answers = match owned.iter().find(|record| record.qtype() == TYPE_CNAME) {
+            Some(cname) if answers.is_empty() => vec![cname],
+            _ => answers,
+        };
+        if !answers.is_empty() {
+            return answers
+                .into_iter()
+                .fold(response, |response, record| {
+                    response.answer((*record).clone())
+                })
+                .build();
+        }
+        let soa = self.rrset(&self.origin, TYPE_SOA).next().cloned();
+        let response = soa.into_iter().fold(response, MessageBuilder::authority);
+        if owned.is_empty() {
+            response.rcode(RCODE_NXDOMAIN).build()
+        } else {
+            response.build()
+        }
+    }
+}
+
+fn same_name(a: &str, b: &str) -> bool {
+    normalize(a) == normalize(b)
+}
+
+/// A declarative description of zones and their servers, to [`start`] on
+/// loopback.
+///
+/// [`start`]: Hierarchy::start
+#[derive(Debug, Default)]
+pub struct Hierarchy {
+    zones: Vec<Zone>,
+    servers: HashMap<String, ServerConfig>,
+}
+
+impl Hierarchy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the zone at `origin` ("." for the root) from records in zone file
+    /// syntax with absolute names. Delegations are NS records below the apex,
+    /// with glue where the parent has it. A SOA record is made up if there is
+    /// none.
+    pub fn zone(mut self, origin: &str, text: &str) -> eyre::Result<Self> {
+        self.zones.push(Zone::parse(origin, text)?);
+        Ok(self)
+    }
+
+    /// Sets how the server named `name` behaves, at all of its addresses.
+    pub fn server(mut self, name: &str, config: ServerConfig) -> Self {
+        self.servers.insert(normalize(name), config);
+        self
+    }
+
+    fn addresses(&self, name: &str) -> Vec<IpAddr> {
+        let mut addresses: Vec<_> = self
+            .zones
+            .iter()
+            .flat_map(|zone| zone.rrset(name, TYPE_A).chain(zone.rrset(name, TYPE_AAAA)))
+            .filter_map(|record| match record.res() {
+                DNSRecordResult::Address(ip) => Some(*ip),
+                _ => None,
+            })
+            .collect();
+        addresses.sort();
+        addresses.dedup();
+        addresses
+    }
+
+    /// Binds every server and starts answering on a runtime of its own, until
+    /// the simulation is dropped.
+    pub fn start(self) -> eyre::Result<Simulation> {
+        let Some(root) = self.zones.iter().find(|zone| zone.origin.is_empty()) else {
+            eyre::bail!("no root zone");
+        };
+        let hint_records: Vec<_> = root
+            .rrset("", TYPE_NS)
+            .cloned()
+            .chain(root.nameservers().flat_map(|ns| {
+                self.zones
+                    .iter()
+                    .flat_map(move |zone| zone.rrset(ns, TYPE_A).chain(zone.rrset(ns, TYPE_AAAA)))
+                    .cloned()
+            }))
+            .collect();
+        let hints = RootHints::from_records(&hint_records)?;
+
+        // Origins of the zones served at each address, and how
+        let mut servers: BTreeMap<IpAddr, (Vec<String>, ServerConfig)> = BTreeMap::new();
+        for zone in self.zones.iter() {
+            for ns in zone.nameservers() {
+                let addresses = self.addresses(ns);
+                if addresses.is_empty() {
+                    eyre::bail!("no address for {ns}, nameserver of {:?}", zone.origin);
+                }
+                for address in addresses {
+                    let (zones, config) = servers.entry(address).or_default();
+                    zones.push(zone.origin.clone());
+                    if let Some(server) = self.servers.get(&normalize(ns)) {
+                        *config = server.clone();
+                    }
+                }
+            }
+        }
+
+        let all_zones = Arc::new(self.zones);
+        let received = Arc::new(Mutex::default());
+        let mut ports = HashMap::new();
+        let mut sockets = Vec::new();
+        for &address in servers.keys() {
+            let (port, udp, tcp) = bind()?;
+            ports.insert(address, port);
+            sockets.push((udp, tcp));
+        }
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()?;
+        let _guard = runtime.enter();
+        for ((address, (zones, config)), (udp, tcp)) in servers.into_iter().zip(sockets) {
+            let server = Arc::new(Server {
+                address,
+                zones,
+                all_zones: all_zones.clone(),
+                config,
+                received: received.clone(),
+            });
+            udp.set_nonblocking(true)?;
+            tcp.set_nonblocking(true)?;
+            tokio::spawn(serve_udp(UdpSocket::from_std(udp)?, server.clone()));
+            tokio::spawn(serve_tcp(TcpListener::from_std(tcp)?, server));
+        }
+        Ok(Simulation {
+            transport: SimulatedTransport {
+                ports: Arc::new(ports),
+            },
+            hints,
+            received,
+            runtime: Some(runtime),
+        })
+    }
+}
+
+/// Binds UDP and TCP on the same free port on 198.51.100.1, retrying with
+/// another port if TCP has it taken.
+fn bind() -> eyre::Result<(u16, std::net::UdpSocket, std::net::TcpListener)> {
+    let mut attempts = 0;
+    loop {
+        let udp = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let port = udp.local_addr()?.port();
+        match std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, port)) {
+            Ok(tcp) => return Ok((port, udp, tcp)),
+            Err(e) if attempts < 10 => {
+                tracing::debug!("Could not bind TCP port {}: {}", port, e);
+                attempts += 1;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Server {
+    address: IpAddr,
+    /// Origins of the zones this server is authoritative for.
+    zones: Vec<String>,
+    all_zones: Arc<Vec<Zone>>,
+    config: ServerConfig,
+    received: Arc<Mutex<Vec<Received>>>,
+}
+
+impl Server {
+    async fn respond(&self, data: &[u8], tcp: bool) -> Vec<u8> {
+        let Ok(query) = DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::copy_from_slice(data)))
+        else {
+            return MessageBuilder::new().rcode(RCODE_FORMERR).to_be_bytes();
+        };
+        let response = MessageBuilder::response_to(&query);
+        let Some(question) = query.questions().first() else {
+            return response.rcode(RCODE_FORMERR).to_be_bytes();
+        };
+        self.received.lock().unwrap().push(Received {
+            server: self.address,
+            name: question.name.clone(),
+            qtype: question.qtype,
+            tcp,
+        });
+        tokio::time::sleep(self.config.delay).await;
+        match self.config.lame {
+            Some(Lame::Refused) => return response.rcode(RCODE_REFUSED).to_be_bytes(),
+            Some(Lame::Upward) => {
+                let roots = self
+                    .all_zones
+                    .iter()
+                    .filter(|zone| zone.origin.is_empty())
+                    .flat_map(|zone| zone.rrset("", TYPE_NS))
+                    .cloned();
+                return roots
+                    .fold(response, MessageBuilder::authority)
+                    .to_be_bytes();
+            }
+            Some(Lame::Empty) => return response.to_be_bytes(),
+            None => {}
+        }
+        if self.config.truncate && !tcp {
+            return response.flag(FLAG_TC, true).to_be_bytes();
+        }
+
+        // The closest enclosing zone this server has
+        let zone = self
+            .all_zones
+            .iter()
+            .filter(|zone| self.zones.contains(&zone.origin))
+            .filter(|zone| is_ancestor(&zone.origin, &question.name))
+            .max_by_key(|zone| zone.origin.len());
+        let Some(zone) = zone else {
+            return response.rcode(RCODE_REFUSED).to_be_bytes();
+        };
+        let bytes = zone
+            .answer(&question.name, question.qtype, response)
+            .to_be_bytes();
+        let max_size = query
+            .edns()
+            .map_or(MAX_UDP_SIZE, |edns| edns.udp_payload_size as usize)
+            .max(MAX_UDP_SIZE);
+        if !tcp && bytes.len() > max_size {
+            return MessageBuilder::response_to(&query)
+                .flag(FLAG_TC, true)
+                .to_be_bytes();
+        }
+        bytes
+    }
+}
+
+async fn serve_udp(socket: UdpSocket, server: Arc<Server>) -> eyre::Result<()> {
+    let socket = Arc::new(socket);
+    let mut buf = [0; 4096];
+    loop {
+        let (len, client) = socket.recv_from(&mut buf).await?;
+        let query = buf[..len].to_vec();
+        let (socket, server) = (socket.clone(), server.clone());
+        tokio::spawn(async move {
+            let response = server.respond(&query, false).await;
+            socket.send_to(&response, client).await
+        });
+    }
+}
+
+async fn serve_tcp(listener: TcpListener, server: Arc<Server>) -> eyre::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(serve_stream(stream, server.clone()));
+    }
+}
+
+/// Answers queries on one connection in turn until the client closes it.
+async fn serve_stream(mut stream: TcpStream, server: Arc<Server>) -> eyre::Result<()> {
+    while let Ok(len) = stream.read_u16().await {
+        let mut query = vec![0; len as usize];
+        stream.read_exact(&mut query).await?;
+        let response = server.respond(&query, true).await;
+        stream.write_all(&tcp_frame(&response)).await?;
+    }
+    Ok(())
+}
+
+/// Queries the simulated server for an address over UDP, and TCP when the
+/// response is truncated, at the port it listens on.
+#[derive(Debug, Clone)]
+pub struct SimulatedTransport {
+    ports: Arc<HashMap<IpAddr, u16>>,
+}
+
+impl SimulatedTransport {
+    /// Where the server for `server` listens, UDP and TCP alike.
+    pub fn address(&self, server: IpAddr) -> eyre::Result<SocketAddr> {
+        match self.ports.get(&server) {
+            Some(&port) => Ok(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)),
+            None => eyre::bail!("no simulated server at {server}"),
+        }
+    }
+
+    fn options(&self, server: IpAddr, options: &QueryOptions) -> eyre::Result<QueryOptions> {
+        Ok(QueryOptions {
+            port: self.address(server)?.port(),
+            ..options.clone()
+        })
+    }
+}
+
+impl Transport for SimulatedTransport {
+    fn query<'a>(
+        &'a self,
+        server: IpAddr,
+        name: &'a str,
+        qtype: u16,
+        options: &'a QueryOptions,
+    ) -> TransportFuture<'a> {
+        Box::pin(async move {
+            let options = self.options(server, options)?;
+            UdpTransport
+                .query(Ipv4Addr::LOCALHOST.into(), name, qtype, &options)
+                .await
+        })
+    }
+
+    fn query_blocking(
+        &self,
+        server: IpAddr,
+        name: &str,
+        qtype: u16,
+        options: &QueryOptions,
+    ) -> eyre::Result<DNSPacket> {
+        let options = self.options(server, options)?;
+        UdpTransport.query_blocking(Ipv4Addr::LOCALHOST.into(), name, qtype, &options)
+    }
+}
+
+/// The servers of a [`Hierarchy`], answering until dropped.
+#[derive(Debug)]
+pub struct Simulation {
+    transport: SimulatedTransport,
+    hints: RootHints,
+    received: Arc<Mutex<Vec<Received>>>,
+    runtime: Option<tokio::runtime::Runtime>,
+}
+
+impl Simulation {
+    /// Sends queries to the simulated servers.
+    pub fn transport(&self) -> SimulatedTransport {
+        self.transport.clone()
+    }
+
+    /// The servers of the root zone.
+    pub fn root_hints(&self) -> &RootHints {
+        &self.hints
+    }
+
+    /// Iterative queries with a short timeout and no retries, so that failing
+    /// over to another server is quick.
+    pub fn resolver_config(&self) -> ResolverConfig {
+        ResolverConfig {
+            query: QueryOptions {
+                timeout: Duration::from_millis(200),
+                retries: 0,
+                deadline: Some(Duration::from_secs(2)),
+                ..QueryOptions::iterative()
+            },
+            ..ResolverConfig::default()
+        }
+    }
+
+    /// A resolver over [`Simulation::transport`] starting from
+    /// [`Simulation::root_hints`], with a cache and server selection of its
+    /// own.
+    pub fn resolver(&self) -> Resolver {
+        Resolver::new(self.resolver_config(), self.transport())
+            .with_cache(Arc::default())
+            .with_selection(Arc::default())
+            .with_root_hints(Arc::new(self.hints.clone()))
+    }
+
+    /// Every query received so far, in the order they came in.
+    pub fn received(&self) -> Vec<Received> {
+        self.received.lock().unwrap().clone()
+    }
+}
+
+impl Drop for Simulation {
+    fn drop(&mut self) {
+        // Doesn't wait, so simulations can be dropped in async code too
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        error::ResolveError,
+        lookup::Authority,
+        selection::{SelectionConfig, ServerSelection},
+        TYPE_A,
+    };
+
+    use super::*;
+
+    const ROOT: &str = "
+        . 3600 NS a.root.test.
+        a.root.test. 3600 A 198.51.100.1
+        test. 3600 NS ns1.example.test.
+        test. 3600 NS ns2.example.test.
+        ns1.example.test. 3600 A 198.51.100.2
+        ns2.example.test. 3600 A 198.51.100.3
+        other. 3600 NS ns.other.
+        ns.other. 3600 A 198.51.100.4";
+
+    const TEST: &str = "
+        test. 3600 NS ns1.example.test.
+        test. 3600 NS ns2.example.test.
+        ns1.example.test. 3600 A 198.51.100.2
+        ns2.example.test. 3600 A 198.51.100.3
+        www.test. 300 A 192.0.2.1
+        alias.test. 300 CNAME www.other.
+        sub.test. 3600 NS ns.other.";
+
+    const OTHER: &str = "
+        other. 3600 NS ns.other.
+        ns.other. 3600 A 198.51.100.4
+        www.other. 300 A 192.0.2.2";
+
+    const SUB: &str = "
+        sub.test. 3600 NS ns.other.
+        www.sub.test. 300 A 192.0.2.3";
+
+    fn hierarchy() -> Hierarchy {
+        Hierarchy::new()
+            .zone(".", ROOT)
+            .and_then(|h| h.zone("test", TEST))
+            .and_then(|h| h.zone("other", OTHER))
+            .and_then(|h| h.zone("sub.test", SUB))
+            .unwrap()
+    }
+
+    fn address(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_delegations() {
+        let simulation = hierarchy().start().unwrap();
+        let resolver = simulation.resolver();
+        let lookup = resolver.lookup("www.test", TYPE_A).unwrap();
+        assert_eq!(lookup.addresses(), [address("192.0.2.1")]);
+        assert_eq!(lookup.authority.unwrap().zone, "test");
+
+        // Delegated without glue to a server for another zone
+        let lookup = resolver.lookup("www.sub.test", TYPE_A).unwrap();
+        assert_eq!(lookup.addresses(), [address("192.0.2.3")]);
+        assert_eq!(
+            lookup.authority,
+            Some(Authority {
+                zone: "sub.test".to_string(),
+                server: address("198.51.100.4"),
+            })
+        );
+
+        let error = resolver.lookup("missing.test", TYPE_A).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ResolveError>(),
+            Some(ResolveError::NxDomain { .. })
+        ));
+    }
+
+    #[test]
+    fn test_cname_across_zones() {
+        let simulation = hierarchy().start().unwrap();
+        let lookup = simulation.resolver().lookup("alias.test", TYPE_A).unwrap();
+        assert_eq!(lookup.canonical_name(), "www.other");
+        assert_eq!(lookup.addresses(), [address("192.0.2.2")]);
+    }
+
+    #[test]
+    fn test_failover() {
+        let servers = "
+            test. 3600 NS lame.test.
+            test. 3600 NS slow.test.
+            test. 3600 NS ns.test.
+            lame.test. 3600 A 198.51.100.2
+            slow.test. 3600 A 198.51.100.3
+            ns.test. 3600 A 198.51.100.4";
+        let root = format!(". 3600 NS a.root.test.\na.root.test. 3600 A 198.51.100.1\n{servers}");
+        let hierarchy = || {
+            Hierarchy::new()
+                .zone(".", &root)
+                .and_then(|h| h.zone("test", &format!("{servers}\nwww.test. 300 A 192.0.2.1")))
+                .unwrap()
+                .server(
+                    "lame.test",
+                    ServerConfig {
+                        lame: Some(Lame::Refused),
+                        ..ServerConfig::default()
+                    },
+                )
+        };
+        let slow = ServerConfig {
+            delay: Duration::from_secs(1),
+            ..ServerConfig::default()
+        };
+
+        let simulation = hierarchy()
+            .server("slow.test", slow.clone())
+            .start()
+            .unwrap();
+        let lookup = simulation.resolver().lookup("www.test", TYPE_A).unwrap();
+        assert_eq!(lookup.authority.unwrap().server, address("198.51.100.4"));
+
+        // Nothing left to fail over to
+        let simulation = hierarchy()
+            .server("slow.test", slow.clone())
+            .server("ns.test", slow)
+            .start()
+            .unwrap();
+        assert!(simulation.resolver().lookup("www.test", TYPE_A).is_err());
+    }
+
+    #[test]
+    fn test_lame_answers() {
+        let servers = "
+            test. 3600 NS lame.test.
+            test. 3600 NS ns.test.
+            lame.test. 3600 A 198.51.100.2
+            ns.test. 3600 A 198.51.100.3";
+        let root = format!(". 3600 NS a.root.test.\na.root.test. 3600 A 198.51.100.1\n{servers}");
+        let zone = format!("{servers}\nwww.test. 300 A 192.0.2.1");
+        for lame in [Lame::Refused, Lame::Upward, Lame::Empty] {
+            let simulation = Hierarchy::new()
+                .zone(".", &root)
+                .and_then(|h| h.zone("test", &zone))
+                .unwrap()
+                .server(
+                    "lame.test",
+                    ServerConfig {
+                        lame: Some(lame),
+                        ..ServerConfig::default()
+                    },
+                )
+                .start()
+                .unwrap();
+            // Never explore, so the lame server listed first is asked first
+            let selection = ServerSelection::new(SelectionConfig {
+                explore: 0.0,
+                ..SelectionConfig::default()
+            });
+            let resolver = simulation.resolver().with_selection(Arc::new(selection));
+            let lookup = resolver.lookup("www.test", TYPE_A).unwrap();
+            assert_eq!(lookup.authority.unwrap().server, address("198.51.100.3"));
+            let asked: Vec<_> = simulation
+                .received()
+                .into_iter()
+                .filter(|received| received.name == "www.test")
+                .map(|received| received.server)
+                .collect();
+            assert_eq!(
+                asked,
+                [
+                    address("198.51.100.1"),
+                    address("198.51.100.2"),
+                    address("198.51.100.3")
+                ],
+                "{lame:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_tcp_fallback() {
+        let simulation = hierarchy()
+            .server(
+                "ns.other",
+                ServerConfig {
+                    truncate: true,
+                    ..ServerConfig::default()
+                },
+            )
+            .start()
+            .unwrap();
+        let lookup = simulation.resolver().lookup("www.other", TYPE_A).unwrap();
+        assert_eq!(lookup.addresses(), [address("192.0.2.2")]);
+        let received: Vec<_> = simulation
+            .received()
+            .into_iter()
+            .filter(|received| received.server == address("198.51.100.4"))
+            .map(|received| received.tcp)
+            .collect();
+        assert_eq!(received, [false, true]);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Hierarchy::new()
+            .zone("test", "www.other. 300 A 192.0.2.1")
+            .is_err());
+        assert!(Hierarchy::new()
+            .zone("test", "www.test. 300 A 192.0.2.1")
+            .is_err());
+        let unreachable = Hierarchy::new()
+            .zone(".", ". 3600 NS a.root.test.")
+            .unwrap();
+        assert!(unreachable.start().is_err());
+    }
+}
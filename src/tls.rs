@@ -0,0 +1,385 @@
+//! DNS over TLS, see RFC 7858. Enabled with the `tls` feature.
+
+use std::{net::TcpStream, sync::Arc};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    CertificateError, ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore,
+    ServerConfig, SignatureScheme, StreamOwned,
+};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+/// Well-known port for DNS over TLS.
+pub const DOT_PORT: u16 = 853;
+
+/// ALPN protocol ID for DNS over TLS.
+const ALPN_DOT: &[u8] = b"dot";
+
+/// How to authenticate a DNS over TLS server.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    server_name: ServerName<'static>,
+    config: Arc<ClientConfig>,
+}
+
+impl TlsConfig {
+    /// Authenticates `server_name` against the CA certificates in `ca_pem`
+    /// and/or a set of SPKI pins, see [`spki_pin`]. With only pins the
+    /// certificate chain isn't checked at all, like the out-of-band key-pinned
+    /// profile of RFC 7858.
+    pub fn new(server_name: &str, ca_pem: Option<&[u8]>, spki_pins: &[&str]) -> eyre::Result<Self> {
+        let provider = Arc::new(ring::default_provider());
+        let inner = match ca_pem {
+            Some(pem) => {
+                let mut roots = RootCertStore::empty();
+                for cert in CertificateDer::pem_slice_iter(pem) {
+                    roots.add(cert?)?;
+                }
+                let verifier =
+                    WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                        .build()?;
+                Some(verifier)
+            }
+            None if spki_pins.is_empty() => {
+                eyre::bail!("either a CA certificate or an SPKI pin is needed")
+            }
+            None => None,
+        };
+        let pins = spki_pins
+            .iter()
+            .map(|pin| {
+                let hash = STANDARD.decode(pin)?;
+                <[u8; 32]>::try_from(hash)
+                    .map_err(|_| eyre::eyre!("SPKI pin {pin} is not a SHA-256 hash"))
+            })
+            .collect::<eyre::Result<_>>()?;
+        let verifier = PinningVerifier {
+            inner,
+            pins,
+            algorithms: provider.signature_verification_algorithms,
+        };
+        let mut config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        config.alpn_protocols = vec![ALPN_DOT.to_vec()];
+        Ok(TlsConfig {
+            server_name: ServerName::try_from(server_name.to_string())?,
+            config: Arc::new(config),
+        })
+    }
+
+    /// The same configuration offering `protocol` through ALPN instead.
+    #[cfg(any(feature = "https", feature = "quic"))]
+    pub(crate) fn with_alpn(&self, protocol: &[u8]) -> Self {
+        let mut config = (*self.config).clone();
+        config.alpn_protocols = vec![protocol.to_vec()];
+        TlsConfig {
+            server_name: self.server_name.clone(),
+            config: Arc::new(config),
+        }
+    }
+
+    #[cfg(any(feature = "https", feature = "quic"))]
+    pub(crate) fn server_name(&self) -> std::borrow::Cow<'_, str> {
+        self.server_name.to_str()
+    }
+
+    #[cfg(feature = "quic")]
+    pub(crate) fn client_config(&self) -> Arc<ClientConfig> {
+        self.config.clone()
+    }
+
+    /// Wraps a connected stream, the handshake happens on first use.
+    pub(crate) fn connect(
+        &self,
+        stream: TcpStream,
+    ) -> eyre::Result<StreamOwned<ClientConnection, TcpStream>> {
+        let connection = ClientConnection::new(self.config.clone(), self.server_name.clone())?;
+        Ok(StreamOwned::new(connection, stream))
+    }
+
+    pub(crate) async fn connect_async(
+        &self,
+        stream: tokio::net::TcpStream,
+    ) -> std::io::Result<TlsStream<tokio::net::TcpStream>> {
+        TlsConnector::from(self.config.clone())
+            .connect(self.server_name.clone(), stream)
+            .await
+    }
+}
+
+/// Returns the base64 encoded SHA-256 hash of the certificate's
+/// SubjectPublicKeyInfo, the pin format of RFC 7469.
+pub fn spki_pin(cert: &CertificateDer<'_>) -> eyre::Result<String> {
+    Ok(STANDARD.encode(spki_hash(cert)?))
+}
+
+fn spki_hash(cert: &CertificateDer<'_>) -> Result<[u8; 32], webpki::Error> {
+    let cert = webpki::EndEntityCert::try_from(cert)?;
+    let digest = ::ring::digest::digest(&::ring::digest::SHA256, &cert.subject_public_key_info());
+    Ok(digest.as_ref().try_into().expect("SHA-256 is 32 bytes"))
+}
+
+/// Checks the chain with `inner` when a CA was configured, then requires the
+/// end-entity key to match one of `pins`.
+#[derive(Debug)]
+struct PinningVerifier {
+    inner: Option<Arc<WebPkiServerVerifier>>,
+    pins: Vec<[u8; 32]>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(inner) = &self.inner {
+            inner.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        }
+        if !self.pins.is_empty() {
+            let hash = spki_hash(end_entity).map_err(|_| CertificateError::BadEncoding)?;
+            if !self.pins.contains(&hash) {
+                tracing::warn!("Certificate for {server_name:?} does not match any SPKI pin");
+                return Err(CertificateError::ApplicationVerificationFailure.into());
+            }
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// Builds the server side configuration from a PEM certificate chain and
+/// private key.
+pub fn server_config(cert_pem: &[u8], key_pem: &[u8]) -> eyre::Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_slice_iter(cert_pem).collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_slice(key_pem)?;
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![ALPN_DOT.to_vec()];
+    Ok(Arc::new(config))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::Cursor,
+        net::{IpAddr, SocketAddr},
+    };
+
+    use bytes::Bytes;
+    use rand::rngs::mock::StepRng;
+    use rcgen::{generate_simple_self_signed, CertifiedKey};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use tokio_rustls::TlsAcceptor;
+
+    use crate::{
+        builder::MessageBuilder,
+        packet::DNSPacket,
+        r#async::{
+            listener::{tls_listener, ListenerConfig},
+            query::send_query_async,
+        },
+        simple::query::{send_query, tcp_frame, QueryOptions},
+        RCODE_FORMERR, TYPE_A,
+    };
+
+    use super::*;
+
+    fn self_signed() -> CertifiedKey {
+        generate_simple_self_signed(vec!["dns.test".to_string()]).unwrap()
+    }
+
+    /// Answers every query on every connection with an empty response.
+    async fn echo_server(cert: &CertifiedKey) -> SocketAddr {
+        let config = server_config(
+            cert.cert.pem().as_bytes(),
+            cert.key_pair.serialize_pem().as_bytes(),
+        )
+        .unwrap();
+        let acceptor = TlsAcceptor::from(config);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let mut stream = acceptor.accept(stream).await?;
+                    let len = stream.read_u16().await?;
+                    let mut message = vec![0; len as usize];
+                    stream.read_exact(&mut message).await?;
+                    let query = DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::from(message)))
+                        .unwrap();
+                    let response = MessageBuilder::response_to(&query).to_be_bytes();
+                    stream.write_all(&tcp_frame(&response)).await?;
+                    std::io::Result::Ok(())
+                });
+            }
+        });
+        address
+    }
+
+    fn options(address: SocketAddr, tls: TlsConfig) -> QueryOptions {
+        QueryOptions {
+            port: address.port(),
+            retries: 0,
+            ..QueryOptions::tls(tls)
+        }
+    }
+
+    async fn query(options: QueryOptions) -> eyre::Result<DNSPacket> {
+        let mut mock_rng = StepRng::new(0x8298, 0);
+        let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+        send_query_async(
+            &mut mock_rng,
+            localhost,
+            "www.example.com",
+            TYPE_A,
+            &options,
+        )
+        .await
+    }
+
+    #[test]
+    fn test_spki_pin() {
+        let cert = self_signed();
+        let digest =
+            ::ring::digest::digest(&::ring::digest::SHA256, &cert.key_pair.public_key_der());
+        assert_eq!(
+            spki_pin(cert.cert.der()).unwrap(),
+            STANDARD.encode(digest.as_ref())
+        );
+        assert!(TlsConfig::new("dns.test", None, &[]).is_err());
+        assert!(TlsConfig::new("dns.test", None, &["c2hvcnQ="]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_query_with_ca() {
+        let cert = self_signed();
+        let address = echo_server(&cert).await;
+        let tls = TlsConfig::new("dns.test", Some(cert.cert.pem().as_bytes()), &[]).unwrap();
+        let response = query(options(address, tls.clone())).await.unwrap();
+        assert_eq!(response.header().id, 0x8298);
+
+        // The certificate isn't valid for any other name
+        let tls = TlsConfig::new("other.test", Some(cert.cert.pem().as_bytes()), &[]).unwrap();
+        assert!(query(options(address, tls)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_query_with_unknown_ca() {
+        let address = echo_server(&self_signed()).await;
+        let other = self_signed();
+        let tls = TlsConfig::new("dns.test", Some(other.cert.pem().as_bytes()), &[]).unwrap();
+        let err = query(options(address, tls)).await.unwrap_err();
+        assert!(
+            err.to_string().contains("invalid peer certificate"),
+            "{err}"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_query_with_pin() {
+        let cert = self_signed();
+        let address = echo_server(&cert).await;
+        let pin = spki_pin(cert.cert.der()).unwrap();
+        let tls = TlsConfig::new("dns.test", None, &[&pin]).unwrap();
+        let options = options(address, tls);
+        // Exercise the blocking client as well
+        let response = tokio::task::spawn_blocking(move || {
+            let mut mock_rng = StepRng::new(0x8298, 0);
+            let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+            send_query(
+                &mut mock_rng,
+                localhost,
+                "www.example.com",
+                TYPE_A,
+                &options,
+            )
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(response.header().id, 0x8298);
+    }
+
+    #[tokio::test]
+    async fn test_query_with_pin_mismatch() {
+        let cert = self_signed();
+        let address = echo_server(&cert).await;
+        let other_pin = spki_pin(self_signed().cert.der()).unwrap();
+        // A valid chain doesn't help if the key isn't pinned
+        let tls =
+            TlsConfig::new("dns.test", Some(cert.cert.pem().as_bytes()), &[&other_pin]).unwrap();
+        let err = query(options(address, tls)).await.unwrap_err();
+        assert!(
+            err.to_string().contains("invalid peer certificate"),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tls_listener() {
+        let cert = self_signed();
+        let config = server_config(
+            cert.cert.pem().as_bytes(),
+            cert.key_pair.serialize_pem().as_bytes(),
+        )
+        .unwrap();
+        let (address, server) = tls_listener(ListenerConfig::default(), config).unwrap();
+        tokio::spawn(server);
+
+        let tls = TlsConfig::new("dns.test", Some(cert.cert.pem().as_bytes()), &[]).unwrap();
+        let stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        let mut stream = tls.connect_async(stream).await.unwrap();
+        assert_eq!(stream.get_ref().1.alpn_protocol(), Some(ALPN_DOT));
+        // Queries without a question are answered with FORMERR, no resolving needed
+        let query = MessageBuilder::new().id(9).to_be_bytes();
+        stream.write_all(&tcp_frame(&query)).await.unwrap();
+        let len = stream.read_u16().await.unwrap();
+        let mut message = vec![0; len as usize];
+        stream.read_exact(&mut message).await.unwrap();
+        let response = DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::from(message))).unwrap();
+        assert_eq!(response.header().id, 9);
+        assert_eq!(response.header().rcode(), RCODE_FORMERR);
+    }
+}
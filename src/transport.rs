@@ -0,0 +1,696 @@
+//! How a [`crate::resolver::Resolver`] gets its queries to nameservers and the
+//! responses back.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    future::Future,
+    io::Cursor,
+    net::IpAddr,
+    path::Path,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use rand::SeedableRng;
+
+#[cfg(feature = "https")]
+use crate::https::HttpsConfig;
+#[cfg(feature = "quic")]
+use crate::quic::QuicConfig;
+#[cfg(feature = "tls")]
+use crate::tls::TlsConfig;
+use crate::{
+    encoding::{from_hex, to_hex},
+    packet::DNSPacket,
+    r#async::query::send_query_async,
+    simple::query::{send_query, Protocol, QueryOptions},
+    types::{type_from_str, type_to_string},
+};
+
+pub type TransportFuture<'a> = Pin<Box<dyn Future<Output = eyre::Result<DNSPacket>> + Send + 'a>>;
+
+/// Sends one query and returns the response to it, with whatever retries the
+/// transport needs. The resolver takes care of failing over between servers.
+pub trait Transport: fmt::Debug + Send + Sync {
+    fn query<'a>(
+        &'a self,
+        server: IpAddr,
+        name: &'a str,
+        qtype: u16,
+        options: &'a QueryOptions,
+    ) -> TransportFuture<'a>;
+
+    /// Like [`Transport::query`], blocking the thread instead, for
+    /// [`crate::resolver::Resolver::lookup`].
+    fn query_blocking(
+        &self,
+        server: IpAddr,
+        name: &str,
+        qtype: u16,
+        options: &QueryOptions,
+    ) -> eyre::Result<DNSPacket>;
+}
+
+/// Queries over UDP, retrying over TCP when the response is truncated. Ignores
+/// the protocol in the options.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UdpTransport;
+
+impl Transport for UdpTransport {
+    fn query<'a>(
+        &'a self,
+        server: IpAddr,
+        name: &'a str,
+        qtype: u16,
+        options: &'a QueryOptions,
+    ) -> TransportFuture<'a> {
+        Box::pin(query_with(Protocol::Udp, server, name, qtype, options))
+    }
+
+    fn query_blocking(
+        &self,
+        server: IpAddr,
+        name: &str,
+        qtype: u16,
+        options: &QueryOptions,
+    ) -> eyre::Result<DNSPacket> {
+        query_with_blocking(Protocol::Udp, server, name, qtype, options)
+    }
+}
+
+/// Queries over a new TCP connection every time. Ignores the protocol in the
+/// options.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    fn query<'a>(
+        &'a self,
+        server: IpAddr,
+        name: &'a str,
+        qtype: u16,
+        options: &'a QueryOptions,
+    ) -> TransportFuture<'a> {
+        Box::pin(query_with(Protocol::Tcp, server, name, qtype, options))
+    }
+
+    fn query_blocking(
+        &self,
+        server: IpAddr,
+        name: &str,
+        qtype: u16,
+        options: &QueryOptions,
+    ) -> eyre::Result<DNSPacket> {
+        query_with_blocking(Protocol::Tcp, server, name, qtype, options)
+    }
+}
+
+/// Queries over DNS over TLS, on the port in the options. Every server has
+/// to present a certificate for the name in the TLS config, so this is mostly
+/// of use with forwarders, see [`crate::resolver::ResolverConfig::forwarding`].
+#[cfg(feature = "tls")]
+#[derive(Debug, Clone)]
+pub struct TlsTransport {
+    tls: TlsConfig,
+}
+
+#[cfg(feature = "tls")]
+impl TlsTransport {
+    pub fn new(tls: TlsConfig) -> Self {
+        TlsTransport { tls }
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Transport for TlsTransport {
+    fn query<'a>(
+        &'a self,
+        server: IpAddr,
+        name: &'a str,
+        qtype: u16,
+        options: &'a QueryOptions,
+    ) -> TransportFuture<'a> {
+        let protocol = Protocol::Tls(self.tls.clone());
+        Box::pin(query_with(protocol, server, name, qtype, options))
+    }
+
+    fn query_blocking(
+        &self,
+        server: IpAddr,
+        name: &str,
+        qtype: u16,
+        options: &QueryOptions,
+    ) -> eyre::Result<DNSPacket> {
+        let protocol = Protocol::Tls(self.tls.clone());
+        query_with_blocking(protocol, server, name, qtype, options)
+    }
+}
+
+/// Queries over DNS over HTTPS, on the port in the options. Like
+/// [`TlsTransport`], mostly of use with forwarders.
+#[cfg(feature = "https")]
+#[derive(Debug, Clone)]
+pub struct HttpsTransport {
+    https: HttpsConfig,
+}
+
+#[cfg(feature = "https")]
+impl HttpsTransport {
+    pub fn new(https: HttpsConfig) -> Self {
+        HttpsTransport { https }
+    }
+}
+
+#[cfg(feature = "https")]
+impl Transport for HttpsTransport {
+    fn query<'a>(
+        &'a self,
+        server: IpAddr,
+        name: &'a str,
+        qtype: u16,
+        options: &'a QueryOptions,
+    ) -> TransportFuture<'a> {
+        let protocol = Protocol::Https(self.https.clone());
+        Box::pin(query_with(protocol, server, name, qtype, options))
+    }
+
+    fn query_blocking(
+        &self,
+        server: IpAddr,
+        name: &str,
+        qtype: u16,
+        options: &QueryOptions,
+    ) -> eyre::Result<DNSPacket> {
+        let protocol = Protocol::Https(self.https.clone());
+        query_with_blocking(protocol, server, name, qtype, options)
+    }
+}
+
+/// Queries over DNS over QUIC, on the port in the options. Like
+/// [`TlsTransport`], mostly of use with forwarders.
+#[cfg(feature = "quic")]
+#[derive(Debug, Clone)]
+pub struct QuicTransport {
+    quic: QuicConfig,
+}
+
+#[cfg(feature = "quic")]
+impl QuicTransport {
+    pub fn new(quic: QuicConfig) -> Self {
+        QuicTransport { quic }
+    }
+}
+
+#[cfg(feature = "quic")]
+impl Transport for QuicTransport {
+    fn query<'a>(
+        &'a self,
+        server: IpAddr,
+        name: &'a str,
+        qtype: u16,
+        options: &'a QueryOptions,
+    ) -> TransportFuture<'a> {
+        let protocol = Protocol::Quic(self.quic.clone());
+        Box::pin(query_with(protocol, server, name, qtype, options))
+    }
+
+    fn query_blocking(
+        &self,
+        server: IpAddr,
+        name: &str,
+        qtype: u16,
+        options: &QueryOptions,
+    ) -> eyre::Result<DNSPacket> {
+        let protocol = Protocol::Quic(self.quic.clone());
+        query_with_blocking(protocol, server, name, qtype, options)
+    }
+}
+
+async fn query_with(
+    protocol: Protocol,
+    server: IpAddr,
+    name: &str,
+    qtype: u16,
+    options: &QueryOptions,
+) -> eyre::Result<DNSPacket> {
+    let options = QueryOptions {
+        protocol,
+        ..options.clone()
+    };
+    let rng = &mut rand::rngs::SmallRng::from_entropy();
+    send_query_async(rng, server, name, qtype, &options).await
+}
+
+fn query_with_blocking(
+    protocol: Protocol,
+    server: IpAddr,
+    name: &str,
+    qtype: u16,
+    options: &QueryOptions,
+) -> eyre::Result<DNSPacket> {
+    let options = QueryOptions {
+        protocol,
+        ..options.clone()
+    };
+    let rng = &mut rand::rngs::SmallRng::from_entropy();
+    send_query(rng, server, name, qtype, &options)
+}
+
+impl<T: Transport + ?Sized> Transport for Arc<T> {
+    fn query<'a>(
+        &'a self,
+        server: IpAddr,
+        name: &'a str,
+        qtype: u16,
+        options: &'a QueryOptions,
+    ) -> TransportFuture<'a> {
+        (**self).query(server, name, qtype, options)
+    }
+
+    fn query_blocking(
+        &self,
+        server: IpAddr,
+        name: &str,
+        qtype: u16,
+        options: &QueryOptions,
+    ) -> eyre::Result<DNSPacket> {
+        (**self).query_blocking(server, name, qtype, options)
+    }
+}
+
+type Handler = dyn Fn(IpAddr, &str, u16) -> eyre::Result<DNSPacket> + Send + Sync;
+
+/// Answers queries with a function instead of the network, for tests.
+pub struct MockTransport {
+    handler: Box<Handler>,
+}
+
+impl MockTransport {
+    /// Answers with `handler`, called with the server, name and type of each
+    /// query.
+    pub fn new<F>(handler: F) -> Self
+    where
+        F: Fn(IpAddr, &str, u16) -> eyre::Result<DNSPacket> + Send + Sync + 'static,
+    {
+        MockTransport {
+            handler: Box::new(handler),
+        }
+    }
+}
+
+impl fmt::Debug for MockTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockTransport").finish_non_exhaustive()
+    }
+}
+
+impl Transport for MockTransport {
+    fn query<'a>(
+        &'a self,
+        server: IpAddr,
+        name: &'a str,
+        qtype: u16,
+        _options: &'a QueryOptions,
+    ) -> TransportFuture<'a> {
+        let response = (self.handler)(server, name, qtype);
+        Box::pin(async move { response })
+    }
+
+    fn query_blocking(
+        &self,
+        server: IpAddr,
+        name: &str,
+        qtype: u16,
+        _options: &QueryOptions,
+    ) -> eyre::Result<DNSPacket> {
+        (self.handler)(server, name, qtype)
+    }
+}
+
+/// A server and the question asked of it.
+type FixtureKey = (IpAddr, String, u16);
+
+/// Responses recorded from nameservers, keyed by the server and the question
+/// asked. Names are compared case-insensitively.
+///
+/// Fixture files have one response per line: the server, the question name
+/// and type, and the response message in hex. Anything after a `;` is a
+/// comment.
+///
+/// ```text
+/// 198.41.0.4 www.example.com A 8298800000010000000d000b...
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Fixtures {
+    responses: BTreeMap<FixtureKey, DNSPacket>,
+}
+
+impl Fixtures {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn parse(text: &str) -> eyre::Result<Self> {
+        let mut fixtures = Fixtures::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or_default();
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let [server, name, qtype, message] = fields[..] else {
+                if fields.is_empty() {
+                    continue;
+                }
+                eyre::bail!(
+                    "line {}: expected 4 fields, found {}",
+                    number + 1,
+                    fields.len()
+                );
+            };
+            let qtype = type_from_str(qtype)
+                .ok_or_else(|| eyre::eyre!("line {}: unknown type {qtype}", number + 1))?;
+            let message = from_hex(message).map_err(|e| eyre::eyre!("line {}: {e}", number + 1))?;
+            let response = DNSPacket::parse_dns_packet(&mut Cursor::new(Bytes::from(message)))
+                .map_err(|e| eyre::eyre!("line {}: {e}", number + 1))?;
+            let server = server
+                .parse()
+                .map_err(|e| eyre::eyre!("line {}: {e}", number + 1))?;
+            fixtures.insert(server, name, qtype, response);
+        }
+        Ok(fixtures)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> eyre::Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| eyre::eyre!("failed to read {}: {e}", path.display()))?;
+        Self::parse(&text)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> eyre::Result<()> {
+        std::fs::write(path, self.to_string())?;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.responses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.responses.is_empty()
+    }
+
+    /// Adds a response, replacing any earlier one to the same question.
+    pub fn insert(&mut self, server: IpAddr, name: &str, qtype: u16, response: DNSPacket) {
+        self.responses.insert(key(server, name, qtype), response);
+    }
+
+    pub fn get(&self, server: IpAddr, name: &str, qtype: u16) -> Option<&DNSPacket> {
+        self.responses.get(&key(server, name, qtype))
+    }
+
+    /// The recorded response to `query` if it had been sent to `server`,
+    /// with the ID and question of the query, for replaying fixtures over
+    /// the network.
+    pub fn reply(&self, server: IpAddr, query: &DNSPacket) -> Option<DNSPacket> {
+        let question = query.questions().first()?;
+        let response = self.get(server, &question.name, question.qtype)?;
+        let mut header = response.header().clone();
+        header.id = query.header().id;
+        Some(DNSPacket::from_parts(
+            header,
+            query.questions().to_vec(),
+            response.answers().to_vec(),
+            response.authorities().to_vec(),
+            response.additionals().to_vec(),
+        ))
+    }
+}
+
+impl fmt::Display for Fixtures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for ((server, name, qtype), response) in &self.responses {
+            let name = if name.is_empty() { "." } else { name };
+            writeln!(
+                f,
+                "{server} {name} {} {}",
+                type_to_string(*qtype),
+                to_hex(&response.to_be_bytes())
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn key(server: IpAddr, name: &str, qtype: u16) -> FixtureKey {
+    let name = name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase();
+    (server, name, qtype)
+}
+
+/// Answers from [`Fixtures`] only, failing queries nothing was recorded for
+/// like an unreachable server would.
+#[derive(Debug, Clone, Default)]
+pub struct FixtureTransport {
+    fixtures: Arc<Fixtures>,
+}
+
+impl FixtureTransport {
+    pub fn new(fixtures: Fixtures) -> Self {
+        FixtureTransport {
+            fixtures: Arc::new(fixtures),
+        }
+    }
+
+    pub fn fixtures(&self) -> &Fixtures {
+        &self.fixtures
+    }
+
+    fn response(&self, server: IpAddr, name: &str, qtype: u16) -> eyre::Result<DNSPacket> {
+        self.fixtures
+            .get(server, name, qtype)
+            .cloned()
+            .ok_or_else(|| {
+                eyre::eyre!(
+                    "no fixture for {name} {} at {server}",
+                    type_to_string(qtype)
+                )
+            })
+    }
+}
+
+impl Transport for FixtureTransport {
+    fn query<'a>(
+        &'a self,
+        server: IpAddr,
+        name: &'a str,
+        qtype: u16,
+        _options: &'a QueryOptions,
+    ) -> TransportFuture<'a> {
+        let response = self.response(server, name, qtype);
+        Box::pin(async move { response })
+    }
+
+    fn query_blocking(
+        &self,
+        server: IpAddr,
+        name: &str,
+        qtype: u16,
+        _options: &QueryOptions,
+    ) -> eyre::Result<DNSPacket> {
+        self.response(server, name, qtype)
+    }
+}
+
+/// Passes queries on to another transport and records the responses, to save
+/// as fixtures for [`FixtureTransport`].
+#[derive(Debug, Default)]
+pub struct Recorder<T> {
+    inner: T,
+    fixtures: Mutex<Fixtures>,
+}
+
+impl<T: Transport> Recorder<T> {
+    pub fn new(inner: T) -> Self {
+        Recorder {
+            inner,
+            fixtures: Mutex::default(),
+        }
+    }
+
+    /// What has been recorded so far.
+    pub fn fixtures(&self) -> Fixtures {
+        self.fixtures.lock().unwrap().clone()
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> eyre::Result<()> {
+        self.fixtures.lock().unwrap().save(path)
+    }
+
+    fn record(&self, server: IpAddr, name: &str, qtype: u16, response: &DNSPacket) {
+        self.fixtures
+            .lock()
+            .unwrap()
+            .insert(server, name, qtype, response.clone());
+    }
+}
+
+impl<T: Transport> Transport for Recorder<T> {
+    fn query<'a>(
+        &'a self,
+        server: IpAddr,
+        name: &'a str,
+        qtype: u16,
+        options: &'a QueryOptions,
+    ) -> TransportFuture<'a> {
+        Box::pin(async move {
+            let response = self.inner.query(server, name, qtype, options).await?;
+            self.record(server, name, qtype, &response);
+            Ok(response)
+        })
+    }
+
+    fn query_blocking(
+        &self,
+        server: IpAddr,
+        name: &str,
+        qtype: u16,
+        options: &QueryOptions,
+    ) -> eyre::Result<DNSPacket> {
+        let response = self.inner.query_blocking(server, name, qtype, options)?;
+        self.record(server, name, qtype, &response);
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "tls")]
+    use std::net::SocketAddr;
+
+    #[cfg(feature = "tls")]
+    use rcgen::{generate_simple_self_signed, CertifiedKey};
+
+    #[cfg(feature = "quic")]
+    use crate::r#async::listener::quic_listener;
+    use crate::TYPE_A;
+    #[cfg(feature = "tls")]
+    use crate::{
+        cache::record_cache,
+        r#async::listener::{tls_listener, ListenerConfig},
+        resolver::{Resolver, ResolverConfig},
+    };
+    #[cfg(feature = "https")]
+    use crate::{
+        https::{Method, DOH_PATH},
+        r#async::listener::https_listener,
+    };
+
+    use super::*;
+
+    #[cfg(feature = "tls")]
+    fn self_signed() -> (CertifiedKey, TlsConfig) {
+        let cert = generate_simple_self_signed(vec!["dns.test".to_string()]).unwrap();
+        let tls = TlsConfig::new("dns.test", Some(cert.cert.pem().as_bytes()), &[]).unwrap();
+        (cert, tls)
+    }
+
+    /// Looks up `name` by forwarding over `transport` to the listener at
+    /// `address`, which answers from the shared cache.
+    #[cfg(feature = "tls")]
+    async fn forward<T: Transport + 'static>(transport: T, address: SocketAddr, name: &str) {
+        let record = format!("{name}. 300 A 192.0.2.1").parse().unwrap();
+        record_cache().insert(vec![record]);
+        let mut config = ResolverConfig::forwarding(&[address.ip()]);
+        config.query.port = address.port();
+        let lookup = Resolver::new(config, transport)
+            .with_cache(Arc::default())
+            .with_selection(Arc::default())
+            .lookup_async(name, TYPE_A)
+            .await
+            .unwrap();
+        assert_eq!(lookup.addresses(), ["192.0.2.1".parse::<IpAddr>().unwrap()]);
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn test_tls_transport() {
+        let (cert, tls) = self_signed();
+        let config = crate::tls::server_config(
+            cert.cert.pem().as_bytes(),
+            cert.key_pair.serialize_pem().as_bytes(),
+        )
+        .unwrap();
+        let (address, server) = tls_listener(ListenerConfig::default(), config).unwrap();
+        tokio::spawn(server);
+        forward(TlsTransport::new(tls), address, "tls.transport.test").await;
+    }
+
+    #[cfg(feature = "https")]
+    #[tokio::test]
+    async fn test_https_transport() {
+        let (cert, tls) = self_signed();
+        let config = crate::https::server_config(
+            cert.cert.pem().as_bytes(),
+            cert.key_pair.serialize_pem().as_bytes(),
+        )
+        .unwrap();
+        let (address, server) = https_listener(ListenerConfig::default(), config).unwrap();
+        tokio::spawn(server);
+        let https = HttpsConfig::new(tls, DOH_PATH, Method::Post);
+        forward(HttpsTransport::new(https), address, "https.transport.test").await;
+    }
+
+    #[cfg(feature = "quic")]
+    #[tokio::test]
+    async fn test_quic_transport() {
+        let (cert, tls) = self_signed();
+        let config = crate::quic::server_config(
+            cert.cert.pem().as_bytes(),
+            cert.key_pair.serialize_pem().as_bytes(),
+        )
+        .unwrap();
+        let (address, server) = quic_listener(ListenerConfig::default(), config).unwrap();
+        tokio::spawn(server);
+        let quic = QuicConfig::new(tls).unwrap();
+        forward(QuicTransport::new(quic), address, "quic.transport.test").await;
+    }
+
+    #[test]
+    fn test_fixtures_round_trip() {
+        let fixtures = Fixtures::parse(include_str!("../tests/fixtures/resolve.txt")).unwrap();
+        assert_eq!(fixtures.len(), 15);
+        let reparsed = Fixtures::parse(&fixtures.to_string()).unwrap();
+        assert_eq!(reparsed.to_string(), fixtures.to_string());
+        let server = "198.41.0.4".parse().unwrap();
+        assert!(fixtures.get(server, "WWW.Google.com.", TYPE_A).is_some());
+        assert!(Fixtures::parse("198.41.0.4 www.google.com A 0").is_err());
+        let error = Fixtures::parse("\n198.41.0.4 www.google.com A 1234").unwrap_err();
+        assert!(error.to_string().starts_with("line 2: "), "{error}");
+    }
+
+    #[tokio::test]
+    async fn test_recorder() {
+        let fixtures = Fixtures::parse(include_str!("../tests/fixtures/resolve.txt")).unwrap();
+        let recorder = Recorder::new(FixtureTransport::new(fixtures));
+        let options = QueryOptions::iterative();
+        let server = "198.41.0.4".parse().unwrap();
+        recorder
+            .query(server, "www.google.com", TYPE_A, &options)
+            .await
+            .unwrap();
+        assert!(recorder
+            .query(server, "www.nowhere.test", TYPE_A, &options)
+            .await
+            .is_err());
+        let recorded = recorder.fixtures();
+        assert_eq!(recorded.len(), 1);
+
+        // What was recorded replays the same
+        let replay = FixtureTransport::new(recorded);
+        let response = replay
+            .query(server, "www.google.com", TYPE_A, &options)
+            .await
+            .unwrap();
+        assert!(!response.get_nameserver_ips().is_empty());
+    }
+}
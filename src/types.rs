@@ -0,0 +1,135 @@
+use crate::{
+    CLASS_IN, TYPE_A, TYPE_AAAA, TYPE_CNAME, TYPE_MX, TYPE_NS, TYPE_OPT, TYPE_PTR, TYPE_SOA,
+    TYPE_TXT,
+};
+
+/// Mnemonic for a record type, falling back to the `TYPE<n>` form for types we
+/// don't know about.
+pub fn type_to_string(qtype: u16) -> String {
+    let name = match qtype {
+        TYPE_A => "A",
+        TYPE_NS => "NS",
+        TYPE_CNAME => "CNAME",
+        TYPE_SOA => "SOA",
+        TYPE_PTR => "PTR",
+        TYPE_MX => "MX",
+        TYPE_TXT => "TXT",
+        TYPE_AAAA => "AAAA",
+        TYPE_OPT => "OPT",
+        _ => return format!("TYPE{qtype}"),
+    };
+    name.to_string()
+}
+
+/// Inverse of [`type_to_string`], also accepting the RFC 3597 `TYPE<n>` form
+/// for any type.
+pub fn type_from_str(s: &str) -> Option<u16> {
+    let s = s.to_ascii_uppercase();
+    if let Some(n) = s.strip_prefix("TYPE") {
+        return n.parse().ok();
+    }
+    let qtype = match s.as_str() {
+        "A" => TYPE_A,
+        "NS" => TYPE_NS,
+        "CNAME" => TYPE_CNAME,
+        "SOA" => TYPE_SOA,
+        "PTR" => TYPE_PTR,
+        "MX" => TYPE_MX,
+        "TXT" => TYPE_TXT,
+        "AAAA" => TYPE_AAAA,
+        "OPT" => TYPE_OPT,
+        _ => return None,
+    };
+    Some(qtype)
+}
+
+/// Mnemonic for a record class, falling back to the `CLASS<n>` form.
+pub fn class_to_string(class: u16) -> String {
+    let name = match class {
+        CLASS_IN => "IN",
+        3 => "CH",
+        4 => "HS",
+        255 => "ANY",
+        _ => return format!("CLASS{class}"),
+    };
+    name.to_string()
+}
+
+/// Inverse of [`class_to_string`].
+pub fn class_from_str(s: &str) -> Option<u16> {
+    let s = s.to_ascii_uppercase();
+    if let Some(n) = s.strip_prefix("CLASS") {
+        return n.parse().ok();
+    }
+    let class = match s.as_str() {
+        "IN" => CLASS_IN,
+        "CH" => 3,
+        "HS" => 4,
+        "ANY" => 255,
+        _ => return None,
+    };
+    Some(class)
+}
+
+pub fn opcode_to_string(opcode: u8) -> String {
+    let name = match opcode {
+        0 => "QUERY",
+        1 => "IQUERY",
+        2 => "STATUS",
+        4 => "NOTIFY",
+        5 => "UPDATE",
+        _ => return format!("RESERVED{opcode}"),
+    };
+    name.to_string()
+}
+
+pub fn rcode_to_string(rcode: u8) -> String {
+    let name = match rcode {
+        0 => "NOERROR",
+        1 => "FORMERR",
+        2 => "SERVFAIL",
+        3 => "NXDOMAIN",
+        4 => "NOTIMP",
+        5 => "REFUSED",
+        _ => return format!("RCODE{rcode}"),
+    };
+    name.to_string()
+}
+
+/// Presentation form of a domain name, always fully qualified.
+pub fn name_to_string(name: &str) -> String {
+    if name.is_empty() || name == "." {
+        ".".to_string()
+    } else if name.ends_with('.') {
+        name.to_string()
+    } else {
+        format!("{name}.")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_type_to_string() {
+        assert_eq!(type_to_string(TYPE_A), "A");
+        assert_eq!(type_to_string(TYPE_AAAA), "AAAA");
+        assert_eq!(type_to_string(65534), "TYPE65534");
+    }
+
+    #[test]
+    fn test_type_from_str() {
+        assert_eq!(type_from_str("aaaa"), Some(TYPE_AAAA));
+        assert_eq!(type_from_str("TYPE65534"), Some(65534));
+        assert_eq!(type_from_str("TYPE1"), Some(TYPE_A));
+        assert_eq!(type_from_str("BOGUS"), None);
+    }
+
+    #[test]
+    fn test_name_to_string() {
+        assert_eq!(name_to_string(""), ".");
+        assert_eq!(name_to_string("example.com"), "example.com.");
+        assert_eq!(name_to_string("example.com."), "example.com.");
+    }
+}
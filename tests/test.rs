@@ -1,6 +1,11 @@
 use std::{str::FromStr, sync::OnceLock, thread};
 
-use diaw::r#async::listener;
+use diaw::{
+    hints::RootHints,
+    r#async::listener,
+    resolver::{set_resolver, Resolver, ResolverConfig},
+    transport::{FixtureTransport, Fixtures},
+};
 use tracing::Level;
 use trust_dns_client::{
     client::{AsyncClient, Client, ClientHandle, SyncClient},
@@ -16,9 +21,29 @@ fn init_logger() {
         tracing_subscriber::fmt()
             .with_max_level(Level::TRACE)
             .init();
+        init_resolver();
     });
 }
 
+/// Resolves from the recorded responses instead of the real root servers.
+fn init_resolver() {
+    let fixtures = Fixtures::load(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/resolve.txt"
+    ))
+    .unwrap();
+    let hints = RootHints::from_named_root(
+        ". 3600000 NS a.root-servers.net.\na.root-servers.net. 3600000 A 198.41.0.4",
+    )
+    .unwrap();
+    set_resolver(
+        Resolver::new(ResolverConfig::default(), FixtureTransport::new(fixtures))
+            .with_cache(Default::default())
+            .with_selection(Default::default())
+            .with_root_hints(hints.into()),
+    );
+}
+
 #[test]
 fn test_resolver() -> eyre::Result<()> {
     init_logger();
@@ -35,6 +60,7 @@ fn test_resolver() -> eyre::Result<()> {
     let client = SyncClient::new(conn);
     let name = Name::from_str("www.example.com.")?;
     let response = client.query(&name, DNSClass::IN, RecordType::A)?;
+    assert_eq!(response.answers().len(), 1);
     Ok(())
 }
 
@@ -49,4 +75,5 @@ async fn test_resolver_async() {
     let name = Name::from_str("www.example.com.").unwrap();
     let query = client.query(name, DNSClass::IN, RecordType::A);
     let response = query.await.unwrap();
+    assert_eq!(response.answers().len(), 1);
 }